@@ -6,22 +6,17 @@ use std::str::FromStr;
 use thiserror::Error;
 
 /// Whether messages should use color output.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum ColorChoice {
     /// Force color output.
     Always,
     /// Intelligently guess whether to use color output.
+    #[default]
     Auto,
     /// Force disable color output.
     Never,
 }
 
-impl Default for ColorChoice {
-    fn default() -> Self {
-        Self::Auto
-    }
-}
-
 impl fmt::Display for ColorChoice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -33,7 +28,7 @@ impl fmt::Display for ColorChoice {
 }
 
 #[derive(Debug, Error)]
-#[error("expected `always` or `never`, got `{}`", self.0)]
+#[error("expected `always`, `auto`, or `never`, got `{}`", self.0)]
 pub struct ParseColorChoiceError(String);
 
 impl FromStr for ColorChoice {
@@ -50,10 +45,18 @@ impl FromStr for ColorChoice {
 }
 
 impl ColorChoice {
+    /// Whether messages should use color output.
+    ///
+    /// Messages are written to stderr so only that stream is checked, it
+    /// doesn't matter whether the script on stdout is piped somewhere.
     pub fn is_color(self) -> bool {
+        self.is_color_for(io::stderr().is_terminal())
+    }
+
+    pub fn is_color_for(self, is_terminal: bool) -> bool {
         match self {
             Self::Always => true,
-            Self::Auto => io::stderr().is_terminal(),
+            Self::Auto => is_terminal,
             Self::Never => false,
         }
     }
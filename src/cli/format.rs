@@ -0,0 +1,39 @@
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// The format to output errors in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable text.
+    #[default]
+    Text,
+    /// A JSON array of error objects.
+    Json,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => f.write_str("text"),
+            Self::Json => f.write_str("json"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("expected `text` or `json`, got `{}`", self.0)]
+pub struct ParseFormatError(String);
+
+impl FromStr for Format {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            s => Err(ParseFormatError(s.to_string())),
+        }
+    }
+}
@@ -1,24 +1,29 @@
 //! Command line interface.
 
 mod color_choice;
+mod format;
 mod raw;
 
 #[cfg(test)]
 mod tests;
 
 use std::env;
+use std::fmt;
 use std::io;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process;
 
 use anyhow::{anyhow, Context as ResultExt, Result};
-use clap::{CommandFactory, Parser};
-use clap_complete as complete;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
 
-use crate::cli::raw::{Add, RawCommand, RawOpt};
-use crate::config::{EditPlugin, GitReference, RawPlugin, Shell};
+use crate::cli::color_choice::ColorChoice;
+use crate::cli::format::Format;
+use crate::cli::raw::{Add, ConfigCommand, RawCommand, RawOpt};
+use crate::config::{EditPlugin, GitReference, Position, RawPlugin, Shell, Use};
 use crate::context::{log_error, log_error_as_warning, Context, Output, Verbosity};
-use crate::lock::LockMode;
+use crate::lock::{Annotate, LockMode};
 use crate::util::build;
 
 /// Parse the command line arguments.
@@ -26,7 +31,9 @@ use crate::util::build;
 /// In the event of failure it will print the error message and quit the program
 /// without returning.
 pub fn from_args() -> Opt {
-    Opt::from_raw_opt(RawOpt::parse())
+    let matches = RawOpt::command().get_matches();
+    let raw_opt = RawOpt::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    Opt::from_raw_opt(raw_opt, &matches)
 }
 
 /// Resolved command line options with defaults set.
@@ -38,73 +45,205 @@ pub struct Opt {
     pub command: Command,
 }
 
+/// Where a resolved directory was configured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Provenance {
+    /// A command line option.
+    Option(String),
+    /// An environment variable.
+    Env(String),
+    /// The default location.
+    Default,
+    /// The deprecated `~/.sheldon` location.
+    Deprecated,
+}
+
 /// The resolved command.
 #[derive(Debug)]
 pub enum Command {
     /// Initialize a new config file.
-    Init { shell: Option<Shell> },
+    Init {
+        shell: Option<Shell>,
+        from: Option<String>,
+    },
     /// Add a new plugin to the config file.
     Add {
         name: String,
         plugin: Box<EditPlugin>,
+        position: Option<Position>,
+        force: bool,
     },
     /// Open up the config file in the default editor.
-    Edit,
+    Edit { editor: Option<String> },
     /// Remove a plugin from the config file.
-    Remove { name: String },
+    Remove { name: String, dry_run: bool },
+    /// Rename a plugin in the config file.
+    Rename { old: String, new: String },
+    /// Enable a disabled plugin in the config file.
+    Enable { name: String },
+    /// Disable a plugin in the config file.
+    Disable { name: String },
+    /// Print the value of a top-level setting in the config file.
+    ConfigGet { key: String },
+    /// Set the value of a top-level setting in the config file.
+    ConfigSet { key: String, values: Vec<String> },
+    /// Rewrite deprecated keys and template syntax in the config file.
+    MigrateConfig,
     /// Install the plugins sources and generate the lock file.
-    Lock,
+    Lock {
+        no_clean: bool,
+        diff: bool,
+        yes: bool,
+        keep_going: bool,
+    },
     /// Generate and print out the script.
-    Source,
+    Source {
+        annotate: Option<Annotate>,
+        no_lock_file: bool,
+        plugins_dir: Option<PathBuf>,
+        output: Option<PathBuf>,
+        check: bool,
+        plugins_filter: Option<String>,
+        no_clean: bool,
+        keep_going: bool,
+    },
+    /// Show the directory and files that a plugin resolved to.
+    Which { name: String },
+    /// List the templates available to `apply`.
+    Templates,
+    /// Garbage collect the cloned Git sources.
+    Gc,
+    /// Reinstall the plugin sources as recorded in the lock file.
+    Restore,
+    /// Remove the sources in the lock file that are no longer configured.
+    Prune,
 }
 
 impl Opt {
-    fn from_raw_opt(raw_opt: RawOpt) -> Self {
+    fn from_raw_opt(raw_opt: RawOpt, matches: &ArgMatches) -> Self {
         let RawOpt {
             quiet,
             non_interactive,
             verbose,
             color,
+            format,
             data_dir,
             config_dir,
             config_file,
+            use_state_dir,
             profile,
             command,
         } = raw_opt;
 
         let mut lock_mode = None;
+        let mut strict_verify = false;
+        let mut offline = false;
+        let mut jobs = None;
+        let mut max_download_size = None;
+        let mut timings = false;
 
-        let command = match command {
-            RawCommand::Init { shell } => Command::Init { shell },
+        let mut command = match command {
+            RawCommand::Init { shell, from } => Command::Init { shell, from },
             RawCommand::Add(add) => {
-                let (name, plugin) = EditPlugin::from_add(*add);
+                let mut add = *add;
+                let position = match (add.before.take(), add.after.take()) {
+                    (Some(name), None) => Some(Position::Before(name)),
+                    (None, Some(name)) => Some(Position::After(name)),
+                    (None, None) => None,
+                    (Some(_), Some(_)) => unreachable!(),
+                };
+                let force = add.force;
+                let (name, plugin) = EditPlugin::from_add(add);
                 Command::Add {
                     name,
                     plugin: Box::new(plugin),
+                    position,
+                    force,
                 }
             }
-            RawCommand::Edit => Command::Edit,
-            RawCommand::Remove { name } => Command::Remove { name },
-            RawCommand::Lock { update, reinstall } => {
+            RawCommand::Edit { editor } => Command::Edit { editor },
+            RawCommand::Remove { name, dry_run } => Command::Remove { name, dry_run },
+            RawCommand::Rename { old, new } => Command::Rename { old, new },
+            RawCommand::Enable { name } => Command::Enable { name },
+            RawCommand::Disable { name } => Command::Disable { name },
+            RawCommand::Config(ConfigCommand::Get { key }) => Command::ConfigGet { key },
+            RawCommand::Config(ConfigCommand::Set { key, values }) => {
+                Command::ConfigSet { key, values }
+            }
+            RawCommand::MigrateConfig => Command::MigrateConfig,
+            RawCommand::Lock {
+                update,
+                reinstall,
+                yes,
+                offline: o,
+                no_clean,
+                diff,
+                keep_going,
+                jobs: j,
+                max_download_size: m,
+                timings: t,
+            } => {
                 lock_mode = LockMode::from_lock_flags(update, reinstall);
-                Command::Lock
+                offline = o;
+                jobs = j;
+                max_download_size = m;
+                timings = t;
+                Command::Lock {
+                    no_clean,
+                    diff,
+                    yes,
+                    keep_going,
+                }
             }
             RawCommand::Source {
                 relock,
                 update,
                 reinstall,
+                annotate,
+                strict_verify: s,
+                no_lock_file,
+                plugins_dir,
+                output,
+                check,
+                plugins_filter,
+                no_clean,
+                keep_going,
+                jobs: j,
+                max_download_size: m,
+                timings: t,
             } => {
                 lock_mode = LockMode::from_source_flags(relock, update, reinstall);
-                Command::Source
+                strict_verify = s;
+                jobs = j;
+                max_download_size = m;
+                timings = t;
+                Command::Source {
+                    annotate: Annotate::from_flags(annotate, color, io::stdout().is_terminal()),
+                    no_lock_file: no_lock_file || plugins_dir.is_some(),
+                    plugins_dir,
+                    output,
+                    check,
+                    plugins_filter,
+                    no_clean,
+                    keep_going,
+                }
             }
+            RawCommand::Which { name } => Command::Which { name },
+            RawCommand::Templates => Command::Templates,
+            RawCommand::Gc => Command::Gc,
+            RawCommand::Restore => Command::Restore,
+            RawCommand::Prune => Command::Prune,
             RawCommand::Completions { shell } => {
-                let shell = complete::Shell::from(shell);
                 let mut app = RawOpt::command();
                 clap_complete::generate(shell, &mut app, build::CRATE_NAME, &mut io::stdout());
                 process::exit(0);
             }
-            RawCommand::Version => {
-                println!("{} {}", build::CRATE_NAME, build::CRATE_VERBOSE_VERSION);
+            RawCommand::Version { json } => {
+                if json {
+                    println!("{}", build::version_json());
+                } else {
+                    println!("{} {}", build::CRATE_NAME, build::CRATE_VERBOSE_VERSION);
+                }
                 process::exit(0);
             }
         };
@@ -120,6 +259,7 @@ impl Opt {
         let output = Output {
             verbosity,
             no_color: !color.is_color(),
+            json_errors: format == Format::Json,
         };
 
         let home = match home::home_dir() {
@@ -131,7 +271,27 @@ impl Opt {
             }
         };
 
-        let (config_file, config_dir, data_dir) =
+        let expand = |resolved: Option<Resolved>| resolved.map(|r| expand_tilde(&home, r));
+        let config_file = expand(resolve_option(
+            matches,
+            profile.as_deref(),
+            "config_file",
+            config_file,
+        ));
+        let config_dir = expand(resolve_option(
+            matches,
+            profile.as_deref(),
+            "config_dir",
+            config_dir,
+        ));
+        let data_dir = expand(resolve_option(
+            matches,
+            profile.as_deref(),
+            "data_dir",
+            data_dir,
+        ));
+
+        let (config_file, (config_dir, config_provenance), (data_dir, data_provenance)) =
             match resolve_paths(&home, config_file, config_dir, data_dir, output.no_color) {
                 Ok(paths) => paths,
                 Err(err) => {
@@ -139,9 +299,14 @@ impl Opt {
                     process::exit(1);
                 }
             };
+        let lock_dir = if use_state_dir {
+            default_state_dir(&home).0
+        } else {
+            data_dir.clone()
+        };
         let lock_file = match profile.as_deref() {
-            Some("") | None => data_dir.join("plugins.lock"),
-            Some(p) => data_dir.join(format!("plugins.{p}.lock")),
+            Some("") | None => lock_dir.join("plugins.lock"),
+            Some(p) => lock_dir.join(format!("plugins.{p}.lock")),
         };
         let clone_dir = data_dir.join("repos");
         let download_dir = data_dir.join("downloads");
@@ -156,11 +321,38 @@ impl Opt {
             clone_dir,
             download_dir,
             profile,
+            shell: None,
             output,
             interactive: !non_interactive,
             lock_mode,
+            strict_verify,
+            offline,
+            timings,
+            jobs,
+            max_download_size,
         };
 
+        // There is no config file to compare the lock file against when the
+        // config is read from stdin, so always lock from scratch.
+        if let Command::Source { no_lock_file, .. } = &mut command {
+            *no_lock_file |= ctx.config_from_stdin();
+        }
+
+        ctx.log_verbose_status(
+            "Config",
+            &format!(
+                "{} ({config_provenance})",
+                ctx.replace_home(ctx.config_dir()).display()
+            ),
+        );
+        ctx.log_verbose_status(
+            "Data",
+            &format!(
+                "{} ({data_provenance})",
+                ctx.replace_home(ctx.data_dir()).display()
+            ),
+        );
+
         Self { ctx, command }
     }
 }
@@ -172,7 +364,9 @@ impl EditPlugin {
             git,
             gist,
             github,
+            bitbucket,
             remote,
+            no_rewrite,
             local,
             proto,
             branch,
@@ -183,6 +377,9 @@ impl EditPlugin {
             apply,
             profiles,
             hooks,
+            before: _,
+            after: _,
+            force: _,
         } = add;
 
         let hooks = hooks.map(|h| h.into_iter().collect());
@@ -200,19 +397,29 @@ impl EditPlugin {
         (
             name,
             Self::from(RawPlugin {
-                git,
+                git: git.map(String::from),
                 gist,
                 github,
-                remote,
+                bitbucket,
+                remote: remote.map(String::from),
                 local,
                 inline: None,
                 proto,
+                host: None,
+                ssh_user: None,
+                ssh_port: None,
                 reference,
+                mirrors: None,
+                post_clone: None,
+                rewrite: no_rewrite.then_some(false),
+                remote_index: None,
                 dir,
-                uses,
+                uses: uses.map(|uses| uses.into_iter().map(Use::from).collect()),
                 apply,
                 profiles,
+                enabled: None,
                 hooks,
+                vars: None,
                 rest: None,
             }),
         )
@@ -240,13 +447,105 @@ impl LockMode {
     }
 }
 
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Option(option) => write!(f, "from {option}"),
+            Self::Env(var) => write!(f, "from ${var}"),
+            Self::Default => f.write_str("default location"),
+            Self::Deprecated => f.write_str("deprecated location"),
+        }
+    }
+}
+
+/// Resolve the value of a path option along with where it was configured.
+///
+/// Profile specific environment variables take precedence over the generic
+/// ones, but not over options given on the command line.
+fn resolve_option(
+    matches: &ArgMatches,
+    profile: Option<&str>,
+    id: &str,
+    value: Option<PathBuf>,
+) -> Option<Resolved> {
+    if let Some((var, value)) = profile_env_var(matches, profile, id) {
+        return Some((value, Provenance::Env(var)));
+    }
+    let provenance = match matches.value_source(id) {
+        Some(ValueSource::EnvVariable) => Provenance::Env(format!(
+            "{}_{}",
+            build::CRATE_NAME.to_uppercase(),
+            id.to_uppercase()
+        )),
+        _ => Provenance::Option(format!("--{}", id.replace('_', "-"))),
+    };
+    value.map(|value| (value, provenance))
+}
+
+/// Returns the name and value of the profile specific environment variable for
+/// the given option, for example `SHELDON_WORK_DATA_DIR` for the `data_dir`
+/// option and the `work` profile.
+fn profile_env_var(
+    matches: &ArgMatches,
+    profile: Option<&str>,
+    id: &str,
+) -> Option<(String, PathBuf)> {
+    let profile = profile.filter(|p| !p.is_empty())?;
+    if matches.value_source(id) == Some(ValueSource::CommandLine) {
+        return None;
+    }
+    let profile: String = profile
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let key = format!(
+        "{}_{}_{}",
+        build::CRATE_NAME.to_uppercase(),
+        profile,
+        id.to_uppercase()
+    );
+    let value = env::var_os(&key)?;
+    Some((key, PathBuf::from(value)))
+}
+
+impl Annotate {
+    /// Annotations are only ever colored when explicitly asked for and when
+    /// the script is being displayed in a terminal, since it is usually
+    /// evaluated by the shell.
+    fn from_flags(annotate: bool, color: ColorChoice, is_terminal: bool) -> Option<Self> {
+        match (annotate, color, is_terminal) {
+            (false, _, _) => None,
+            (true, ColorChoice::Always, true) => Some(Self::Colored),
+            (true, _, _) => Some(Self::Plain),
+        }
+    }
+}
+
+/// A resolved path and where it was resolved from.
+type Resolved = (PathBuf, Provenance);
+
+/// Expand a leading `~` in the path to the home directory, shells don't do
+/// this for environment variables that are quoted.
+fn expand_tilde(home: &Path, (path, provenance): Resolved) -> Resolved {
+    match path.strip_prefix("~") {
+        Ok(p) => (home.join(p), provenance),
+        Err(_) => (path, provenance),
+    }
+}
+
 fn resolve_paths(
     home: &Path,
-    config_file: Option<PathBuf>,
-    config_dir: Option<PathBuf>,
-    data_dir: Option<PathBuf>,
+    config_file: Option<Resolved>,
+    config_dir: Option<Resolved>,
+    data_dir: Option<Resolved>,
     no_color: bool,
-) -> Result<(PathBuf, PathBuf, PathBuf)> {
+) -> Result<(PathBuf, Resolved, Resolved)> {
     // TODO: Remove this warning in a later release and stop falling back to
     // the old directory.
     let err = anyhow!(
@@ -265,7 +564,13 @@ See the release notes at https://github.com/rossmacarthur/sheldon for more infor
     );
     let mut using_old = false;
     let (config_file, config_dir) = match config_file {
-        Some(file) => {
+        // The config is read from stdin so the config directory is resolved
+        // as if no config file was given.
+        Some((file, _)) if file == Path::new("-") => {
+            let dir = config_dir.unwrap_or_else(|| default_config_dir(home));
+            (file, dir)
+        }
+        Some((file, provenance)) => {
             let dir = file
                 .parent()
                 .with_context(|| {
@@ -275,28 +580,28 @@ See the release notes at https://github.com/rossmacarthur/sheldon for more infor
                     )
                 })?
                 .to_path_buf();
-            (file, dir)
+            (file, (dir, provenance))
         }
         None => {
-            let dir = config_dir.unwrap_or_else(|| {
+            let (dir, provenance) = config_dir.unwrap_or_else(|| {
                 let default = default_config_dir(home);
                 let old = home.join(".sheldon");
-                if old.exists() && !default.exists() {
+                if old.exists() && !default.0.exists() {
                     log_error_as_warning(no_color, &err);
                     using_old = true;
-                    return old;
+                    return (old, Provenance::Deprecated);
                 }
                 default
             });
             let file = dir.join("plugins.toml");
-            (file, dir)
+            (file, (dir, provenance))
         }
     };
 
     let data_dir = data_dir.unwrap_or_else(|| {
         let default = default_data_dir(home);
-        if using_old && !default.exists() {
-            return config_dir.clone();
+        if using_old && !default.0.exists() {
+            return (config_dir.0.clone(), Provenance::Deprecated);
         }
         default
     });
@@ -304,18 +609,23 @@ See the release notes at https://github.com/rossmacarthur/sheldon for more infor
     Ok((config_file, config_dir, data_dir))
 }
 
-fn default_config_dir(home: &Path) -> PathBuf {
-    let mut p = env::var_os("XDG_CONFIG_HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|| home.join(".config"));
-    p.push("sheldon");
-    p
+fn default_config_dir(home: &Path) -> Resolved {
+    default_dir(home, "XDG_CONFIG_HOME", ".config")
+}
+
+fn default_data_dir(home: &Path) -> Resolved {
+    default_dir(home, "XDG_DATA_HOME", ".local/share")
 }
 
-fn default_data_dir(home: &Path) -> PathBuf {
-    let mut p = env::var_os("XDG_DATA_HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|| home.join(".local/share"));
+fn default_state_dir(home: &Path) -> Resolved {
+    default_dir(home, "XDG_STATE_HOME", ".local/state")
+}
+
+fn default_dir(home: &Path, var: &str, default: &str) -> Resolved {
+    let (mut p, provenance) = match env::var_os(var) {
+        Some(p) => (PathBuf::from(p), Provenance::Env(var.to_string())),
+        None => (home.join(default), Provenance::Default),
+    };
     p.push("sheldon");
-    p
+    (p, provenance)
 }
@@ -1,13 +1,17 @@
 #![deny(missing_docs)]
 
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, Parser, Subcommand};
 use clap_complete as complete;
 use url::Url;
 
 use crate::cli::color_choice::ColorChoice;
-use crate::config::{GistRepository, GitHubRepository, GitProtocol, Shell};
+use crate::cli::format::Format;
+use crate::config::{
+    self, BitbucketRepository, GistRepository, GitHubRepository, GitProtocol, Shell,
+};
 use crate::util::build;
 
 const HELP_TEMPLATE: &str = "\
@@ -32,7 +36,7 @@ const HELP_TEMPLATE: &str = "\
     subcommand_required(true),
 )]
 pub struct RawOpt {
-    /// Suppress any informational output.
+    /// Suppress any informational output, warnings and errors are still shown.
     #[clap(long, short)]
     pub quiet: bool,
 
@@ -48,6 +52,10 @@ pub struct RawOpt {
     #[clap(long, value_name = "WHEN", default_value_t)]
     pub color: ColorChoice,
 
+    /// Error output format: text or json.
+    #[clap(long, value_name = "FORMAT", default_value_t)]
+    pub format: Format,
+
     /// The configuration directory.
     #[clap(long, value_name = "PATH", env = "SHELDON_CONFIG_DIR")]
     pub config_dir: Option<PathBuf>,
@@ -56,12 +64,16 @@ pub struct RawOpt {
     #[clap(long, value_name = "PATH", env = "SHELDON_DATA_DIR")]
     pub data_dir: Option<PathBuf>,
 
-    /// The config file.
+    /// The config file, or `-` to read the config from stdin.
     #[clap(long, value_name = "PATH", env = "SHELDON_CONFIG_FILE")]
     pub config_file: Option<PathBuf>,
 
+    /// Store the lock file in the state directory instead of the data directory.
+    #[clap(long, env = "SHELDON_USE_STATE_DIR")]
+    pub use_state_dir: bool,
+
     /// The profile used for conditional plugins.
-    #[clap(long, value_name = "PROFILE", env = "SHELDON_PROFILE")]
+    #[clap(long, value_name = "PROFILE", global = true, env = "SHELDON_PROFILE")]
     pub profile: Option<String>,
 
     /// The subcommand to run.
@@ -73,24 +85,68 @@ pub struct RawOpt {
 pub enum RawCommand {
     /// Initialize a new config file.
     Init {
-        /// The type of shell, accepted values are: bash, fish, zsh.
+        /// The type of shell, accepted values are: bash, fish, nu, zsh.
         #[clap(long, value_name = "SHELL")]
         shell: Option<Shell>,
+
+        /// Use the config file at this URL or path as the initial config.
+        #[clap(long, value_name = "URL|PATH", conflicts_with = "shell")]
+        from: Option<String>,
     },
 
     /// Add a new plugin to the config file.
     Add(Box<Add>),
 
     /// Open up the config file in the default editor.
-    Edit,
+    Edit {
+        /// The editor command to use instead of `$VISUAL` or `$EDITOR`.
+        #[clap(long, value_name = "CMD")]
+        editor: Option<String>,
+    },
 
     /// Remove a plugin from the config file.
     Remove {
-        /// A unique name for this plugin.
+        /// The name of the plugin, or a glob pattern matching plugin names.
+        #[clap(value_name = "NAME")]
+        name: String,
+
+        /// List the matching plugins without removing them.
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Rename a plugin in the config file.
+    Rename {
+        /// The current name of the plugin.
+        #[clap(value_name = "OLD")]
+        old: String,
+
+        /// The new name for the plugin.
+        #[clap(value_name = "NEW")]
+        new: String,
+    },
+
+    /// Enable a disabled plugin in the config file.
+    Enable {
+        /// The name of the plugin.
         #[clap(value_name = "NAME")]
         name: String,
     },
 
+    /// Disable a plugin in the config file without removing it.
+    Disable {
+        /// The name of the plugin.
+        #[clap(value_name = "NAME")]
+        name: String,
+    },
+
+    /// Get or set a top-level setting in the config file.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Rewrite deprecated keys and template syntax in the config file.
+    MigrateConfig,
+
     /// Install the plugins sources and generate the lock file.
     Lock {
         /// Update all plugin sources.
@@ -100,6 +156,44 @@ pub enum RawCommand {
         /// Reinstall all plugin sources.
         #[clap(long, conflicts_with = "update")]
         reinstall: bool,
+
+        /// Don't ask for confirmation before reinstalling.
+        #[clap(short, long, requires = "reinstall")]
+        yes: bool,
+
+        /// Only use sources that are already cloned or downloaded.
+        #[clap(long, conflicts_with_all = ["update", "reinstall"])]
+        offline: bool,
+
+        /// Don't remove unused clones and downloads from the data directory.
+        #[clap(long)]
+        no_clean: bool,
+
+        /// Print the changes to the lock file before writing it.
+        #[clap(long)]
+        diff: bool,
+
+        /// Write the lock file with the plugins that were installed even if
+        /// others failed, then exit with an error.
+        #[clap(long)]
+        keep_going: bool,
+
+        /// The maximum number of sources to install at the same time.
+        #[clap(long, value_name = "N", env = "SHELDON_JOBS")]
+        jobs: Option<NonZeroUsize>,
+
+        /// The maximum size of each remote source download, for example `10M`.
+        #[clap(
+            long,
+            value_name = "SIZE",
+            value_parser = size_parser,
+            env = "SHELDON_MAX_DOWNLOAD_SIZE"
+        )]
+        max_download_size: Option<u64>,
+
+        /// Print how long each plugin took to install and render.
+        #[clap(long)]
+        timings: bool,
     },
 
     /// Generate and print out the script.
@@ -115,17 +209,119 @@ pub enum RawCommand {
         /// Reinstall all plugin sources (implies --relock).
         #[clap(long, conflicts_with = "update")]
         reinstall: bool,
+
+        /// Annotate the script with a comment naming each plugin.
+        #[clap(long)]
+        annotate: bool,
+
+        /// Also verify that Git sources are checked out at the locked
+        /// reference, relocking if not.
+        #[clap(long, env = "SHELDON_STRICT_VERIFY")]
+        strict_verify: bool,
+
+        /// Lock the plugins in memory without reading or writing the lock file.
+        #[clap(long)]
+        no_lock_file: bool,
+
+        /// Use each subdirectory of this directory as a local plugin (implies
+        /// --no-lock-file).
+        #[clap(long, value_name = "DIR")]
+        plugins_dir: Option<PathBuf>,
+
+        /// Write the script to this file instead of printing it.
+        #[clap(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+
+        /// Check that the plugins lock and the script renders without
+        /// printing it, exiting with an error if anything fails.
+        #[clap(long, conflicts_with = "output")]
+        check: bool,
+
+        /// Only output the plugins with a name matching this glob pattern.
+        #[clap(long, value_name = "GLOB")]
+        plugins_filter: Option<String>,
+
+        /// Don't remove unused clones and downloads from the data directory.
+        #[clap(long)]
+        no_clean: bool,
+
+        /// Write the lock file and script with the plugins that were installed
+        /// even if others failed, then exit with an error.
+        #[clap(long)]
+        keep_going: bool,
+
+        /// The maximum number of sources to install at the same time.
+        #[clap(long, value_name = "N", env = "SHELDON_JOBS")]
+        jobs: Option<NonZeroUsize>,
+
+        /// The maximum size of each remote source download, for example `10M`.
+        #[clap(
+            long,
+            value_name = "SIZE",
+            value_parser = size_parser,
+            env = "SHELDON_MAX_DOWNLOAD_SIZE"
+        )]
+        max_download_size: Option<u64>,
+
+        /// Print how long each plugin took to install and render.
+        #[clap(long)]
+        timings: bool,
     },
 
+    /// Show the directory and files that a plugin resolved to.
+    Which {
+        /// The name of the plugin.
+        #[clap(value_name = "NAME")]
+        name: String,
+    },
+
+    /// List the templates available to `apply`, including the built-in ones.
+    Templates,
+
+    /// Garbage collect the cloned Git sources to reclaim disk space.
+    Gc,
+
+    /// Reinstall the plugin sources exactly as recorded in the lock file.
+    Restore,
+
+    /// Remove the sources in the lock file that are no longer in the config.
+    Prune,
+
     /// Generate completions for the given shell.
     Completions {
-        /// The type of shell, accepted values are: bash, zsh.
+        /// The type of shell.
         #[clap(long, value_name = "SHELL")]
-        shell: Shell,
+        shell: complete::Shell,
     },
 
     /// Prints detailed version information.
-    Version,
+    Version {
+        /// Print the version information as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+/// A `config` subcommand.
+#[derive(Debug, PartialEq, Eq, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the value of a top-level setting.
+    Get {
+        /// The name of the setting.
+        #[clap(value_name = "KEY")]
+        key: String,
+    },
+
+    /// Set the value of a top-level setting.
+    Set {
+        /// The name of the setting.
+        #[clap(value_name = "KEY")]
+        key: String,
+
+        /// The new value, `match` and `apply` take one or more values.
+        #[clap(value_name = "VALUE", required = true, num_args = 1..)]
+        values: Vec<String>,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Parser)]
@@ -139,7 +335,7 @@ pub struct Add {
     pub name: String,
 
     /// Add a clonable Git repository.
-    #[clap(long, value_name = "URL", group = "plugin")]
+    #[clap(long, value_name = "URL", group = "plugin", value_parser = git_url_parser)]
     pub git: Option<Url>,
 
     /// Add a clonable Gist snippet.
@@ -150,15 +346,23 @@ pub struct Add {
     #[clap(long, value_name = "REPO", group = "plugin")]
     pub github: Option<GitHubRepository>,
 
+    /// Add a clonable Bitbucket repository.
+    #[clap(long, value_name = "REPO", group = "plugin")]
+    pub bitbucket: Option<BitbucketRepository>,
+
     /// Add a downloadable file.
     #[clap(long, value_name = "URL", group = "plugin")]
     pub remote: Option<Url>,
 
+    /// Don't rewrite a GitHub blob URL to the raw file URL.
+    #[clap(long, conflicts_with_all = &["git", "gist", "github", "bitbucket", "local"])]
+    pub no_rewrite: bool,
+
     /// Add a local directory.
     #[clap(long, value_name = "DIR", group = "plugin")]
     pub local: Option<PathBuf>,
 
-    /// The Git protocol for a Gist or GitHub plugin.
+    /// The Git protocol for a Gist, GitHub, or Bitbucket plugin.
     #[clap(long, value_name = "PROTO", conflicts_with_all = &["git", "remote", "local"])]
     pub proto: Option<GitProtocol>,
 
@@ -193,16 +397,45 @@ pub struct Add {
     /// Hooks executed during template evaluation.
     #[clap(long, value_name = "SCRIPT", value_parser = key_value_parser, num_args(1..))]
     pub hooks: Option<Vec<(String, String)>>,
+
+    /// Add the plugin before the plugin with this name.
+    #[clap(long, value_name = "NAME", conflicts_with = "after")]
+    pub before: Option<String>,
+
+    /// Add the plugin after the plugin with this name.
+    #[clap(long, value_name = "NAME")]
+    pub after: Option<String>,
+
+    /// Replace the plugin if one with this name already exists.
+    #[clap(long)]
+    pub force: bool,
+}
+
+fn git_url_parser(s: &str) -> Result<Url, String> {
+    config::parse_git_url(s).map_err(|err| format!("{err:#}"))
 }
 
-impl From<Shell> for complete::Shell {
-    fn from(s: Shell) -> Self {
-        match s {
-            Shell::Bash => complete::Shell::Bash,
-            Shell::Fish => complete::Shell::Fish,
-            Shell::Zsh => complete::Shell::Zsh,
+fn size_parser(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit {
+        "" | "B" => 0,
+        "K" | "KiB" => 10,
+        "M" | "MiB" => 20,
+        "G" | "GiB" => 30,
+        _ => {
+            return Err(format!(
+            "{s} isn't a valid size, expected a number of bytes optionally followed by K, M, or G"
+        ))
         }
-    }
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("{s} isn't a valid size"))
 }
 
 fn key_value_parser(s: &str) -> Result<(String, String), String> {
@@ -1,11 +1,14 @@
 use super::*;
 
 use std::iter;
+use std::num::NonZeroUsize;
 
 use clap::error::ErrorKind;
+use clap::Parser;
 use pretty_assertions::assert_eq;
 
 use crate::cli::color_choice::ColorChoice;
+use crate::cli::format::Format;
 
 fn setup() {
     for (k, _) in env::vars() {
@@ -20,6 +23,13 @@ fn raw_opt(args: &[&str]) -> RawOpt {
     RawOpt::try_parse_from(iter::once(build::CRATE_NAME).chain(args.iter().copied())).unwrap()
 }
 
+fn opt(args: &[&str]) -> Opt {
+    let matches = RawOpt::command()
+        .try_get_matches_from(iter::once(build::CRATE_NAME).chain(args.iter().copied()))
+        .unwrap();
+    Opt::from_raw_opt(RawOpt::from_arg_matches(&matches).unwrap(), &matches)
+}
+
 fn raw_opt_err(args: &[&str]) -> clap::Error {
     RawOpt::try_parse_from(iter::once(build::CRATE_NAME).chain(args.iter().copied())).unwrap_err()
 }
@@ -67,13 +77,23 @@ fn raw_opt_no_options() {
             non_interactive: false,
             verbose: false,
             color: Default::default(),
+            format: Default::default(),
             config_dir: None,
             data_dir: None,
             config_file: None,
+            use_state_dir: false,
             profile: None,
             command: RawCommand::Lock {
                 update: false,
-                reinstall: false
+                reinstall: false,
+                yes: false,
+                offline: false,
+                no_clean: false,
+                diff: false,
+                keep_going: false,
+                jobs: None,
+                max_download_size: None,
+                timings: false,
             },
         }
     );
@@ -88,6 +108,8 @@ fn raw_opt_options() {
             "--verbose",
             "--color",
             "never",
+            "--format",
+            "json",
             "--config-dir",
             "/test",
             "--data-dir",
@@ -103,13 +125,23 @@ fn raw_opt_options() {
             non_interactive: false,
             verbose: true,
             color: ColorChoice::Never,
+            format: Format::Json,
             config_dir: Some("/test".into()),
             data_dir: Some("/test".into()),
             config_file: Some("/plugins.toml".into()),
+            use_state_dir: false,
             profile: Some("profile".into()),
             command: RawCommand::Lock {
                 update: false,
-                reinstall: false
+                reinstall: false,
+                yes: false,
+                offline: false,
+                no_clean: false,
+                diff: false,
+                keep_going: false,
+                jobs: None,
+                max_download_size: None,
+                timings: false,
             },
         }
     );
@@ -144,6 +176,22 @@ fn raw_opt_init_with_invalid_shell() {
     );
 }
 
+#[test]
+fn raw_opt_init_from() {
+    setup();
+    assert_eq!(
+        raw_opt(&["init", "--from", "https://example.com/plugins.toml"]).command,
+        RawCommand::Init {
+            shell: None,
+            from: Some("https://example.com/plugins.toml".into()),
+        }
+    );
+    assert_eq!(
+        raw_opt_err(&["init", "--shell", "zsh", "--from", "plugins.toml"]).kind(),
+        ErrorKind::ArgumentConflict
+    );
+}
+
 #[test]
 fn raw_opt_add_help() {
     setup();
@@ -191,7 +239,9 @@ fn raw_opt_add_git_with_options() {
             ),
             gist: None,
             github: None,
+            bitbucket: None,
             remote: None,
+            no_rewrite: false,
             local: None,
             proto: None,
             branch: None,
@@ -202,6 +252,9 @@ fn raw_opt_add_git_with_options() {
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
             hooks: None,
+            before: None,
+            after: None,
+            force: false,
         }))
     );
 }
@@ -234,7 +287,9 @@ fn raw_opt_add_gist_options() {
             git: None,
             gist: Some("579d02802b1cc17baed07753d09f5009".parse().unwrap()),
             github: None,
+            bitbucket: None,
             remote: None,
+            no_rewrite: false,
             local: None,
             proto: Some("ssh".parse().unwrap()),
             branch: None,
@@ -245,6 +300,9 @@ fn raw_opt_add_gist_options() {
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
             hooks: None,
+            before: None,
+            after: None,
+            force: false,
         }))
     );
 }
@@ -277,7 +335,9 @@ fn raw_opt_add_github_options() {
             git: None,
             gist: None,
             github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            bitbucket: None,
             remote: None,
+            no_rewrite: false,
             local: None,
             proto: Some("https".parse().unwrap()),
             branch: Some("feature".into()),
@@ -288,6 +348,47 @@ fn raw_opt_add_github_options() {
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
             hooks: None,
+            before: None,
+            after: None,
+            force: false,
+        }))
+    );
+}
+
+#[test]
+fn raw_opt_add_bitbucket_options() {
+    setup();
+    assert_eq!(
+        raw_opt(&[
+            "add",
+            "test",
+            "--bitbucket",
+            "rossmacarthur/sheldon-test",
+            "--proto",
+            "ssh",
+        ])
+        .command,
+        RawCommand::Add(Box::new(Add {
+            name: "test".to_string(),
+            git: None,
+            gist: None,
+            github: None,
+            bitbucket: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            remote: None,
+            no_rewrite: false,
+            local: None,
+            proto: Some("ssh".parse().unwrap()),
+            branch: None,
+            rev: None,
+            tag: None,
+            dir: None,
+            uses: None,
+            apply: None,
+            profiles: None,
+            hooks: None,
+            before: None,
+            after: None,
+            force: false,
         }))
     );
 }
@@ -314,7 +415,9 @@ fn raw_opt_add_remote_options() {
             git: None,
             gist: None,
             github: None,
+            bitbucket: None,
             remote: Some("https://raw.githubusercontent.com/rossmacarthur/sheldon-test/master/test.plugin.zsh".parse().unwrap()),
+            no_rewrite: false,
             local: None,
             proto: None,
             branch: None,
@@ -325,6 +428,9 @@ fn raw_opt_add_remote_options() {
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
             hooks: None,
+            before: None,
+            after: None,
+            force: false,
         }))
     );
 }
@@ -351,7 +457,9 @@ fn raw_opt_add_local_options() {
             git: None,
             gist: None,
             github: None,
+            bitbucket: None,
             remote: None,
+            no_rewrite: false,
             local: Some("~/.dotfiles/zsh/pure".into()),
             proto: None,
             branch: None,
@@ -362,6 +470,9 @@ fn raw_opt_add_local_options() {
             apply: Some(vec_into!["something", "another-thing"]),
             profiles: None,
             hooks: None,
+            before: None,
+            after: None,
+            force: false,
         }))
     );
 }
@@ -451,6 +562,22 @@ fn raw_opt_add_remote_with_protocol_expect_conflict() {
     );
 }
 
+#[test]
+fn raw_opt_add_github_with_no_rewrite_expect_conflict() {
+    setup();
+    assert_eq!(
+        raw_opt_err(&[
+            "add",
+            "test",
+            "--github",
+            "rossmacarthur/sheldon-test",
+            "--no-rewrite",
+        ])
+        .kind(),
+        ErrorKind::ArgumentConflict
+    );
+}
+
 #[test]
 fn raw_opt_add_local_with_protocol_expect_conflict() {
     setup();
@@ -468,6 +595,111 @@ fn raw_opt_add_local_with_protocol_expect_conflict() {
     );
 }
 
+#[test]
+fn raw_opt_edit() {
+    setup();
+    assert_eq!(
+        raw_opt(&["edit"]).command,
+        RawCommand::Edit { editor: None }
+    );
+    assert_eq!(
+        raw_opt(&["edit", "--editor", "code --wait"]).command,
+        RawCommand::Edit {
+            editor: Some("code --wait".into())
+        }
+    );
+}
+
+#[test]
+fn opt_add_position() {
+    setup();
+    let position = |args: &[&str]| match opt(args).command {
+        Command::Add { position, .. } => position,
+        command => panic!("unexpected command {command:?}"),
+    };
+    assert_eq!(position(&["add", "test", "--github", "a/b"]), None);
+    assert_eq!(
+        position(&["add", "test", "--github", "a/b", "--before", "other"]),
+        Some(Position::Before("other".into()))
+    );
+    assert_eq!(
+        position(&["add", "test", "--github", "a/b", "--after", "other"]),
+        Some(Position::After("other".into()))
+    );
+    let err = raw_opt_err(&[
+        "add", "test", "--github", "a/b", "--before", "one", "--after", "two",
+    ]);
+    assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+}
+
+#[test]
+fn raw_opt_rename() {
+    setup();
+    assert_eq!(
+        raw_opt(&["rename", "old", "new"]).command,
+        RawCommand::Rename {
+            old: "old".into(),
+            new: "new".into(),
+        }
+    );
+}
+
+#[test]
+fn raw_opt_enable_and_disable() {
+    setup();
+    assert_eq!(
+        raw_opt(&["enable", "test"]).command,
+        RawCommand::Enable {
+            name: "test".into()
+        }
+    );
+    assert_eq!(
+        raw_opt(&["disable", "test"]).command,
+        RawCommand::Disable {
+            name: "test".into()
+        }
+    );
+}
+
+#[test]
+fn raw_opt_config() {
+    setup();
+    assert_eq!(
+        raw_opt(&["config", "get", "shell"]).command,
+        RawCommand::Config(ConfigCommand::Get {
+            key: "shell".into()
+        })
+    );
+    assert_eq!(
+        raw_opt(&["config", "set", "match", "a", "b"]).command,
+        RawCommand::Config(ConfigCommand::Set {
+            key: "match".into(),
+            values: vec!["a".into(), "b".into()],
+        })
+    );
+    assert_eq!(
+        raw_opt_err(&["config", "set", "shell"]).kind(),
+        ErrorKind::MissingRequiredArgument
+    );
+}
+
+#[test]
+fn raw_opt_templates() {
+    setup();
+    assert_eq!(raw_opt(&["templates"]).command, RawCommand::Templates);
+}
+
+#[test]
+fn raw_opt_which() {
+    setup();
+    assert_eq!(
+        raw_opt(&["which", "test"]).command,
+        RawCommand::Which {
+            name: "test".into(),
+        }
+    );
+}
+
 #[test]
 fn raw_opt_lock_help() {
     setup();
@@ -485,6 +717,148 @@ fn raw_opt_lock_with_update_and_reinstall_expect_conflict() {
     );
 }
 
+#[test]
+fn raw_opt_lock_with_offline() {
+    setup();
+    assert!(opt(&["lock", "--offline"]).ctx.offline);
+    assert!(!opt(&["lock"]).ctx.offline);
+    for flag in ["--update", "--reinstall"] {
+        let err = raw_opt_err(&["lock", "--offline", flag]);
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+}
+
+#[test]
+fn raw_opt_lock_with_profile() {
+    setup();
+    assert_eq!(
+        raw_opt(&["lock", "--profile", "work"]).profile.as_deref(),
+        Some("work")
+    );
+}
+
+#[test]
+fn raw_opt_lock_with_no_clean() {
+    setup();
+    assert_eq!(
+        raw_opt(&["lock", "--no-clean"]).command,
+        RawCommand::Lock {
+            update: false,
+            reinstall: false,
+            yes: false,
+            offline: false,
+            no_clean: true,
+            diff: false,
+            keep_going: false,
+            jobs: None,
+            max_download_size: None,
+            timings: false,
+        }
+    );
+}
+
+#[test]
+fn raw_opt_lock_with_jobs() {
+    setup();
+    assert_eq!(
+        raw_opt(&["lock", "--jobs", "1"]).command,
+        RawCommand::Lock {
+            update: false,
+            reinstall: false,
+            yes: false,
+            offline: false,
+            no_clean: false,
+            diff: false,
+            keep_going: false,
+            jobs: NonZeroUsize::new(1),
+            max_download_size: None,
+            timings: false,
+        }
+    );
+    raw_opt_err(&["lock", "--jobs", "0"]);
+}
+
+#[test]
+fn raw_opt_lock_with_max_download_size() {
+    setup();
+    for (arg, size) in [
+        ("512", 512),
+        ("10K", 10 << 10),
+        ("2MiB", 2 << 20),
+        ("1G", 1 << 30),
+    ] {
+        match raw_opt(&["lock", "--max-download-size", arg]).command {
+            RawCommand::Lock {
+                max_download_size, ..
+            } => assert_eq!(max_download_size, Some(size)),
+            command => panic!("unexpected command {command:?}"),
+        }
+    }
+    raw_opt_err(&["lock", "--max-download-size", "10X"]);
+    raw_opt_err(&["lock", "--max-download-size", "M"]);
+}
+
+#[test]
+fn raw_opt_lock_with_diff() {
+    setup();
+    assert_eq!(
+        raw_opt(&["lock", "--diff"]).command,
+        RawCommand::Lock {
+            update: false,
+            reinstall: false,
+            yes: false,
+            offline: false,
+            no_clean: false,
+            diff: true,
+            keep_going: false,
+            jobs: None,
+            max_download_size: None,
+            timings: false,
+        }
+    );
+}
+
+#[test]
+fn raw_opt_lock_with_reinstall_and_yes() {
+    setup();
+    assert_eq!(
+        raw_opt(&["lock", "--reinstall", "-y"]).command,
+        RawCommand::Lock {
+            update: false,
+            reinstall: true,
+            yes: true,
+            offline: false,
+            no_clean: false,
+            diff: false,
+            keep_going: false,
+            jobs: None,
+            max_download_size: None,
+            timings: false,
+        }
+    );
+    raw_opt_err(&["lock", "--yes"]);
+}
+
+#[test]
+fn raw_opt_lock_with_keep_going() {
+    setup();
+    assert_eq!(
+        raw_opt(&["lock", "--keep-going"]).command,
+        RawCommand::Lock {
+            update: false,
+            reinstall: false,
+            yes: false,
+            offline: false,
+            no_clean: false,
+            diff: false,
+            keep_going: true,
+            jobs: None,
+            max_download_size: None,
+            timings: false,
+        }
+    );
+}
+
 #[test]
 fn raw_opt_source_help() {
     setup();
@@ -493,6 +867,19 @@ fn raw_opt_source_help() {
     assert_eq!(err.kind(), ErrorKind::DisplayHelp);
 }
 
+#[test]
+fn raw_opt_source_with_check() {
+    setup();
+    assert!(matches!(
+        raw_opt(&["source", "--check"]).command,
+        RawCommand::Source { check: true, .. }
+    ));
+    assert_eq!(
+        raw_opt_err(&["source", "--check", "--output", "plugins.zsh"]).kind(),
+        ErrorKind::ArgumentConflict
+    );
+}
+
 #[test]
 fn raw_opt_source_with_update_and_reinstall_expect_conflict() {
     setup();
@@ -501,3 +888,142 @@ fn raw_opt_source_with_update_and_reinstall_expect_conflict() {
         ErrorKind::ArgumentConflict
     );
 }
+
+#[test]
+fn opt_source_profile_after_subcommand() {
+    setup();
+    let opt = opt(&[
+        "--data-dir",
+        "/test",
+        "--profile",
+        "home",
+        "source",
+        "--profile",
+        "work",
+    ]);
+    assert_eq!(opt.ctx.profile.as_deref(), Some("work"));
+    assert_eq!(opt.ctx.lock_file, Path::new("/test/plugins.work.lock"));
+}
+
+#[test]
+fn opt_source_config_file_stdin() {
+    setup();
+    let opt = opt(&["--config-dir", "/test", "--config-file", "-", "source"]);
+    assert!(opt.ctx.config_from_stdin());
+    assert_eq!(opt.ctx.config_dir, Path::new("/test"));
+    assert!(matches!(
+        opt.command,
+        Command::Source {
+            no_lock_file: true,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn opt_expands_tilde_in_directories() {
+    setup();
+    let home = home::home_dir().unwrap();
+    let ctx = opt(&["--config-dir", "~/config", "--data-dir", "~/data", "lock"]).ctx;
+    assert_eq!(ctx.config_dir, home.join("config"));
+    assert_eq!(ctx.config_file, home.join("config/plugins.toml"));
+    assert_eq!(ctx.data_dir, home.join("data"));
+    assert_eq!(ctx.clone_dir, home.join("data/repos"));
+    assert_eq!(ctx.download_dir, home.join("data/downloads"));
+
+    let ctx = opt(&["--config-file", "~/plugins.toml", "lock"]).ctx;
+    assert_eq!(ctx.config_file, home.join("plugins.toml"));
+    assert_eq!(ctx.config_dir, home);
+}
+
+#[test]
+fn opt_use_state_dir() {
+    setup();
+    let home = home::home_dir().unwrap();
+    let ctx = opt(&["--data-dir", "/data", "lock"]).ctx;
+    assert_eq!(ctx.lock_file, Path::new("/data/plugins.lock"));
+
+    let ctx = opt(&["--data-dir", "/data", "--use-state-dir", "lock"]).ctx;
+    assert_eq!(ctx.data_dir, Path::new("/data"));
+    assert_eq!(
+        ctx.lock_file,
+        home.join(".local/state/sheldon/plugins.lock")
+    );
+
+    let ctx = opt(&["--use-state-dir", "--profile", "work", "lock"]).ctx;
+    assert_eq!(
+        ctx.lock_file,
+        home.join(".local/state/sheldon/plugins.work.lock")
+    );
+}
+
+#[test]
+fn opt_source_strict_verify() {
+    setup();
+    assert!(!opt(&["source"]).ctx.strict_verify);
+    assert!(opt(&["source", "--strict-verify"]).ctx.strict_verify);
+}
+
+#[test]
+fn opt_timings() {
+    setup();
+    assert!(!opt(&["lock"]).ctx.timings);
+    assert!(opt(&["lock", "--timings"]).ctx.timings);
+    assert!(opt(&["source", "--timings"]).ctx.timings);
+}
+
+#[test]
+fn raw_opt_completions_shells() {
+    setup();
+    for (name, shell) in [
+        ("bash", clap_complete::Shell::Bash),
+        ("elvish", clap_complete::Shell::Elvish),
+        ("fish", clap_complete::Shell::Fish),
+        ("powershell", clap_complete::Shell::PowerShell),
+        ("zsh", clap_complete::Shell::Zsh),
+    ] {
+        assert_eq!(
+            raw_opt(&["completions", "--shell", name]).command,
+            RawCommand::Completions { shell }
+        );
+        let mut buf = Vec::new();
+        clap_complete::generate(shell, &mut RawOpt::command(), build::CRATE_NAME, &mut buf);
+        assert!(!buf.is_empty(), "empty completions for {name}");
+    }
+}
+
+#[test]
+fn raw_opt_completions_unsupported_shell() {
+    setup();
+    assert_eq!(
+        raw_opt_err(&["completions", "--shell", "nu"]).kind(),
+        ErrorKind::InvalidValue
+    );
+}
+
+#[test]
+fn color_choice_is_color_for() {
+    assert!(ColorChoice::Always.is_color_for(true));
+    assert!(ColorChoice::Always.is_color_for(false));
+    assert!(ColorChoice::Auto.is_color_for(true));
+    assert!(!ColorChoice::Auto.is_color_for(false));
+    assert!(!ColorChoice::Never.is_color_for(true));
+    assert!(!ColorChoice::Never.is_color_for(false));
+}
+
+#[test]
+fn annotate_from_flags() {
+    assert_eq!(Annotate::from_flags(false, ColorChoice::Always, true), None);
+    assert_eq!(
+        Annotate::from_flags(true, ColorChoice::Always, false),
+        Some(Annotate::Plain)
+    );
+    assert_eq!(
+        Annotate::from_flags(true, ColorChoice::Auto, true),
+        Some(Annotate::Plain)
+    );
+    assert_eq!(
+        Annotate::from_flags(true, ColorChoice::Always, true),
+        Some(Annotate::Colored)
+    );
+}
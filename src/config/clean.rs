@@ -1,16 +1,140 @@
 use std::collections::HashSet;
-use std::path::Path;
-use std::{fs, result};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context as ResultExt, Error, Result};
+use rayon::prelude::*;
 use walkdir::WalkDir;
 
 use crate::config::{Config, Plugin, Source};
 use crate::context::Context;
 use crate::lock::source;
+use crate::util::git;
+
+/// The paths in the clone and download directories used by a config.
+struct Referenced {
+    /// Each source directory.
+    source_dirs: HashSet<PathBuf>,
+    /// Each ancestor of a source directory or downloaded file.
+    parent_dirs: HashSet<PathBuf>,
+    /// Each downloaded file.
+    files: HashSet<PathBuf>,
+}
 
 /// Clean the clone and download directories.
 pub fn clean(ctx: &Context, warnings: &mut Vec<Error>, config: &Config) -> Result<()> {
+    let Referenced {
+        source_dirs,
+        parent_dirs,
+        files,
+    } = referenced(ctx, config)?;
+
+    let mut stale = stale_paths(ctx.clone_dir(), &source_dirs, |p| parent_dirs.contains(p));
+    stale.extend(stale_paths(ctx.download_dir(), &source_dirs, |p| {
+        files.contains(p) || parent_dirs.contains(p)
+    }));
+    stale.extend(stale_lock_files(ctx, &source_dirs)?);
+
+    let errors: Vec<_> = stale
+        .par_iter()
+        .filter_map(|path| remove_path(ctx, path).err())
+        .collect();
+    warnings.extend(errors);
+
+    Ok(())
+}
+
+/// Remove the given installed sources that are no longer used by the config.
+///
+/// Unlike [`clean`] this only considers the given paths, typically the sources
+/// recorded in the lock file, and leaves anything else in the clone and
+/// download directories alone. Returns the number of paths removed.
+pub fn prune<I>(
+    ctx: &Context,
+    warnings: &mut Vec<Error>,
+    config: &Config,
+    installed: I,
+) -> Result<usize>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    let Referenced {
+        source_dirs, files, ..
+    } = referenced(ctx, config)?;
+
+    let mut removed = 0;
+    for path in installed {
+        if source_dirs.contains(&path) || files.contains(&path) || !path.exists() {
+            continue;
+        }
+        match remove_path(ctx, &path) {
+            Ok(()) => removed += 1,
+            Err(err) => warnings.push(err),
+        }
+        // Git repositories also have a lock file.
+        if path.starts_with(ctx.clone_dir()) {
+            let lock_file = git::lock_file(&source::locks_dir(ctx), &path)?;
+            if lock_file.exists() {
+                if let Err(err) = remove_path(ctx, &lock_file) {
+                    warnings.push(err);
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Collect the lock files of repository directories that are no longer used.
+fn stale_lock_files(ctx: &Context, source_dirs: &HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+    let locks_dir = source::locks_dir(ctx);
+    let Ok(entries) = fs::read_dir(&locks_dir) else {
+        return Ok(Vec::new());
+    };
+    let used = source_dirs
+        .iter()
+        .filter(|dir| dir.starts_with(ctx.clone_dir()))
+        .map(|dir| git::lock_file(&locks_dir, dir))
+        .collect::<Result<HashSet<_>>>()?;
+    Ok(entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| !used.contains(path))
+        .collect())
+}
+
+/// Collect the paths under the root directory that should be removed.
+///
+/// Source directories are skipped entirely and paths for which `keep` returns
+/// true are left alone. Only the top-most stale path of a tree is returned, so
+/// that removing the returned paths in any order never races a directory
+/// against its own children.
+fn stale_paths<F>(root: &Path, source_dirs: &HashSet<PathBuf>, keep: F) -> Vec<PathBuf>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut paths = Vec::new();
+    let mut it = WalkDir::new(root).into_iter();
+    while let Some(entry) = it.next() {
+        let Ok(entry) = entry else { continue };
+        let path = entry.path();
+        if source_dirs.contains(path) {
+            if entry.file_type().is_dir() {
+                it.skip_current_dir();
+            }
+            continue;
+        }
+        if keep(path) {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            it.skip_current_dir();
+        }
+        paths.push(entry.into_path());
+    }
+    paths
+}
+
+/// Collect the paths used by each plugin in the config.
+fn referenced(ctx: &Context, config: &Config) -> Result<Referenced> {
     let mut source_dirs = HashSet::new();
     let mut parent_dirs = HashSet::new();
     let mut files = HashSet::new();
@@ -23,13 +147,24 @@ pub fn clean(ctx: &Context, warnings: &mut Vec<Error>, config: &Config) -> Resul
                     parent_dirs.extend(dir.ancestors().map(Path::to_path_buf));
                     source_dirs.insert(dir);
                 }
-                Source::Remote { url } => {
+                Source::Remote { url, index: false } => {
                     let (dir, file) = source::remote_dir_and_file(ctx, url)?;
                     files.insert(file);
                     parent_dirs.extend(dir.ancestors().map(Path::to_path_buf));
                 }
-                Source::Local { .. } => {
-                    // Don't remove local plugins!
+                Source::Remote { url, index: true } => {
+                    let (dir, file) = source::remote_index_dir_and_file(ctx, url)?;
+                    files.insert(file);
+                    parent_dirs.extend(dir.parent().unwrap().ancestors().map(Path::to_path_buf));
+                    source_dirs.insert(dir);
+                }
+                Source::Local { dir } => {
+                    // Don't remove local plugins! But extracted archives live
+                    // in the download directory.
+                    if let Some(dir) = source::archive_dir(ctx, dir) {
+                        parent_dirs.extend(dir.ancestors().map(Path::to_path_buf));
+                        source_dirs.insert(dir);
+                    }
                 }
             }
         }
@@ -38,31 +173,11 @@ pub fn clean(ctx: &Context, warnings: &mut Vec<Error>, config: &Config) -> Resul
     parent_dirs.insert(ctx.clone_dir().to_path_buf());
     parent_dirs.insert(ctx.download_dir().to_path_buf());
 
-    for entry in WalkDir::new(ctx.clone_dir())
-        .into_iter()
-        .filter_entry(|e| !source_dirs.contains(e.path()))
-        .filter_map(result::Result::ok)
-        .filter(|e| !parent_dirs.contains(e.path()))
-    {
-        if let Err(err) = remove_path(ctx, entry.path()) {
-            warnings.push(err);
-        }
-    }
-
-    for entry in WalkDir::new(ctx.download_dir())
-        .into_iter()
-        .filter_map(result::Result::ok)
-        .filter(|e| {
-            let p = e.path();
-            !files.contains(p) && !parent_dirs.contains(p)
-        })
-    {
-        if let Err(err) = remove_path(ctx, entry.path()) {
-            warnings.push(err);
-        }
-    }
-
-    Ok(())
+    Ok(Referenced {
+        source_dirs,
+        parent_dirs,
+        files,
+    })
 }
 
 fn remove_path(ctx: &Context, path: &Path) -> Result<()> {
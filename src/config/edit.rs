@@ -5,8 +5,21 @@ use std::fs;
 use std::path::Path;
 
 use anyhow::{bail, Context as ResultExt, Result};
+use itertools::Itertools;
+use regex_macro::regex;
 
-use crate::config::{RawPlugin, Shell};
+use crate::config::{GitProtocol, RawPlugin, Shell};
+
+/// The top-level settings that can be read and written with
+/// [`EditConfig::get`] and [`EditConfig::set`].
+const SETTINGS: &[&str] = &[
+    "shell",
+    "proto",
+    "track_shell_version",
+    "strict",
+    "match",
+    "apply",
+];
 
 /// An editable plugin.
 #[derive(Debug)]
@@ -14,6 +27,15 @@ pub struct EditPlugin {
     inner: RawPlugin,
 }
 
+/// Where to add a plugin relative to an existing plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Position {
+    /// Add the plugin before the plugin with this name.
+    Before(String),
+    /// Add the plugin after the plugin with this name.
+    After(String),
+}
+
 /// An editable config.
 #[derive(Debug)]
 pub struct EditConfig {
@@ -74,8 +96,83 @@ impl EditConfig {
         Self::from_str(contents)
     }
 
+    /// Returns the value of a top-level setting, if it is set.
+    ///
+    /// Strings are returned without quotes and each item of a list is on its
+    /// own line.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        check_setting(key)?;
+        match self.doc.get(key) {
+            Some(item) => {
+                let value = item
+                    .as_value()
+                    .with_context(|| format!("current `{key}` entry is not a value"))?;
+                Ok(Some(display_value(value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Set a top-level setting.
+    ///
+    /// The `match` and `apply` settings are set to all the given values, the
+    /// other settings take exactly one value which must be valid.
+    pub fn set(&mut self, key: &str, values: &[String]) -> Result<()> {
+        check_setting(key)?;
+        let value: toml_edit::Value = match (key, values) {
+            ("match" | "apply", values) => values
+                .iter()
+                .map(String::as_str)
+                .collect::<toml_edit::Array>()
+                .into(),
+            ("shell", [value]) => value.parse::<Shell>()?.to_string().into(),
+            ("proto", [value]) => {
+                value.parse::<GitProtocol>()?;
+                value.as_str().into()
+            }
+            (_, [value]) => value
+                .parse::<bool>()
+                .map_err(|_| anyhow::anyhow!("`{value}` is not a valid boolean"))?
+                .into(),
+            _ => bail!("`{key}` takes exactly one value"),
+        };
+        self.doc[key] = toml_edit::value(value);
+        Ok(())
+    }
+
     /// Add a new plugin.
-    pub fn add(&mut self, name: &str, plugin: &EditPlugin) -> Result<()> {
+    ///
+    /// The plugin is added after all the other plugins unless a position is
+    /// given.
+    pub fn add(
+        &mut self,
+        name: &str,
+        plugin: &EditPlugin,
+        position: Option<&Position>,
+    ) -> Result<()> {
+        self.insert(name, plugin, position, false).map(|_| ())
+    }
+
+    /// Add a new plugin, replacing the plugin with the same name if it exists.
+    ///
+    /// A replaced plugin keeps its place unless a position is given. Returns
+    /// whether a plugin was replaced.
+    pub fn add_or_replace(
+        &mut self,
+        name: &str,
+        plugin: &EditPlugin,
+        position: Option<&Position>,
+    ) -> Result<bool> {
+        self.insert(name, plugin, position, true)
+    }
+
+    fn insert(
+        &mut self,
+        name: &str,
+        plugin: &EditPlugin,
+        position: Option<&Position>,
+        replace: bool,
+    ) -> Result<bool> {
         let contents =
             toml::to_string_pretty(&plugin.inner).expect("failed to serialize plugin as TOML");
 
@@ -93,18 +190,63 @@ impl EditConfig {
             _ => bail!("current `plugins` entry is not a table"),
         }
 
-        match &mut self.doc["plugins"][name] {
+        if let Some(Position::Before(anchor) | Position::After(anchor)) = position {
+            if self.doc["plugins"].get(anchor).is_none() {
+                bail!("plugin with name `{anchor}` does not exist");
+            }
+        }
+
+        let mut table = toml_edit::table();
+        for (k, v) in mini.as_table().iter() {
+            table[k] = v.clone();
+        }
+        let replaced = match &mut self.doc["plugins"][name] {
             item @ toml_edit::Item::None => {
-                let mut table = toml_edit::table();
-                for (k, v) in mini.as_table().iter() {
-                    table[k] = v.clone();
+                *item = table;
+                false
+            }
+            item if replace => {
+                // Keep the comments and place of the replaced plugin.
+                if let (Some(old), Some(new)) = (item.as_table(), table.as_table_mut()) {
+                    *new.decor_mut() = old.decor().clone();
+                    if let Some(position) = old.position() {
+                        new.set_position(position);
+                    }
                 }
                 *item = table;
+                true
             }
             _ => bail!("plugin with name `{name}` already exists"),
+        };
+
+        if let Some(position) = position {
+            let plugins = self.doc["plugins"].as_table_mut().unwrap();
+            move_key(plugins, name, position);
         }
 
-        Ok(())
+        Ok(replaced)
+    }
+
+    /// Returns the names of the plugins matching the given glob pattern.
+    ///
+    /// A plain plugin name only matches itself.
+    pub fn matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let matcher = globset::Glob::new(pattern)
+            .with_context(|| format!("failed to parse glob `{pattern}`"))?
+            .compile_matcher();
+        let names: Vec<_> = self
+            .doc
+            .get("plugins")
+            .and_then(|p| p.as_table_like())
+            .into_iter()
+            .flat_map(|plugins| plugins.iter())
+            .map(|(name, _)| name.to_string())
+            .filter(|name| matcher.is_match(name))
+            .collect();
+        if names.is_empty() {
+            bail!("no plugins match `{pattern}`");
+        }
+        Ok(names)
     }
 
     /// Remove a plugin.
@@ -112,6 +254,104 @@ impl EditConfig {
         self.doc["plugins"][name] = toml_edit::Item::None;
     }
 
+    /// Rename a plugin, preserving its contents and position.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<()> {
+        let plugins = match self.doc.get_mut("plugins").and_then(|p| p.as_table_mut()) {
+            Some(plugins) if plugins.contains_key(old) => plugins,
+            _ => bail!("plugin with name `{old}` does not exist"),
+        };
+        if plugins.contains_key(new) {
+            bail!("plugin with name `{new}` already exists");
+        }
+
+        rename_key(plugins, old, new);
+        Ok(())
+    }
+
+    /// Enable or disable a plugin.
+    ///
+    /// Disabling sets `enabled = false` on the plugin and enabling removes the
+    /// key again, since plugins are enabled by default.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<()> {
+        let plugin = self
+            .doc
+            .get_mut("plugins")
+            .and_then(|p| p.as_table_like_mut())
+            .and_then(|p| p.get_mut(name));
+        match plugin {
+            Some(toml_edit::Item::Table(table)) => {
+                if enabled {
+                    table.remove("enabled");
+                } else {
+                    table.insert("enabled", toml_edit::value(false));
+                }
+            }
+            Some(toml_edit::Item::Value(toml_edit::Value::InlineTable(table))) => {
+                if enabled {
+                    table.remove("enabled");
+                } else {
+                    table.insert("enabled", false.into());
+                }
+                table.fmt();
+            }
+            _ => bail!("plugin with name `{name}` does not exist"),
+        }
+        Ok(())
+    }
+
+    /// Rewrite deprecated config keys and template syntax.
+    ///
+    /// Returns a description of each rewrite that was made.
+    pub fn migrate(&mut self) -> Vec<String> {
+        let mut rewrites = Vec::new();
+
+        if let Some(plugins) = self.doc.get_mut("plugins").and_then(|p| p.as_table_mut()) {
+            for (name, plugin) in plugins.iter_mut() {
+                let renamed = match plugin {
+                    toml_edit::Item::Table(table) => {
+                        table.contains_key("protocol")
+                            && !table.contains_key("proto")
+                            && rename_key(table, "protocol", "proto")
+                    }
+                    toml_edit::Item::Value(toml_edit::Value::InlineTable(table)) => {
+                        table.contains_key("protocol")
+                            && !table.contains_key("proto")
+                            && rename_inline_key(table, "protocol", "proto")
+                    }
+                    _ => false,
+                };
+                if renamed {
+                    let name = name.get();
+                    rewrites.push(format!(
+                        "`plugins.{name}.protocol` to `plugins.{name}.proto`"
+                    ));
+                }
+            }
+        }
+
+        if let Some(templates) = self
+            .doc
+            .get_mut("templates")
+            .and_then(|t| t.as_table_like_mut())
+        {
+            for (name, template) in templates.iter_mut() {
+                let value = match template {
+                    toml_edit::Item::Value(toml_edit::Value::InlineTable(table)) => {
+                        table.get_mut("value")
+                    }
+                    item => item.as_value_mut(),
+                };
+                if let Some(value) = value {
+                    if replace_get_filter(value) {
+                        rewrites.push(format!("`get` filter in `templates.{}`", name.get()));
+                    }
+                }
+            }
+        }
+
+        rewrites
+    }
+
     /// Write a config to the given path.
     pub fn to_path<P>(&self, path: P) -> Result<()>
     where
@@ -123,6 +363,108 @@ impl EditConfig {
     }
 }
 
+/// Rename a key in the table, preserving its position and formatting.
+///
+/// Returns whether the key was renamed.
+fn rename_key(table: &mut toml_edit::Table, old: &str, new: &str) -> bool {
+    // Remove and reinsert every entry so that the renamed key keeps its
+    // position in the table.
+    let keys: Vec<_> = table.iter().map(|(k, _)| k.to_owned()).collect();
+    let mut renamed = false;
+    for k in keys {
+        let (key, item) = table.remove_entry(&k).unwrap();
+        let key = if k == old {
+            renamed = true;
+            toml_edit::Key::new(new).with_decor(key.decor().clone())
+        } else {
+            key
+        };
+        table.insert_formatted(&key, item);
+    }
+    renamed
+}
+
+/// Check that the key is one of the supported top-level settings.
+fn check_setting(key: &str) -> Result<()> {
+    if !SETTINGS.contains(&key) {
+        bail!(
+            "unknown setting `{key}`, expected one of {}",
+            SETTINGS.iter().map(|s| format!("`{s}`")).join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Display a TOML value for printing, without quotes around strings.
+fn display_value(value: &toml_edit::Value) -> String {
+    match value {
+        toml_edit::Value::String(s) => s.value().clone(),
+        toml_edit::Value::Array(array) => array.iter().map(display_value).join("\n"),
+        value => value.to_string().trim().to_string(),
+    }
+}
+
+/// Move a key in the table so that it is before or after another key.
+fn move_key(table: &mut toml_edit::Table, key: &str, position: &Position) {
+    // Remove and reinsert every entry, since there is no way to insert an
+    // entry at a particular index.
+    let mut moved = table.remove_entry(key);
+    let keys: Vec<_> = table.iter().map(|(k, _)| k.to_owned()).collect();
+    for k in keys {
+        let (other_key, other) = table.remove_entry(&k).unwrap();
+        if matches!(position, Position::Before(anchor) if *anchor == k) {
+            if let Some((key, item)) = moved.take() {
+                table.insert_formatted(&key, item);
+            }
+        }
+        table.insert_formatted(&other_key, other);
+        if matches!(position, Position::After(anchor) if *anchor == k) {
+            if let Some((key, item)) = moved.take() {
+                table.insert_formatted(&key, item);
+            }
+        }
+    }
+}
+
+/// Rename a key in the inline table, preserving its position and formatting.
+///
+/// Returns whether the key was renamed.
+fn rename_inline_key(table: &mut toml_edit::InlineTable, old: &str, new: &str) -> bool {
+    let keys: Vec<_> = table.iter().map(|(k, _)| k.to_owned()).collect();
+    let mut renamed = false;
+    for k in keys {
+        let (key, value) = table.remove_entry(&k).unwrap();
+        let key = if k == old {
+            renamed = true;
+            toml_edit::Key::new(new).with_decor(key.decor().clone())
+        } else {
+            key
+        };
+        table.insert_formatted(&key, value);
+    }
+    renamed
+}
+
+/// Replace uses of the deprecated `get` filter in a template with the `?.`
+/// operator, for example `{{ hooks | get: "pre" }}` becomes `{{ hooks?.pre }}`.
+///
+/// Returns whether the template was changed.
+fn replace_get_filter(value: &mut toml_edit::Value) -> bool {
+    let re = regex!(r#"([A-Za-z_][\w.?]*)\s*\|\s*get:\s*"([A-Za-z_]\w*)""#);
+    let template = match value.as_str() {
+        Some(template) => template,
+        None => return false,
+    };
+    if !re.is_match(template) {
+        return false;
+    }
+    let replaced = re.replace_all(template, "$1?.$2").into_owned();
+    let decor = value.decor().clone();
+    *value = toml_edit::Value::from(replaced);
+    *value.decor_mut() = decor;
+    true
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Unit tests
 ////////////////////////////////////////////////////////////////////////////////
@@ -134,7 +476,6 @@ mod tests {
     use std::io::Write;
 
     use pretty_assertions::assert_eq;
-    use url::Url;
 
     use crate::config::GitReference;
 
@@ -183,10 +524,11 @@ tag = "0.1.0"
             .add(
                 "sheldon-test",
                 &EditPlugin::from(RawPlugin {
-                    git: Some(Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap()),
+                    git: Some("https://github.com/rossmacarthur/sheldon-test".into()),
                     reference: Some(GitReference::Branch("feature".to_string())),
                     ..Default::default()
                 }),
+                None,
             )
             .unwrap();
         assert_eq!(
@@ -198,6 +540,124 @@ branch = "feature"
         );
     }
 
+    #[test]
+    fn edit_config_add_with_position() {
+        let contents = r#"
+# the first plugin
+[plugins.one]
+github = "owner/one"
+
+[plugins.two]
+github = "owner/two"
+
+[templates]
+test = "test"
+"#;
+        let plugin = |repo: &str| {
+            EditPlugin::from(RawPlugin {
+                github: Some(repo.parse().unwrap()),
+                ..Default::default()
+            })
+        };
+
+        let mut config = EditConfig::from_str(contents).unwrap();
+        config
+            .add(
+                "first",
+                &plugin("owner/first"),
+                Some(&Position::Before("one".into())),
+            )
+            .unwrap();
+        config
+            .add(
+                "middle",
+                &plugin("owner/middle"),
+                Some(&Position::After("one".into())),
+            )
+            .unwrap();
+        config
+            .add(
+                "last",
+                &plugin("owner/last"),
+                Some(&Position::After("two".into())),
+            )
+            .unwrap();
+        assert_eq!(
+            config.doc.to_string(),
+            r#"[plugins.first]
+github = "owner/first"
+
+# the first plugin
+[plugins.one]
+github = "owner/one"
+
+[plugins.middle]
+github = "owner/middle"
+
+[plugins.two]
+github = "owner/two"
+
+[plugins.last]
+github = "owner/last"
+
+[templates]
+test = "test"
+"#
+        );
+
+        let err = config
+            .add(
+                "test",
+                &plugin("owner/test"),
+                Some(&Position::Before("missing".into())),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "plugin with name `missing` does not exist");
+    }
+
+    #[test]
+    fn edit_config_add_or_replace() {
+        let contents = r#"
+# the first plugin
+[plugins.one]
+github = "owner/one"
+tag = "v0.1.0"
+
+[plugins.two]
+github = "owner/two"
+"#;
+        let plugin = |repo: &str| {
+            EditPlugin::from(RawPlugin {
+                github: Some(repo.parse().unwrap()),
+                ..Default::default()
+            })
+        };
+
+        let mut config = EditConfig::from_str(contents).unwrap();
+        let err = config.add("one", &plugin("owner/new"), None).unwrap_err();
+        assert_eq!(err.to_string(), "plugin with name `one` already exists");
+        assert!(config
+            .add_or_replace("one", &plugin("owner/new"), None)
+            .unwrap());
+        assert!(!config
+            .add_or_replace("three", &plugin("owner/three"), None)
+            .unwrap());
+        assert_eq!(
+            config.doc.to_string(),
+            r#"
+# the first plugin
+[plugins.one]
+github = "owner/new"
+
+[plugins.two]
+github = "owner/two"
+
+[plugins.three]
+github = "owner/three"
+"#
+        );
+    }
+
     #[test]
     fn edit_config_empty_add_github() {
         let mut config = EditConfig::from_str("").unwrap();
@@ -209,6 +669,7 @@ branch = "feature"
                     reference: Some(GitReference::Tag("0.1.0".to_string())),
                     ..Default::default()
                 }),
+                None,
             )
             .unwrap();
         assert_eq!(
@@ -220,6 +681,213 @@ tag = "0.1.0"
         );
     }
 
+    #[test]
+    fn edit_config_rename() {
+        let mut config = EditConfig::from_str(
+            r#"
+# test configuration file
+
+# the first plugin
+[plugins.one]
+github = "rossmacarthur/one"
+
+# the second plugin
+[plugins.two]
+github = "rossmacarthur/two" # inline comment
+
+[plugins.three]
+github = "rossmacarthur/three"
+"#,
+        )
+        .unwrap();
+        config.rename("two", "deux").unwrap();
+        assert_eq!(
+            config.doc.to_string(),
+            r#"
+# test configuration file
+
+# the first plugin
+[plugins.one]
+github = "rossmacarthur/one"
+
+# the second plugin
+[plugins.deux]
+github = "rossmacarthur/two" # inline comment
+
+[plugins.three]
+github = "rossmacarthur/three"
+"#
+        );
+    }
+
+    #[test]
+    fn edit_config_set_enabled() {
+        let mut config = EditConfig::from_str(
+            r#"[plugins.one]
+github = "rossmacarthur/one"
+
+[plugins.two]
+github = "rossmacarthur/two"
+enabled = false
+
+[plugins.three]
+github = "rossmacarthur/three"
+"#,
+        )
+        .unwrap();
+        config.set_enabled("one", false).unwrap();
+        config.set_enabled("two", true).unwrap();
+        assert_eq!(
+            config.doc.to_string(),
+            r#"[plugins.one]
+github = "rossmacarthur/one"
+enabled = false
+
+[plugins.two]
+github = "rossmacarthur/two"
+
+[plugins.three]
+github = "rossmacarthur/three"
+"#
+        );
+    }
+
+    #[test]
+    fn edit_config_set_enabled_inline_table() {
+        let mut config = EditConfig::from_str(
+            r#"[plugins]
+one = { github = "rossmacarthur/one" }
+"#,
+        )
+        .unwrap();
+        config.set_enabled("one", false).unwrap();
+        assert_eq!(
+            config.doc.to_string(),
+            r#"[plugins]
+one = { github = "rossmacarthur/one", enabled = false }
+"#
+        );
+    }
+
+    #[test]
+    fn edit_config_set_enabled_missing() {
+        let mut config = EditConfig::from_str("[plugins]\n").unwrap();
+        assert_eq!(
+            config.set_enabled("one", false).unwrap_err().to_string(),
+            "plugin with name `one` does not exist"
+        );
+    }
+
+    #[test]
+    fn edit_config_rename_inline_tables() {
+        let mut config = EditConfig::from_str(
+            r#"[plugins]
+one = { github = "rossmacarthur/one" }
+two = { github = "rossmacarthur/two" }
+three = { github = "rossmacarthur/three" }
+"#,
+        )
+        .unwrap();
+        config.rename("two", "deux").unwrap();
+        assert_eq!(
+            config.doc.to_string(),
+            r#"[plugins]
+one = { github = "rossmacarthur/one" }
+deux = { github = "rossmacarthur/two" }
+three = { github = "rossmacarthur/three" }
+"#
+        );
+    }
+
+    #[test]
+    fn edit_config_rename_errors() {
+        let mut config = EditConfig::from_str(
+            r#"
+[plugins.one]
+github = "rossmacarthur/one"
+
+[plugins.two]
+github = "rossmacarthur/two"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.rename("three", "four").unwrap_err().to_string(),
+            "plugin with name `three` does not exist"
+        );
+        assert_eq!(
+            config.rename("one", "two").unwrap_err().to_string(),
+            "plugin with name `two` already exists"
+        );
+    }
+
+    #[test]
+    fn edit_config_get_and_set() {
+        let mut config = EditConfig::from_str(
+            r#"
+# the shell
+shell = "bash"
+
+[plugins.one]
+github = "rossmacarthur/one"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.get("shell").unwrap(), Some("bash".into()));
+        assert_eq!(config.get("apply").unwrap(), None);
+
+        config.set("shell", &["zsh".into()]).unwrap();
+        config
+            .set("apply", &["source".into(), "PATH".into()])
+            .unwrap();
+        config.set("strict", &["true".into()]).unwrap();
+        assert_eq!(config.get("shell").unwrap(), Some("zsh".into()));
+        assert_eq!(config.get("apply").unwrap(), Some("source\nPATH".into()));
+        assert_eq!(
+            config.doc.to_string(),
+            r#"
+# the shell
+shell = "zsh"
+apply = ["source", "PATH"]
+strict = true
+
+[plugins.one]
+github = "rossmacarthur/one"
+"#
+        );
+    }
+
+    #[test]
+    fn edit_config_set_errors() {
+        let mut config = EditConfig::from_str("").unwrap();
+        assert_eq!(
+            config
+                .set("shell", &["tcsh".into()])
+                .unwrap_err()
+                .to_string(),
+            "expected one of `bash`, `fish`, `nu`, or `zsh`, got `tcsh`"
+        );
+        assert_eq!(
+            config
+                .set("strict", &["yes".into()])
+                .unwrap_err()
+                .to_string(),
+            "`yes` is not a valid boolean"
+        );
+        assert_eq!(
+            config
+                .set("shell", &["bash".into(), "zsh".into()])
+                .unwrap_err()
+                .to_string(),
+            "`shell` takes exactly one value"
+        );
+        assert_eq!(
+            config.get("plugins").unwrap_err().to_string(),
+            "unknown setting `plugins`, expected one of `shell`, `proto`, \
+             `track_shell_version`, `strict`, `match`, `apply`"
+        );
+    }
+
     #[test]
     fn edit_config_others_add_git() {
         let mut config = EditConfig::from_str(
@@ -246,6 +914,7 @@ use = ["{{ name }}.zsh"]
                     reference: Some(GitReference::Tag("0.1.0".to_string())),
                     ..Default::default()
                 }),
+                None,
             )
             .unwrap();
         assert_eq!(
@@ -269,4 +938,148 @@ tag = "0.1.0"
     "#
         );
     }
+
+    #[test]
+    fn edit_config_others_remove_preserves_comments() {
+        let mut config = EditConfig::from_str(
+            r#"
+# test configuration file
+shell = "zsh" # the shell
+
+# yes this is the pure plugin
+[plugins.pure]
+github = "sindresorhus/pure" # upstream
+apply = ["prompt"]
+
+# the test plugin
+[plugins.sheldon-test]
+github = "rossmacarthur/sheldon-test"
+
+# syntax highlighting
+[plugins.zsh-syntax-highlighting]
+github = "zsh-users/zsh-syntax-highlighting"
+    "#,
+        )
+        .unwrap();
+        config.remove("sheldon-test");
+        assert_eq!(
+            config.doc.to_string(),
+            r#"
+# test configuration file
+shell = "zsh" # the shell
+
+# yes this is the pure plugin
+[plugins.pure]
+github = "sindresorhus/pure" # upstream
+apply = ["prompt"]
+
+# syntax highlighting
+[plugins.zsh-syntax-highlighting]
+github = "zsh-users/zsh-syntax-highlighting"
+    "#
+        );
+    }
+
+    #[test]
+    fn edit_config_matching_glob() {
+        let config = EditConfig::from_str(
+            r#"
+[plugins.pure]
+github = "sindresorhus/pure"
+
+[plugins.zsh-autosuggestions]
+github = "zsh-users/zsh-autosuggestions"
+
+[plugins.zsh-syntax-highlighting]
+github = "zsh-users/zsh-syntax-highlighting"
+    "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.matching("zsh-*").unwrap(),
+            ["zsh-autosuggestions", "zsh-syntax-highlighting"]
+        );
+        assert_eq!(config.matching("pure").unwrap(), ["pure"]);
+        assert_eq!(
+            config.matching("oh-my-*").unwrap_err().to_string(),
+            "no plugins match `oh-my-*`"
+        );
+    }
+
+    #[test]
+    fn edit_config_migrate() {
+        let mut config = EditConfig::from_str(
+            r#"
+# test configuration file
+
+[templates]
+# load hooks
+source = """{{ hooks | get: "pre" | nl }}{% for file in files %}source "{{ file }}"
+{% endfor %}{{ hooks|get:"post" | nl }}"""
+PATH = 'export PATH="{{ dir }}:$PATH"'
+
+[plugins.pure]
+github = "sindresorhus/pure"
+# clone using ssh
+protocol = "ssh" # trailing
+use = ["{{ name }}.zsh"]
+
+[plugins]
+sheldon-test = { github = "rossmacarthur/sheldon-test", protocol = "git", tag = "0.1.0" }
+"#,
+        )
+        .unwrap();
+
+        let rewrites = config.migrate();
+
+        assert_eq!(
+            rewrites,
+            [
+                "`plugins.pure.protocol` to `plugins.pure.proto`",
+                "`plugins.sheldon-test.protocol` to `plugins.sheldon-test.proto`",
+                "`get` filter in `templates.source`",
+            ]
+        );
+        assert_eq!(
+            config.to_string(),
+            r#"
+# test configuration file
+
+[templates]
+# load hooks
+source = """
+{{ hooks?.pre | nl }}{% for file in files %}source \"{{ file }}\"
+{% endfor %}{{ hooks?.post | nl }}"""
+PATH = 'export PATH="{{ dir }}:$PATH"'
+
+[plugins.pure]
+github = "sindresorhus/pure"
+# clone using ssh
+proto = "ssh" # trailing
+use = ["{{ name }}.zsh"]
+
+[plugins]
+sheldon-test = { github = "rossmacarthur/sheldon-test", proto = "git", tag = "0.1.0" }
+"#
+        );
+
+        let raw = toml::from_str(&config.to_string()).unwrap();
+        let mut warnings = Vec::new();
+        crate::config::normalize::normalize(raw, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        assert!(config.migrate().is_empty());
+    }
+
+    #[test]
+    fn edit_config_migrate_proto_already_set() {
+        let contents = r#"
+[plugins.pure]
+github = "sindresorhus/pure"
+proto = "ssh"
+protocol = "git"
+"#;
+        let mut config = EditConfig::from_str(contents).unwrap();
+        assert!(config.migrate().is_empty());
+        assert_eq!(config.to_string(), contents);
+    }
 }
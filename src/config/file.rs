@@ -15,7 +15,7 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 use url::Url;
 
-use crate::config::{GitReference, Shell};
+use crate::config::{GitReference, Rule, Shell, Template, Use};
 
 /// The contents of the configuration file.
 #[derive(Debug, Default, Deserialize)]
@@ -23,13 +23,28 @@ use crate::config::{GitReference, Shell};
 pub struct RawConfig {
     /// What type of shell is being used.
     pub shell: Option<Shell>,
+    /// The default protocol to use when cloning a repository.
+    pub proto: Option<GitProtocol>,
+    /// Whether to relock when the version of the shell changes.
+    pub track_shell_version: Option<bool>,
+    /// Whether unused config keys are errors instead of warnings.
+    pub strict: Option<bool>,
     /// Which files to match and use in a plugin's directory.
     #[serde(rename = "match")]
     pub matches: Option<Vec<String>>,
     /// The default list of template names to apply to each matched file.
     pub apply: Option<Vec<String>>,
-    /// A map of name to template string.
-    pub templates: IndexMap<String, String>,
+    /// A map of name to a list of template names that `apply` can reference as
+    /// `@name`.
+    pub apply_groups: IndexMap<String, Vec<String>>,
+    /// A map of name to template.
+    pub templates: IndexMap<String, Template>,
+    /// Rules for which templates to apply to matching files.
+    pub rules: Vec<Rule>,
+    /// Hooks executed before and after all plugins.
+    pub hooks: BTreeMap<String, String>,
+    /// Default values for fields that each plugin inherits.
+    pub defaults: RawDefaults,
     /// A map of name to plugin.
     pub plugins: IndexMap<String, RawPlugin>,
     /// Any extra keys,
@@ -37,27 +52,75 @@ pub struct RawConfig {
     pub rest: Option<toml::Value>,
 }
 
+/// Default values for plugin fields.
+///
+/// Each field is only used if the plugin doesn't set it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawDefaults {
+    /// Which directory to use in each plugin.
+    pub dir: Option<String>,
+    /// Which files to use in each plugin's directory.
+    #[serde(rename = "use")]
+    pub uses: Option<Vec<Use>>,
+    /// What templates to apply to each matched file.
+    pub apply: Option<Vec<String>>,
+    /// Only install each plugin if one of the given profiles is set.
+    pub profiles: Option<Vec<String>>,
+    /// Hooks executed during template evaluation.
+    pub hooks: Option<BTreeMap<String, String>>,
+    /// Custom values available to templates under the `vars` namespace.
+    pub vars: Option<BTreeMap<String, String>>,
+    /// Any extra keys,
+    #[serde(flatten, deserialize_with = "deserialize_rest_toml_value")]
+    pub rest: Option<toml::Value>,
+}
+
 /// The actual plugin configuration.
 #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
 #[serde(default)]
 pub struct RawPlugin {
     /// A clonable Git repository.
-    pub git: Option<Url>,
+    ///
+    /// Besides URLs this can be an SCP-like address such as
+    /// `git@github.com:owner/repo.git`.
+    pub git: Option<String>,
     /// A clonable Gist repository.
     pub gist: Option<GistRepository>,
     /// A clonable GitHub repository.
     pub github: Option<GitHubRepository>,
+    /// A clonable Bitbucket repository.
+    pub bitbucket: Option<BitbucketRepository>,
     /// A downloadable file.
-    pub remote: Option<Url>,
+    ///
+    /// This URL can contain template parameters.
+    pub remote: Option<String>,
     /// A local directory.
     pub local: Option<PathBuf>,
     /// An inline script.
     pub inline: Option<String>,
     /// What protocol to use when cloning a repository.
     pub proto: Option<GitProtocol>,
+    /// The host to clone a Gist, GitHub, or Bitbucket style repository from.
+    pub host: Option<String>,
+    /// The user to authenticate as when cloning a repository using SSH.
+    pub ssh_user: Option<String>,
+    /// The port to connect to when cloning a repository using SSH.
+    pub ssh_port: Option<u16>,
     /// The Git reference to checkout.
     #[serde(flatten)]
     pub reference: Option<GitReference>,
+    /// Mirror URLs to clone a Git repository from if the main one fails.
+    pub mirrors: Option<Vec<Url>>,
+    /// Commands to run in a Git repository after it is cloned or updated.
+    #[serde(deserialize_with = "deserialize_string_or_vec")]
+    pub post_clone: Option<Vec<String>>,
+    /// Whether to rewrite a GitHub blob URL in `remote` to the raw file URL,
+    /// defaults to `true`.
+    pub rewrite: Option<bool>,
+    /// Whether `remote` is an index file listing the URLs of the files to
+    /// download, defaults to `false`.
+    pub remote_index: Option<bool>,
     /// Which directory to use in this plugin.
     ///
     /// This directory can contain template parameters.
@@ -65,17 +128,22 @@ pub struct RawPlugin {
     /// Which files to use in this plugin's directory. If this is `None` then
     /// this will figured out based on the global `matches` field.
     ///
-    /// These files can contain template parameters.
+    /// These files can contain template parameters. Each one can instead be a
+    /// table with the templates to apply to its files.
     #[serde(rename = "use")]
-    pub uses: Option<Vec<String>>,
+    pub uses: Option<Vec<Use>>,
     /// What templates to apply to each matched file. If this is `None` then the
     /// default templates will be applied.
     pub apply: Option<Vec<String>>,
     /// If configured, only installs this plugin if one of the given profiles is
     /// set in the SHELDON_PROFILE environment variable.
     pub profiles: Option<Vec<String>>,
+    /// Whether this plugin should be installed at all, defaults to `true`.
+    pub enabled: Option<bool>,
     /// Hooks executed during template evaluation.
     pub hooks: Option<BTreeMap<String, String>>,
+    /// Custom values available to templates under the `vars` namespace.
+    pub vars: Option<BTreeMap<String, String>>,
     /// Any extra keys,
     #[serde(flatten, deserialize_with = "deserialize_rest_toml_value")]
     pub rest: Option<toml::Value>,
@@ -99,6 +167,15 @@ pub struct GitHubRepository {
     pub name: String,
 }
 
+/// A Bitbucket repository identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitbucketRepository {
+    /// The Bitbucket username / workspace.
+    pub owner: String,
+    /// The Bitbucket repository name.
+    pub name: String,
+}
+
 /// The Git protocol.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GitProtocol {
@@ -116,6 +193,7 @@ impl fmt::Display for Shell {
         match self {
             Self::Bash => f.write_str("bash"),
             Self::Fish => f.write_str("fish"),
+            Self::Nu => f.write_str("nu"),
             Self::Zsh => f.write_str("zsh"),
         }
     }
@@ -154,6 +232,13 @@ impl fmt::Display for GitHubRepository {
     }
 }
 
+impl fmt::Display for BitbucketRepository {
+    /// Displays as "{owner}/{repository}".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
 macro_rules! impl_serialize_as_str {
     ($name:ident) => {
         impl Serialize for $name {
@@ -167,24 +252,59 @@ macro_rules! impl_serialize_as_str {
     };
 }
 
+impl Serialize for Template {
+    /// Serializes as a plain string unless the template has conditions.
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        match &self.when_host {
+            None => serializer.serialize_str(&self.value),
+            Some(when_host) => {
+                let mut s = serializer.serialize_struct("Template", 2)?;
+                s.serialize_field("value", &self.value)?;
+                s.serialize_field("when_host", when_host)?;
+                s.end()
+            }
+        }
+    }
+}
+
+impl Serialize for Use {
+    /// Serializes as a plain string unless the glob has its own templates.
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        match &self.apply {
+            None => serializer.serialize_str(&self.pattern),
+            Some(apply) => {
+                let mut s = serializer.serialize_struct("Use", 2)?;
+                s.serialize_field("match", &self.pattern)?;
+                s.serialize_field("apply", apply)?;
+                s.end()
+            }
+        }
+    }
+}
+
 impl_serialize_as_str! { Shell }
 impl_serialize_as_str! { GitProtocol }
 impl_serialize_as_str! { GistRepository }
 impl_serialize_as_str! { GitHubRepository }
+impl_serialize_as_str! { BitbucketRepository }
 
 ////////////////////////////////////////////////////////////////////////////////
 // Deserialization implementations
 ////////////////////////////////////////////////////////////////////////////////
 
-impl Default for Shell {
-    fn default() -> Self {
-        Self::Zsh
-    }
-}
-
 /// Produced when we fail to parse the shell type.
 #[derive(Debug, Error)]
-#[error("expected one of `bash`, `fish`, or `zsh`, got `{}`", self.0)]
+#[error("expected one of `bash`, `fish`, `nu`, or `zsh`, got `{}`", self.0)]
 pub struct ParseShellError(String);
 
 impl FromStr for Shell {
@@ -194,6 +314,7 @@ impl FromStr for Shell {
         match &*s.to_lowercase() {
             "bash" => Ok(Self::Bash),
             "fish" => Ok(Self::Fish),
+            "nu" => Ok(Self::Nu),
             "zsh" => Ok(Self::Zsh),
             s => Err(ParseShellError(s.to_string())),
         }
@@ -256,6 +377,25 @@ impl FromStr for GitHubRepository {
     }
 }
 
+/// Produced when we fail to parse a Bitbucket repository.
+#[derive(Debug, Error)]
+#[error("`{}` is not a valid Bitbucket repository, the username/repository should be provided", self.0)]
+pub struct ParseBitbucketRepositoryError(String);
+
+impl FromStr for BitbucketRepository {
+    type Err = ParseBitbucketRepositoryError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let re = regex!("^(?P<owner>[a-zA-Z0-9_-]+)/(?P<name>[a-zA-Z0-9\\._-]+)$");
+        let captures = re
+            .captures(s)
+            .ok_or_else(|| ParseBitbucketRepositoryError(s.to_string()))?;
+        let owner = captures.name("owner").unwrap().as_str().to_string();
+        let name = captures.name("name").unwrap().as_str().to_string();
+        Ok(Self { owner, name })
+    }
+}
+
 macro_rules! impl_deserialize_from_str {
     ($module:ident, $name:ident, $expecting:expr) => {
         mod $module {
@@ -294,6 +434,103 @@ impl_deserialize_from_str! { shell, Shell, "a supported shell type" }
 impl_deserialize_from_str! { git_protocol, GitProtocol, "a Git protocol type" }
 impl_deserialize_from_str! { gist_repository, GistRepository, "a Gist identifier" }
 impl_deserialize_from_str! { github_repository, GitHubRepository, "a GitHub repository" }
+impl_deserialize_from_str! { bitbucket_repository, BitbucketRepository, "a Bitbucket repository" }
+
+mod template {
+    use super::*;
+
+    struct Visitor;
+
+    impl<'de> de::Visitor<'de> for Visitor {
+        type Value = Template;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a template string or table")
+        }
+
+        fn visit_str<E>(self, value: &str) -> result::Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Template::from(value))
+        }
+
+        fn visit_map<M>(self, map: M) -> result::Result<Self::Value, M::Error>
+        where
+            M: de::MapAccess<'de>,
+        {
+            #[derive(Deserialize)]
+            #[serde(deny_unknown_fields)]
+            struct Table {
+                value: String,
+                when_host: Option<String>,
+            }
+
+            let Table { value, when_host } =
+                Table::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            Ok(Template { value, when_host })
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Template {
+        fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(Visitor)
+        }
+    }
+}
+
+mod uses {
+    use super::*;
+
+    struct Visitor;
+
+    impl<'de> de::Visitor<'de> for Visitor {
+        type Value = Use;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a glob string or table")
+        }
+
+        fn visit_str<E>(self, value: &str) -> result::Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Use::from(value))
+        }
+
+        fn visit_map<M>(self, map: M) -> result::Result<Self::Value, M::Error>
+        where
+            M: de::MapAccess<'de>,
+        {
+            #[derive(Deserialize)]
+            #[serde(deny_unknown_fields)]
+            struct Table {
+                #[serde(rename = "match")]
+                pattern: String,
+                apply: Vec<String>,
+            }
+
+            let Table { pattern, apply } =
+                Table::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            Ok(Use {
+                pattern,
+                apply: Some(apply),
+            })
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Use {
+        fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(Visitor)
+        }
+    }
+}
 
 /// Deserialize the remaining keys into an [`Option<toml::Value>`]. Empty tables
 /// are coerced to [`None`].
@@ -308,6 +545,26 @@ where
     })
 }
 
+/// Deserialize either a single string or a list of strings.
+fn deserialize_string_or_vec<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrVec {
+        String(String),
+        Vec(Vec<String>),
+    }
+
+    Ok(
+        Option::<StringOrVec>::deserialize(deserializer)?.map(|value| match value {
+            StringOrVec::String(s) => vec![s],
+            StringOrVec::Vec(v) => v,
+        }),
+    )
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Unit tests
 ////////////////////////////////////////////////////////////////////////////////
@@ -321,6 +578,7 @@ mod tests {
     #[test]
     fn shell_to_string() {
         assert_eq!(Shell::Bash.to_string(), "bash");
+        assert_eq!(Shell::Nu.to_string(), "nu");
         assert_eq!(Shell::Zsh.to_string(), "zsh");
     }
 
@@ -354,6 +612,15 @@ mod tests {
         assert_eq!(test.to_string(), "rossmacarthur/sheldon-test");
     }
 
+    #[test]
+    fn bitbucket_repository_to_string() {
+        let test = BitbucketRepository {
+            owner: "rossmacarthur".to_string(),
+            name: "sheldon-test".to_string(),
+        };
+        assert_eq!(test.to_string(), "rossmacarthur/sheldon-test");
+    }
+
     #[derive(Debug, Deserialize)]
     struct ShellTest {
         s: Shell,
@@ -365,6 +632,12 @@ mod tests {
         assert_eq!(test.s, Shell::Bash);
     }
 
+    #[test]
+    fn shell_deserialize_nu() {
+        let test: ShellTest = toml::from_str("s = 'nu'").unwrap();
+        assert_eq!(test.s, Shell::Nu);
+    }
+
     #[test]
     fn shell_deserialize_invalid() {
         let error = toml::from_str::<ShellTest>("s = 'ksh'").unwrap_err();
@@ -374,7 +647,7 @@ mod tests {
   |
 1 | s = 'ksh'
   |     ^^^^^
-expected one of `bash`, `fish`, or `zsh`, got `ksh`
+expected one of `bash`, `fish`, `nu`, or `zsh`, got `ksh`
 "
         );
     }
@@ -403,6 +676,38 @@ expected one of `bash`, `fish`, or `zsh`, got `ksh`
         assert_eq!(test.g, GitReference::Rev(String::from("cd65e828")));
     }
 
+    #[derive(Debug, Deserialize)]
+    struct TestTemplate {
+        t: Template,
+    }
+
+    #[test]
+    fn template_deserialize_as_str() {
+        let test: TestTemplate = toml::from_str("t = 'source {{ file }}'").unwrap();
+        assert_eq!(test.t, Template::from("source {{ file }}"));
+    }
+
+    #[test]
+    fn template_deserialize_when_host() {
+        let test: TestTemplate =
+            toml::from_str("t = { value = 'source {{ file }}', when_host = 'gist.github.com' }")
+                .unwrap();
+        assert_eq!(
+            test.t,
+            Template {
+                value: "source {{ file }}".into(),
+                when_host: Some("gist.github.com".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn template_deserialize_unknown_field() {
+        let error =
+            toml::from_str::<TestTemplate>("t = { value = 'test', when = 'test' }").unwrap_err();
+        assert!(error.to_string().contains("unknown field `when`"));
+    }
+
     #[derive(Debug, Deserialize)]
     struct TestGistRepository {
         g: GistRepository,
@@ -501,10 +806,42 @@ expected one of `bash`, `fish`, or `zsh`, got `ksh`
         );
     }
 
+    #[derive(Debug, Deserialize)]
+    struct TestBitbucketRepository {
+        b: BitbucketRepository,
+    }
+
+    #[test]
+    fn bitbucket_repository_deserialize() {
+        let test: TestBitbucketRepository =
+            toml::from_str("b = 'rossmacarthur/sheldon-test'").unwrap();
+        assert_eq!(
+            test.b,
+            BitbucketRepository {
+                owner: "rossmacarthur".to_string(),
+                name: "sheldon-test".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn bitbucket_repository_deserialize_no_slashes() {
+        let error = toml::from_str::<TestBitbucketRepository>("b = 'noslash'").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "TOML parse error at line 1, column 5
+  |
+1 | b = 'noslash'
+  |     ^^^^^^^^^
+`noslash` is not a valid Bitbucket repository, the username/repository should be provided
+"
+        );
+    }
+
     #[test]
     fn raw_plugin_deserialize_git() {
         let expected = RawPlugin {
-            git: Some(Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap()),
+            git: Some("https://github.com/rossmacarthur/sheldon-test".into()),
             ..Default::default()
         };
         let plugin: RawPlugin =
@@ -535,6 +872,33 @@ expected one of `bash`, `fish`, or `zsh`, got `ksh`
         assert_eq!(plugin, expected);
     }
 
+    #[test]
+    fn raw_plugin_deserialize_enabled() {
+        let expected = RawPlugin {
+            enabled: Some(false),
+            ..Default::default()
+        };
+        let plugin: RawPlugin = toml::from_str("enabled = false").unwrap();
+        assert_eq!(plugin, expected);
+    }
+
+    #[test]
+    fn raw_plugin_deserialize_post_clone() {
+        let expected = RawPlugin {
+            post_clone: Some(vec_into!["make"]),
+            ..Default::default()
+        };
+        let plugin: RawPlugin = toml::from_str("post_clone = 'make'").unwrap();
+        assert_eq!(plugin, expected);
+
+        let expected = RawPlugin {
+            post_clone: Some(vec_into!["make", "make install"]),
+            ..Default::default()
+        };
+        let plugin: RawPlugin = toml::from_str("post_clone = ['make', 'make install']").unwrap();
+        assert_eq!(plugin, expected);
+    }
+
     #[test]
     fn raw_plugin_deserialize_hooks() {
         let expected = RawPlugin {
@@ -547,4 +911,28 @@ expected one of `bash`, `fish`, or `zsh`, got `ksh`
         let plugin: RawPlugin = toml::from_str("hooks.pre = 'PRE'\nhooks.post = 'POST'").unwrap();
         assert_eq!(plugin, expected);
     }
+
+    #[test]
+    fn raw_plugin_deserialize_vars() {
+        let expected = RawPlugin {
+            vars: Some(BTreeMap::from([("theme".into(), "dark".into())])),
+            ..Default::default()
+        };
+        let plugin: RawPlugin = toml::from_str("vars.theme = 'dark'").unwrap();
+        assert_eq!(plugin, expected);
+    }
+
+    #[test]
+    fn raw_plugin_deserialize_vars_not_string() {
+        let error = toml::from_str::<RawPlugin>("vars.size = 3").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "TOML parse error at line 1, column 13
+  |
+1 | vars.size = 3
+  |             ^
+invalid type: integer `3`, expected a string
+"
+        );
+    }
 }
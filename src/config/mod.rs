@@ -7,7 +7,9 @@ mod normalize;
 mod profile;
 
 use std::collections::BTreeMap;
+use std::env;
 use std::fs;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::str;
 
@@ -16,31 +18,75 @@ use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-pub use crate::config::clean::clean;
-pub use crate::config::edit::{EditConfig, EditPlugin};
-pub use crate::config::file::{GistRepository, GitHubRepository, GitProtocol, RawPlugin};
+pub use crate::config::clean::{clean, prune};
+pub use crate::config::edit::{EditConfig, EditPlugin, Position};
+pub use crate::config::file::{
+    BitbucketRepository, GistRepository, GitHubRepository, GitProtocol, RawPlugin,
+};
+pub use crate::config::normalize::parse_git_url;
 pub use crate::config::profile::MatchesProfile;
 
 /// The user configuration.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Config {
     /// What type of shell is being used.
     pub shell: Shell,
+    /// Whether to relock when the version of the shell changes.
+    pub track_shell_version: bool,
     /// Which files to match and use in a plugin's directory.
     pub matches: Option<Vec<String>>,
     /// The default list of template names to apply to each matched file.
     pub apply: Option<Vec<String>>,
-    /// A map of name to template string.
-    pub templates: IndexMap<String, String>,
+    /// A map of name to template.
+    pub templates: IndexMap<String, Template>,
+    /// Rules for which templates to apply to matching files.
+    pub rules: Vec<Rule>,
+    /// Hooks executed before and after all plugins.
+    pub hooks: BTreeMap<String, String>,
     /// Each configured plugin.
     pub plugins: Vec<Plugin>,
 }
 
+/// A template that can be applied to plugins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    /// The actual template string.
+    pub value: String,
+    /// Only apply this template to plugins whose source is from this host.
+    pub when_host: Option<String>,
+}
+
+/// A rule that applies templates to files matching a glob.
+///
+/// Rules are consulted for each file of a plugin that does not specify its own
+/// `apply`, files that match no rule use the global `apply`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// A glob matched against the file name, or against the path relative to
+    /// the plugin directory if it contains a `/`.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// What templates to apply to each matching file.
+    pub apply: Vec<String>,
+}
+
+/// A glob for which files to use in a plugin's directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Use {
+    /// The glob pattern, this can contain template parameters.
+    pub pattern: String,
+    /// What templates to apply to the matched files instead of the plugin's.
+    pub apply: Option<Vec<String>>,
+}
+
 /// The type of shell that we are using.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub enum Shell {
     Bash,
     Fish,
+    Nu,
+    #[default]
     Zsh,
 }
 
@@ -61,13 +107,17 @@ pub struct ExternalPlugin {
     /// Which directory to use in this plugin.
     pub dir: Option<String>,
     /// What files to use in the plugin's directory.
-    pub uses: Option<Vec<String>>,
+    pub uses: Option<Vec<Use>>,
     /// What templates to apply to each matched file.
     pub apply: Option<Vec<String>>,
     /// Only use this plugin under one of the given profiles.
     pub profiles: Option<Vec<String>>,
+    /// Whether to use this plugin at all.
+    pub enabled: Option<bool>,
     /// Hooks executed during template evaluation.
     pub hooks: Option<BTreeMap<String, String>>,
+    /// Custom values available to templates under the `vars` namespace.
+    pub vars: Option<BTreeMap<String, String>>,
 }
 
 /// The source for a [`Plugin`].
@@ -77,13 +127,25 @@ pub enum Source {
     Git {
         url: Url,
         reference: Option<GitReference>,
+        options: Box<GitOptions>,
     },
-    /// A remote file.
-    Remote { url: Url },
+    /// A remote file, or an index file listing remote files if `index` is set.
+    Remote { url: Url, index: bool },
     /// A local directory.
     Local { dir: PathBuf },
 }
 
+/// Less commonly used options for a Git [`Source`].
+///
+/// These are boxed in the source so that it stays small.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct GitOptions {
+    /// Mirror URLs to clone from if the main one fails.
+    pub mirrors: Vec<Url>,
+    /// Commands to run after the repository is cloned or updated.
+    pub post_clone: Vec<String>,
+}
+
 /// A Git reference.
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -103,12 +165,130 @@ pub struct InlinePlugin {
     pub name: String,
     /// The actual source.
     pub raw: String,
+    /// A template to wrap the source with.
+    pub apply: Option<String>,
     /// Only use this plugin under one of the given profiles.
     pub profiles: Option<Vec<String>>,
+    /// Whether to use this plugin at all.
+    pub enabled: Option<bool>,
     /// Hooks executed during template evaluation.
     pub hooks: Option<BTreeMap<String, String>>,
 }
 
+impl From<&str> for Template {
+    fn from(value: &str) -> Self {
+        Self {
+            value: value.to_string(),
+            when_host: None,
+        }
+    }
+}
+
+impl From<&str> for Use {
+    fn from(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            apply: None,
+        }
+    }
+}
+
+impl From<String> for Use {
+    fn from(pattern: String) -> Self {
+        Self {
+            pattern,
+            apply: None,
+        }
+    }
+}
+
+impl Config {
+    /// Add a local plugin for each subdirectory of the given directory.
+    ///
+    /// Plugins are named after their directory. Hidden directories and
+    /// directories with the same name as a configured plugin are skipped.
+    pub fn add_plugins_dir(&mut self, dir: &Path) -> Result<()> {
+        // The directory is given on the command line so it is relative to the
+        // current directory, not the config file.
+        let dir = &env::current_dir()
+            .map(|cwd| cwd.join(dir))
+            .unwrap_or_else(|_| dir.to_path_buf());
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(dir)
+            .with_context(|| format!("failed to read plugins directory `{}`", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+        dirs.sort();
+
+        for dir in dirs {
+            let name = dir
+                .file_name()
+                .unwrap()
+                .to_str()
+                .with_context(|| {
+                    format!("plugin directory `{}` is not valid UTF-8", dir.display())
+                })?
+                .to_string();
+            if name.starts_with('.') || self.plugins.iter().any(|p| p.name() == name) {
+                continue;
+            }
+            self.plugins.push(Plugin::External(ExternalPlugin {
+                name,
+                source: Source::Local { dir },
+                dir: None,
+                uses: None,
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Describe each template available to the `apply` field.
+    ///
+    /// This is the shell's default templates merged with the configured
+    /// templates, each on its own line with where it is defined.
+    pub fn list_templates(&self) -> String {
+        let defaults = self.shell.default_templates();
+        let templates = self.shell.merge_templates(self.templates.clone());
+        let width = templates.keys().map(String::len).max().unwrap_or(0);
+        let mut s = String::new();
+        for (name, template) in &templates {
+            let origin = match (
+                self.templates.contains_key(name),
+                defaults.contains_key(name),
+            ) {
+                (true, true) => "config (overrides built-in)",
+                (true, false) => "config",
+                (false, _) => "built-in",
+            };
+            s.push_str(&format!("{name:width$}  {origin}"));
+            if let Some(host) = &template.when_host {
+                s.push_str(&format!(", when host is `{host}`"));
+            }
+            s.push('\n');
+        }
+        s
+    }
+}
+
+impl Plugin {
+    /// The name of this plugin.
+    fn name(&self) -> &str {
+        match self {
+            Self::External(plugin) => &plugin.name,
+            Self::Inline(plugin) => &plugin.name,
+        }
+    }
+}
+
 /// Load a [`Config`] from the given path.
 pub fn from_path<P>(path: P, warnings: &mut Vec<Error>) -> Result<Config>
 where
@@ -117,7 +297,110 @@ where
     let path = path.as_ref();
     let bytes =
         fs::read(path).with_context(|| format!("failed to read from `{}`", path.display()))?;
+    from_bytes(bytes, warnings)
+}
+
+/// Read only the shell from the config file at the given path.
+///
+/// This is much cheaper than loading the whole config, and is enough to check
+/// whether a lock file was generated for the same shell.
+pub fn shell_from_path<P>(path: P) -> Result<Shell>
+where
+    P: AsRef<Path>,
+{
+    #[derive(Deserialize)]
+    struct RawShell {
+        shell: Option<Shell>,
+    }
+
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read from `{}`", path.display()))?;
+    let raw: RawShell =
+        toml::from_str(&contents).context("failed to deserialize contents as TOML")?;
+    Ok(raw.shell.unwrap_or_default())
+}
+
+/// Load a [`Config`] from the standard input.
+pub fn from_stdin(warnings: &mut Vec<Error>) -> Result<Config> {
+    let mut bytes = Vec::new();
+    io::stdin()
+        .read_to_end(&mut bytes)
+        .context("failed to read from stdin")?;
+    from_bytes(bytes, warnings)
+}
+
+fn from_bytes(bytes: Vec<u8>, warnings: &mut Vec<Error>) -> Result<Config> {
     let contents = String::from_utf8(bytes).context("config file contents are not valid UTF-8")?;
     let raw_config = toml::from_str(&contents).context("failed to deserialize contents as TOML")?;
     normalize::normalize(raw_config, warnings)
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_add_plugins_dir() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        for name in ["two", "one", ".hidden", "existing"] {
+            fs::create_dir(dir.join(name)).unwrap();
+        }
+        fs::write(dir.join("file.zsh"), "").unwrap();
+        let mut config = Config {
+            plugins: vec![Plugin::Inline(InlinePlugin {
+                name: "existing".into(),
+                raw: "echo 'testing...'".into(),
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+            })],
+            ..Config::default()
+        };
+
+        config.add_plugins_dir(dir).unwrap();
+
+        let names: Vec<_> = config.plugins.iter().map(Plugin::name).collect();
+        assert_eq!(names, ["existing", "one", "two"]);
+        assert!(matches!(
+            &config.plugins[1],
+            Plugin::External(ExternalPlugin { source: Source::Local { dir: d }, .. }) if *d == dir.join("one")
+        ));
+    }
+
+    #[test]
+    fn config_list_templates() {
+        let config = Config {
+            shell: Shell::Bash,
+            templates: IndexMap::from([
+                (
+                    "source".into(),
+                    Template {
+                        value: "source \"{{ file }}\"".into(),
+                        when_host: None,
+                    },
+                ),
+                (
+                    "function".into(),
+                    Template {
+                        value: "fpath+=( \"{{ dir }}\" )".into(),
+                        when_host: Some("github.com".into()),
+                    },
+                ),
+            ]),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.list_templates(),
+            "PATH      built-in\n\
+             source    config (overrides built-in)\n\
+             function  config, when host is `github.com`\n"
+        );
+    }
+}
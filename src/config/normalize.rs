@@ -1,14 +1,23 @@
 //! Normalize a raw config from the file into a [`Config`].
 
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::str;
 use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context as ResultExt, Error, Result};
 use indexmap::IndexMap;
+use itertools::Itertools;
+use regex_macro::regex;
+use serde::Serialize;
+use thiserror::Error;
 use url::Url;
 
-use crate::config::file::{GitProtocol, RawConfig, RawPlugin};
-use crate::config::{Config, ExternalPlugin, InlinePlugin, Plugin, Shell, Source};
+use crate::config::file::{GitProtocol, RawConfig, RawDefaults, RawPlugin};
+use crate::config::{
+    Config, ExternalPlugin, GitOptions, InlinePlugin, Plugin, Shell, Source, Template, Use,
+};
 use crate::util::TEMPLATE_ENGINE;
 
 /// The Gist domain host.
@@ -17,47 +26,155 @@ const GIST_HOST: &str = "gist.github.com";
 /// The GitHub domain host.
 const GITHUB_HOST: &str = "github.com";
 
+/// The Bitbucket domain host.
+const BITBUCKET_HOST: &str = "bitbucket.org";
+
+/// The GitHub raw content domain host.
+const GITHUB_RAW_HOST: &str = "raw.githubusercontent.com";
+
+/// A key in the config file that is not used.
+#[derive(Debug, Error)]
+#[error("unused config key: `{}`", self.0)]
+struct UnusedKey(String);
+
 /// Normalize a raw config from the file into a [`Config`].
 pub fn normalize(raw_config: RawConfig, warnings: &mut Vec<Error>) -> Result<Config> {
     let RawConfig {
         shell,
+        proto,
+        track_shell_version,
+        strict,
         matches,
         apply,
+        apply_groups,
         templates,
+        mut rules,
+        hooks,
+        mut defaults,
         plugins,
         rest,
     } = raw_config;
 
+    let start = warnings.len();
+
     check_extra_toml(rest, |key| {
-        warnings.push(anyhow!("unused config key: `{key}`"));
+        warnings.push(UnusedKey(key.to_string()).into());
+    });
+    check_extra_toml(defaults.rest.take(), |key| {
+        warnings.push(UnusedKey(format!("defaults.{key}")).into());
     });
 
     // Check that the templates can be compiled.
     for (name, template) in &templates {
         TEMPLATE_ENGINE
-            .compile(template)
+            .compile(&template.value)
             .with_context(|| format!("failed to compile template `{name}`"))?;
     }
 
     let shell = shell.unwrap_or_default();
 
+    // Check that the templates only include other templates that exist.
+    for (name, template) in &templates {
+        for include in included_templates(&template.value) {
+            if include == name {
+                bail!(
+                    "template `{name}` includes itself, use a different name to extend the \
+                     built-in `{name}` template"
+                );
+            }
+            if !shell.default_templates().contains_key(include) && !templates.contains_key(include)
+            {
+                bail!("template `{name}` includes unknown template `{include}`");
+            }
+        }
+    }
+
+    // Check that the apply groups are not recursive and use known templates.
+    for name in apply_groups.keys() {
+        let group = expand_apply_groups(Some(vec![format!("@{name}")]), &apply_groups)?;
+        validate_template_names(shell, &group, &templates)
+            .with_context(|| format!("failed to normalize apply group `{name}`"))?;
+    }
+
+    let apply = expand_apply_groups(apply, &apply_groups).context("failed to normalize `apply`")?;
     validate_template_names(shell, &apply, &templates)?;
 
+    // Check that the rules have valid globs and templates.
+    for rule in &mut rules {
+        globset::Glob::new(&rule.pattern)
+            .with_context(|| format!("failed to parse rule glob `{}`", rule.pattern))?;
+        rule.apply =
+            expand_apply_groups(Some(rule.apply.clone()), &apply_groups)?.unwrap_or_default();
+        validate_template_names(shell, &Some(rule.apply.clone()), &templates)?;
+    }
+
+    let matches =
+        prepend_matches(matches, shell.default_matches()).context("failed to normalize `match`")?;
+
+    // Resolve any templates that should be appended to the global ones.
+    let global_apply = apply.as_deref().unwrap_or_else(|| Shell::default_apply());
+    defaults.apply = expand_apply_groups(defaults.apply.take(), &apply_groups)
+        .and_then(|apply| append_apply(apply, global_apply))
+        .context("failed to normalize `defaults.apply`")?;
+    defaults.uses = resolve_use_apply(defaults.uses.take(), &apply_groups, global_apply)
+        .context("failed to normalize `defaults.use`")?;
+
     // Normalize the plugins.
     let mut normalized_plugins = Vec::with_capacity(plugins.len());
 
-    for (name, plugin) in plugins {
+    for (name, mut plugin) in plugins {
         normalized_plugins.push(
-            normalize_plugin(plugin, name.clone(), shell, &templates, warnings)
+            expand_apply_groups(plugin.apply.take(), &apply_groups)
+                .and_then(|apply| {
+                    // Inline plugins apply a single template so there is
+                    // nothing to append to.
+                    if plugin.inline.is_some() && apply.iter().flatten().any(|n| n.starts_with('+'))
+                    {
+                        bail!("appending templates with `+` is not supported by inline plugins");
+                    }
+                    append_apply(apply, global_apply)
+                })
+                .and_then(|apply| {
+                    plugin.apply = apply;
+                    plugin.uses =
+                        resolve_use_apply(plugin.uses.take(), &apply_groups, global_apply)?;
+                    normalize_plugin(
+                        plugin,
+                        name.clone(),
+                        shell,
+                        proto,
+                        &defaults,
+                        &templates,
+                        warnings,
+                    )
+                })
                 .with_context(|| format!("failed to normalize plugin `{name}`"))?,
         );
     }
 
+    if strict == Some(true) {
+        let (unused, other): (Vec<_>, Vec<_>) =
+            warnings.drain(start..).partition(|w| w.is::<UnusedKey>());
+        warnings.extend(other);
+        if !unused.is_empty() {
+            bail!(
+                "found unused config keys and `strict` is enabled: {}",
+                unused
+                    .iter()
+                    .map(|w| format!("`{}`", w.downcast_ref::<UnusedKey>().unwrap().0))
+                    .join(", ")
+            );
+        }
+    }
+
     Ok(Config {
         shell,
+        track_shell_version: track_shell_version.unwrap_or(false),
         matches,
         apply,
         templates,
+        rules,
+        hooks,
         plugins: normalized_plugins,
     })
 }
@@ -65,12 +182,16 @@ pub fn normalize(raw_config: RawConfig, warnings: &mut Vec<Error>) -> Result<Con
 /// Normalize a raw plugin from the file into a [`Plugin`] which is simpler and
 /// easier to handle.
 ///
-/// For example gist and github sources are converted to a [`Source::Git`].
+/// For example gist and github sources are converted to a [`Source::Git`]
+/// using the plugin's protocol, or else the given default protocol. Any fields
+/// that the plugin doesn't set are inherited from the given defaults.
 fn normalize_plugin(
     raw_plugin: RawPlugin,
     name: String,
     shell: Shell,
-    templates: &IndexMap<String, String>,
+    default_proto: Option<GitProtocol>,
+    defaults: &RawDefaults,
+    templates: &IndexMap<String, Template>,
     warnings: &mut Vec<Error>,
 ) -> Result<Plugin> {
     enum TempSource {
@@ -82,21 +203,46 @@ fn normalize_plugin(
         git,
         gist,
         github,
+        bitbucket,
         remote,
         local,
         inline,
         mut proto,
+        host,
+        ssh_user,
+        ssh_port,
         reference,
+        mirrors,
+        post_clone,
+        rewrite,
+        remote_index,
         dir,
         uses,
         apply,
         profiles,
+        enabled,
         hooks,
+        vars,
         mut rest,
     } = raw_plugin;
 
     let is_reference_some = reference.is_some();
-    let is_gist_or_github = gist.is_some() || github.is_some();
+    let is_hosted = gist.is_some() || github.is_some() || bitbucket.is_some();
+
+    // Inherit the defaults for any fields that aren't set. Inline plugins only
+    // inherit the fields that make sense for them.
+    let profiles = profiles.or_else(|| defaults.profiles.clone());
+    let hooks = hooks.or_else(|| defaults.hooks.clone());
+    let (dir, uses, apply, vars) = if inline.is_some() {
+        (dir, uses, apply, vars)
+    } else {
+        (
+            dir.or_else(|| defaults.dir.clone()),
+            uses.or_else(|| defaults.uses.clone()),
+            apply.or_else(|| defaults.apply.clone()),
+            vars.or_else(|| defaults.vars.clone()),
+        )
+    };
 
     // Handle some deprecated items :/
     if proto.is_none() {
@@ -111,45 +257,100 @@ fn normalize_plugin(
     }
 
     check_extra_toml(rest, |key| {
-        warnings.push(anyhow!("unused config key: `plugins.{name}.{key}`"));
+        warnings.push(UnusedKey(format!("plugins.{name}.{key}")).into());
     });
 
-    let raw_source = match (git, gist, github, remote, local, inline) {
+    // The default protocol only applies to Gist, GitHub, and Bitbucket sources.
+    let hosted_proto = if is_hosted {
+        proto.or(default_proto)
+    } else {
+        proto
+    };
+
+    if (ssh_user.is_some() || ssh_port.is_some()) && hosted_proto != Some(GitProtocol::Ssh) {
+        bail!("the `ssh_user` and `ssh_port` fields are only supported when `proto` is `ssh`");
+    }
+
+    let raw_source = match (git, gist, github, bitbucket, remote, local, inline) {
         // `git` type
-        (Some(url), None, None, None, None, None) => {
-            TempSource::External(Source::Git { url, reference })
-        }
+        (Some(url), None, None, None, None, None, None) => TempSource::External(Source::Git {
+            url: parse_git_url(&url)?,
+            reference,
+            options: Box::default(),
+        }),
         // `gist` type
-        (None, Some(repository), None, None, None, None) => {
-            let url_str = format!(
-                "{}{}/{}",
-                proto.unwrap_or(GitProtocol::Https).prefix(),
-                GIST_HOST,
-                repository
-            );
-            let url = Url::parse(&url_str)
-                .with_context(|| format!("failed to construct Gist URL using `{repository}`"))?;
-            TempSource::External(Source::Git { url, reference })
+        (None, Some(repository), None, None, None, None, None) => {
+            let url = hosted_url(
+                "Gist",
+                host.as_deref().unwrap_or(GIST_HOST),
+                &repository,
+                hosted_proto,
+                ssh_user,
+                ssh_port,
+            )?;
+            TempSource::External(Source::Git {
+                url,
+                reference,
+                options: Box::default(),
+            })
         }
         // `github` type
-        (None, None, Some(repository), None, None, None) => {
-            let url_str = format!(
-                "{}{}/{}",
-                proto.unwrap_or(GitProtocol::Https).prefix(),
-                GITHUB_HOST,
-                repository
-            );
-            let url = Url::parse(&url_str)
-                .with_context(|| format!("failed to construct GitHub URL using `{repository}`"))?;
-            TempSource::External(Source::Git { url, reference })
+        (None, None, Some(repository), None, None, None, None) => {
+            let url = hosted_url(
+                "GitHub",
+                host.as_deref().unwrap_or(GITHUB_HOST),
+                &repository,
+                hosted_proto,
+                ssh_user,
+                ssh_port,
+            )?;
+            TempSource::External(Source::Git {
+                url,
+                reference,
+                options: Box::default(),
+            })
+        }
+        // `bitbucket` type
+        (None, None, None, Some(repository), None, None, None) => {
+            let url = hosted_url(
+                "Bitbucket",
+                host.as_deref().unwrap_or(BITBUCKET_HOST),
+                &repository,
+                hosted_proto,
+                ssh_user,
+                ssh_port,
+            )?;
+            TempSource::External(Source::Git {
+                url,
+                reference,
+                options: Box::default(),
+            })
         }
         // `remote` type
-        (None, None, None, Some(url), None, None) => TempSource::External(Source::Remote { url }),
+        (None, None, None, None, Some(url), None, None) => {
+            let url = render_remote_url(&url, &name, vars.as_ref())?;
+            let url = match github_raw_url(&url) {
+                Some(raw_url) if rewrite != Some(false) => {
+                    warnings.push(anyhow!(
+                        "rewrote GitHub blob URL `{url}` to `{raw_url}` for plugin `{name}`, set \
+                         `plugins.{name}.rewrite = false` to disable this"
+                    ));
+                    raw_url
+                }
+                _ => url,
+            };
+            TempSource::External(Source::Remote {
+                url,
+                index: remote_index.unwrap_or(false),
+            })
+        }
         // `local` type
-        (None, None, None, None, Some(dir), None) => TempSource::External(Source::Local { dir }),
+        (None, None, None, None, None, Some(dir), None) => {
+            TempSource::External(Source::Local { dir })
+        }
         // `inline` type
-        (None, None, None, None, None, Some(raw)) => TempSource::Inline(raw),
-        (None, None, None, None, None, None) => {
+        (None, None, None, None, None, None, Some(raw)) => TempSource::Inline(raw),
+        (None, None, None, None, None, None, None) => {
             bail!("plugin `{name}` has no source fields");
         }
         _ => {
@@ -163,11 +364,35 @@ fn normalize_plugin(
                 bail!(
                     "the `branch`, `tag`, and `rev` fields are not supported by this plugin type"
                 );
-            } else if proto.is_some() && !is_gist_or_github {
+            } else if proto.is_some() && !is_hosted {
                 bail!("the `proto` field is not supported by this plugin type");
+            } else if host.is_some() && !is_hosted {
+                bail!("the `host` field is not supported by this plugin type");
+            } else if mirrors.is_some() && !source.is_git() {
+                bail!("the `mirrors` field is not supported by this plugin type");
+            } else if rewrite.is_some() && !source.is_remote() {
+                bail!("the `rewrite` field is not supported by this plugin type");
+            } else if remote_index.is_some() && !source.is_remote() {
+                bail!("the `remote_index` field is not supported by this plugin type");
+            } else if post_clone.is_some() && !source.is_git() {
+                bail!("the `post_clone` field is not supported by this plugin type");
             }
+            let source = match source {
+                Source::Git { url, reference, .. } => Source::Git {
+                    url,
+                    reference,
+                    options: Box::new(GitOptions {
+                        mirrors: mirrors.unwrap_or_default(),
+                        post_clone: post_clone.unwrap_or_default(),
+                    }),
+                },
+                source => source,
+            };
 
             validate_template_names(shell, &apply, templates)?;
+            for u in uses.iter().flatten() {
+                validate_template_names(shell, &u.apply, templates)?;
+            }
 
             Ok(Plugin::External(ExternalPlugin {
                 name,
@@ -176,26 +401,41 @@ fn normalize_plugin(
                 uses,
                 apply,
                 profiles,
+                enabled,
                 hooks,
+                vars,
             }))
         }
         TempSource::Inline(raw) => {
             let unsupported = [
                 ("`proto` field is", proto.is_some()),
+                ("`host` field is", host.is_some()),
                 ("`branch`, `tag`, and `rev` fields are", is_reference_some),
+                ("`mirrors` field is", mirrors.is_some()),
+                ("`post_clone` field is", post_clone.is_some()),
+                ("`rewrite` field is", rewrite.is_some()),
+                ("`remote_index` field is", remote_index.is_some()),
                 ("`dir` field is", dir.is_some()),
                 ("`use` field is", uses.is_some()),
-                ("`apply` field is", apply.is_some()),
+                ("`vars` field is", vars.is_some()),
             ];
             for (field, is_some) in &unsupported {
                 if *is_some {
                     bail!("the {field} not supported by inline plugins");
                 }
             }
+            validate_template_names(shell, &apply, templates)?;
+            let apply = match apply {
+                Some(mut apply) if apply.len() == 1 => apply.pop(),
+                Some(_) => bail!("inline plugins can only apply a single template"),
+                None => None,
+            };
             Ok(Plugin::Inline(InlinePlugin {
                 name,
                 raw,
+                apply,
                 profiles,
+                enabled,
                 hooks,
             }))
         }
@@ -212,11 +452,140 @@ impl GitProtocol {
     }
 }
 
+/// Construct the Git URL of a repository on a hosting service like GitHub.
+///
+/// The `kind` is the name of the service used in the error message.
+fn hosted_url(
+    kind: &str,
+    host: &str,
+    repository: &dyn fmt::Display,
+    proto: Option<GitProtocol>,
+    ssh_user: Option<String>,
+    ssh_port: Option<u16>,
+) -> Result<Url> {
+    let proto = proto.unwrap_or(GitProtocol::Https);
+    let mut url = Url::parse(&format!("{}{host}/{repository}", proto.prefix()))
+        .with_context(|| format!("failed to construct {kind} URL using `{repository}`"))?;
+    set_ssh_options(&mut url, ssh_user, ssh_port)?;
+    Ok(url)
+}
+
+/// Override the default SSH user and port in the given URL.
+fn set_ssh_options(url: &mut Url, ssh_user: Option<String>, ssh_port: Option<u16>) -> Result<()> {
+    if let Some(user) = ssh_user {
+        url.set_username(&user)
+            .map_err(|_| anyhow!("failed to set SSH user `{user}` on URL `{url}`"))?;
+    }
+    if let Some(port) = ssh_port {
+        url.set_port(Some(port))
+            .map_err(|_| anyhow!("failed to set SSH port `{port}` on URL `{url}`"))?;
+    }
+    Ok(())
+}
+
+/// Parse the URL of a `git` source.
+///
+/// An SCP-like address such as `git@github.com:owner/repo.git` is converted to
+/// the equivalent `ssh://` URL and a `git+` prefix on a URL scheme, like
+/// `git+https://`, is removed.
+pub fn parse_git_url(url: &str) -> Result<Url> {
+    let url = url.trim();
+    let url = match url.strip_prefix("git+") {
+        Some(rest) if rest.contains("://") => rest,
+        _ => url,
+    };
+    let parsed = if url.contains("://") {
+        Url::parse(url)
+    } else if let Some(caps) = regex!(r"^(?:([^@/:]+)@)?([^@/:]+):(.+)$").captures(url) {
+        let user = caps.get(1).map(|m| format!("{}@", m.as_str()));
+        let path = caps[3].trim_start_matches('/');
+        Url::parse(&format!(
+            "ssh://{}{}/{path}",
+            user.as_deref().unwrap_or(""),
+            &caps[2]
+        ))
+    } else {
+        Url::parse(url)
+    };
+    parsed.with_context(|| {
+        format!(
+            "`{url}` is not a valid Git URL, expected a URL like `https://host/owner/repo` or an \
+             address like `git@host:owner/repo`"
+        )
+    })
+}
+
+/// Render the template parameters in a `remote` URL and parse the result.
+///
+/// The plugin's name, its `vars`, and the environment variables are available
+/// to the template. URLs without any template syntax are parsed as is.
+fn render_remote_url(
+    url: &str,
+    name: &str,
+    vars: Option<&BTreeMap<String, String>>,
+) -> Result<Url> {
+    #[derive(Serialize)]
+    struct Data<'a> {
+        name: &'a str,
+        vars: Option<&'a BTreeMap<String, String>>,
+        env: BTreeMap<String, String>,
+    }
+
+    if !url.contains("{{") && !url.contains("{%") {
+        return Url::parse(url)
+            .with_context(|| format!("`remote` URL `{url}` for plugin `{name}` is not valid"));
+    }
+
+    let data = Data {
+        name,
+        vars,
+        env: env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+            .collect(),
+    };
+    let rendered = TEMPLATE_ENGINE
+        .compile(url)
+        .with_context(|| format!("failed to compile `remote` URL for plugin `{name}`"))?
+        .render(&TEMPLATE_ENGINE, data)
+        .to_string()
+        .with_context(|| format!("failed to render `remote` URL for plugin `{name}`"))?;
+    Url::parse(&rendered)
+        .with_context(|| format!("`remote` URL `{rendered}` for plugin `{name}` is not valid"))
+}
+
+/// Convert a GitHub blob URL to the URL of the raw file.
+///
+/// For example `https://github.com/owner/repo/blob/branch/plugin.zsh` becomes
+/// `https://raw.githubusercontent.com/owner/repo/branch/plugin.zsh`.
+fn github_raw_url(url: &Url) -> Option<Url> {
+    if url.host_str() != Some(GITHUB_HOST) {
+        return None;
+    }
+    let segments: Vec<_> = url.path_segments()?.collect();
+    match segments.as_slice() {
+        [owner, repo, "blob", reference, path @ ..]
+            if !path.is_empty() && path.iter().all(|s| !s.is_empty()) =>
+        {
+            let url_str = format!(
+                "https://{GITHUB_RAW_HOST}/{owner}/{repo}/{reference}/{}",
+                path.join("/")
+            );
+            Url::parse(&url_str).ok()
+        }
+        _ => None,
+    }
+}
+
 impl Source {
     /// Whether this is a Git source.
     fn is_git(&self) -> bool {
         matches!(*self, Self::Git { .. })
     }
+
+    /// Whether this is a remote source.
+    fn is_remote(&self) -> bool {
+        matches!(*self, Self::Remote { .. })
+    }
 }
 
 /// Try and pop the TOML value from the table.
@@ -248,11 +617,144 @@ where
     }
 }
 
+/// Expand each `@name` in an `apply` list to the templates in the apply group
+/// with that name.
+///
+/// Groups may reference other groups. A reference prefixed with `+`, like
+/// `+@name`, prefixes each of the group's templates with `+`.
+fn expand_apply_groups(
+    apply: Option<Vec<String>>,
+    groups: &IndexMap<String, Vec<String>>,
+) -> Result<Option<Vec<String>>> {
+    fn expand(
+        name: &str,
+        groups: &IndexMap<String, Vec<String>>,
+        stack: &mut Vec<String>,
+        expanded: &mut Vec<String>,
+    ) -> Result<()> {
+        let (prefix, rest) = match name.strip_prefix('+') {
+            Some(rest) => ("+", rest),
+            None => ("", name),
+        };
+        let Some(group) = rest.strip_prefix('@') else {
+            expanded.push(name.to_string());
+            return Ok(());
+        };
+        if stack.iter().any(|g| g == group) {
+            bail!("apply group `{group}` references itself");
+        }
+        let members = groups
+            .get(group)
+            .ok_or_else(|| anyhow!("unknown apply group `{group}`"))?;
+        stack.push(group.to_string());
+        for member in members {
+            let member = match prefix {
+                "" => member.clone(),
+                _ => format!("+{}", member.trim_start_matches('+')),
+            };
+            expand(&member, groups, stack, expanded)?;
+        }
+        stack.pop();
+        Ok(())
+    }
+
+    let Some(apply) = apply else {
+        return Ok(None);
+    };
+    let mut expanded = Vec::with_capacity(apply.len());
+    for name in &apply {
+        expand(name, groups, &mut Vec::new(), &mut expanded)?;
+    }
+    Ok(Some(expanded))
+}
+
+/// Resolve an `apply` list whose templates are prefixed with `+` by appending
+/// them to the global `apply`, any other list is returned as is.
+fn append_apply(
+    apply: Option<Vec<String>>,
+    global_apply: &[String],
+) -> Result<Option<Vec<String>>> {
+    let Some(apply) = apply else {
+        return Ok(None);
+    };
+    let (append, replace): (Vec<_>, Vec<_>) = apply.iter().partition(|n| n.starts_with('+'));
+    if append.is_empty() {
+        return Ok(Some(apply));
+    }
+    if !replace.is_empty() {
+        bail!("templates prefixed with `+` can't be mixed with other templates in `apply`");
+    }
+    let mut resolved = global_apply.to_vec();
+    for name in append {
+        let name = &name[1..];
+        if !resolved.iter().any(|n| n == name) {
+            resolved.push(name.to_string());
+        }
+    }
+    Ok(Some(resolved))
+}
+
+/// Expand the apply groups and resolve the templates prefixed with `+` in the
+/// `apply` of each `use` glob, like a plugin's `apply`.
+fn resolve_use_apply(
+    uses: Option<Vec<Use>>,
+    groups: &IndexMap<String, Vec<String>>,
+    global_apply: &[String],
+) -> Result<Option<Vec<Use>>> {
+    let Some(mut uses) = uses else {
+        return Ok(None);
+    };
+    for u in &mut uses {
+        if u.apply.is_some() && u.pattern.starts_with('!') {
+            bail!(
+                "the `use` glob `{}` excludes files so it can't apply templates",
+                u.pattern
+            );
+        }
+        u.apply = expand_apply_groups(u.apply.take(), groups)
+            .and_then(|apply| append_apply(apply, global_apply))?;
+    }
+    Ok(Some(uses))
+}
+
+/// Resolve a `match` list whose patterns are prefixed with `+` by putting them
+/// before the shell's default patterns, any other list is returned as is.
+fn prepend_matches(
+    matches: Option<Vec<String>>,
+    default_matches: &[String],
+) -> Result<Option<Vec<String>>> {
+    let Some(matches) = matches else {
+        return Ok(None);
+    };
+    let (prepend, replace): (Vec<_>, Vec<_>) = matches.iter().partition(|p| p.starts_with('+'));
+    if prepend.is_empty() {
+        return Ok(Some(matches));
+    }
+    if !replace.is_empty() {
+        bail!("patterns prefixed with `+` can't be mixed with other patterns in `match`");
+    }
+    let mut resolved: Vec<String> = prepend.iter().map(|p| p[1..].to_string()).collect();
+    for pattern in default_matches {
+        if !resolved.contains(pattern) {
+            resolved.push(pattern.clone());
+        }
+    }
+    Ok(Some(resolved))
+}
+
+/// The names of the templates included by a template using
+/// `{% include "name" %}`.
+fn included_templates(template: &str) -> impl Iterator<Item = &str> {
+    regex!(r#"\{%-?\s*include\s+"([^"]*)""#)
+        .captures_iter(template)
+        .map(|caps| caps.get(1).unwrap().as_str())
+}
+
 /// Check whether the specifed templates actually exist.
 fn validate_template_names(
     shell: Shell,
     apply: &Option<Vec<String>>,
-    templates: &IndexMap<String, String>,
+    templates: &IndexMap<String, Template>,
 ) -> Result<()> {
     if let Some(apply) = apply {
         for name in apply {
@@ -272,7 +774,9 @@ fn validate_template_names(
 mod tests {
     use super::*;
 
-    use crate::config::{GitHubRepository, GitReference};
+    use std::collections::BTreeMap;
+
+    use crate::config::{BitbucketRepository, GitHubRepository, GitReference, Rule};
 
     use pretty_assertions::assert_eq;
 
@@ -282,6 +786,7 @@ mod tests {
             ("git", "https://github.com/rossmacarthur/sheldon-test"),
             ("gist", "579d02802b1cc17baed07753d09f5009"),
             ("github", "rossmacarthur/sheldon-test"),
+            ("bitbucket", "rossmacarthur/sheldon-test"),
             ("remote", "https://ross.macarthur.io"),
             ("local", "~/.dotfiles/zsh/pure"),
             ("inline", "derp"),
@@ -298,6 +803,8 @@ mod tests {
                     raw,
                     "test".to_string(),
                     Shell::default(),
+                    None,
+                    &RawDefaults::default(),
                     &IndexMap::new(),
                     &mut Vec::new(),
                 )
@@ -307,6 +814,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn normalize_rules() {
+        let raw = toml::from_str::<RawConfig>(
+            "[[rules]]\nmatch = '_*'\napply = ['fpath']\n\n[[rules]]\nmatch = '*.zsh'\napply = \
+             ['source']",
+        )
+        .unwrap();
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        assert_eq!(
+            config.rules,
+            vec![
+                Rule {
+                    pattern: "_*".into(),
+                    apply: vec_into!["fpath"],
+                },
+                Rule {
+                    pattern: "*.zsh".into(),
+                    apply: vec_into!["source"],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn normalize_rules_unknown_template() {
+        let raw =
+            toml::from_str::<RawConfig>("[[rules]]\nmatch = '_*'\napply = ['unknown']").unwrap();
+        let err = normalize(raw, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "unknown template `unknown`");
+    }
+
+    #[test]
+    fn normalize_rules_invalid_glob() {
+        let raw =
+            toml::from_str::<RawConfig>("[[rules]]\nmatch = '[*'\napply = ['source']").unwrap();
+        let err = normalize(raw, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "failed to parse rule glob `[*`");
+    }
+
     #[test]
     fn normalize_plugin_git() {
         let name = "test".to_string();
@@ -316,21 +862,26 @@ mod tests {
             source: Source::Git {
                 url: url.clone(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
-            git: Some(url),
+            git: Some(url.to_string()),
             ..Default::default()
         };
         let plugin = normalize_plugin(
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -338,6 +889,44 @@ mod tests {
         assert_eq!(plugin, expected);
     }
 
+    #[test]
+    fn parse_git_url_variants() {
+        for (url, expected) in [
+            (
+                "https://github.com/rossmacarthur/sheldon-test",
+                "https://github.com/rossmacarthur/sheldon-test",
+            ),
+            (
+                "git+https://github.com/rossmacarthur/sheldon-test.git",
+                "https://github.com/rossmacarthur/sheldon-test.git",
+            ),
+            (
+                "git+ssh://git@github.com/rossmacarthur/sheldon-test.git",
+                "ssh://git@github.com/rossmacarthur/sheldon-test.git",
+            ),
+            (
+                "git@github.com:rossmacarthur/sheldon-test.git",
+                "ssh://git@github.com/rossmacarthur/sheldon-test.git",
+            ),
+            (
+                "github.com:rossmacarthur/sheldon-test",
+                "ssh://github.com/rossmacarthur/sheldon-test",
+            ),
+        ] {
+            assert_eq!(parse_git_url(url).unwrap().as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn parse_git_url_invalid() {
+        let err = parse_git_url("rossmacarthur/sheldon-test").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`rossmacarthur/sheldon-test` is not a valid Git URL, expected a URL like \
+             `https://host/owner/repo` or an address like `git@host:owner/repo`"
+        );
+    }
+
     #[test]
     fn normalize_plugin_gist_with_git() {
         let name = "test".to_string();
@@ -349,12 +938,15 @@ mod tests {
                 )
                 .unwrap(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
             gist: Some(
@@ -369,6 +961,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -376,6 +970,44 @@ mod tests {
         assert_eq!(plugin, expected);
     }
 
+    #[test]
+    fn normalize_plugin_gist_with_reference() {
+        for (key, value, reference) in [
+            ("branch", "feature", GitReference::Branch("feature".into())),
+            ("tag", "v0.1.0", GitReference::Tag("v0.1.0".into())),
+            ("rev", "be8fde2", GitReference::Rev("be8fde2".into())),
+        ] {
+            let text = format!("gist = '579d02802b1cc17baed07753d09f5009'\n{key} = '{value}'");
+            let raw = toml::from_str::<RawPlugin>(&text).unwrap();
+            let plugin = normalize_plugin(
+                raw,
+                "test".to_string(),
+                Shell::default(),
+                None,
+                &RawDefaults::default(),
+                &IndexMap::new(),
+                &mut Vec::new(),
+            )
+            .unwrap();
+            match plugin {
+                Plugin::External(ExternalPlugin {
+                    source:
+                        Source::Git {
+                            url, reference: r, ..
+                        },
+                    ..
+                }) => {
+                    assert_eq!(
+                        url.as_str(),
+                        "https://gist.github.com/579d02802b1cc17baed07753d09f5009"
+                    );
+                    assert_eq!(r, Some(reference));
+                }
+                plugin => panic!("expected a Git source, got {plugin:?}"),
+            }
+        }
+    }
+
     #[test]
     fn normalize_plugin_gist_with_https() {
         let name = "test".to_string();
@@ -385,12 +1017,15 @@ mod tests {
                 url: Url::parse("https://gist.github.com/579d02802b1cc17baed07753d09f5009")
                     .unwrap(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
             gist: Some("579d02802b1cc17baed07753d09f5009".parse().unwrap()),
@@ -400,6 +1035,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -418,12 +1055,15 @@ mod tests {
                 )
                 .unwrap(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
             gist: Some(
@@ -438,6 +1078,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -445,6 +1087,42 @@ mod tests {
         assert_eq!(plugin, expected);
     }
 
+    #[test]
+    fn normalize_plugin_github_with_mirrors() {
+        let mirror = Url::parse("https://git.example.com/rossmacarthur/sheldon-test").unwrap();
+        let raw_plugin = RawPlugin {
+            github: Some(GitHubRepository {
+                owner: "rossmacarthur".to_string(),
+                name: "sheldon-test".to_string(),
+            }),
+            mirrors: Some(vec![mirror.clone()]),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Git { url, options, .. },
+                ..
+            }) => {
+                assert_eq!(
+                    url.as_str(),
+                    "https://github.com/rossmacarthur/sheldon-test"
+                );
+                assert_eq!(options.mirrors, vec![mirror]);
+            }
+            plugin => panic!("unexpected plugin {plugin:?}"),
+        }
+    }
+
     #[test]
     fn normalize_plugin_github_with_git() {
         let name = "test".to_string();
@@ -453,12 +1131,15 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("git://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
             github: Some(GitHubRepository {
@@ -472,6 +1153,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -487,12 +1170,15 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
             github: Some(GitHubRepository {
@@ -505,6 +1191,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -520,12 +1208,15 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("ssh://git@github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
+            vars: None,
         });
         let raw_plugin = RawPlugin {
             github: Some(GitHubRepository {
@@ -539,6 +1230,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -547,109 +1240,982 @@ mod tests {
     }
 
     #[test]
-    fn normalize_plugin_remote() {
-        let name = "test".to_string();
-        let url =
-            Url::parse("https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh")
-                .unwrap();
-        let expected = Plugin::External(ExternalPlugin {
-            name: name.clone(),
-            source: Source::Remote { url: url.clone() },
-            dir: None,
-            uses: None,
-            apply: None,
-            profiles: None,
-            hooks: None,
-        });
-        let raw_plugin = RawPlugin {
-            remote: Some(url),
-            ..Default::default()
-        };
-        let plugin = normalize_plugin(
-            raw_plugin,
-            name,
-            Shell::default(),
-            &IndexMap::new(),
-            &mut Vec::new(),
+    fn normalize_default_proto() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+proto = "ssh"
+
+[plugins.one]
+github = "rossmacarthur/one"
+
+[plugins.two]
+github = "rossmacarthur/two"
+proto = "https"
+
+[plugins.three]
+git = "https://example.com/rossmacarthur/three"
+"#,
         )
         .unwrap();
-        assert_eq!(plugin, expected);
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        let urls: Vec<_> = config
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                Plugin::External(ExternalPlugin {
+                    source: Source::Git { url, .. },
+                    ..
+                }) => url.as_str(),
+                plugin => panic!("unexpected plugin {plugin:?}"),
+            })
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "ssh://git@github.com/rossmacarthur/one",
+                "https://github.com/rossmacarthur/two",
+                "https://example.com/rossmacarthur/three",
+            ]
+        );
     }
 
     #[test]
-    fn normalize_plugin_remote_with_reference() {
-        let raw_plugin = RawPlugin {
-            remote: Some(
-                Url::parse(
-                    "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh",
-                )
-                .unwrap(),
-            ),
-            reference: Some(GitReference::Tag("v0.1.0".to_string())),
-            ..Default::default()
-        };
-        let err = normalize_plugin(
-            raw_plugin,
-            "test".to_string(),
-            Shell::default(),
-            &IndexMap::new(),
-            &mut Vec::new(),
+    fn normalize_defaults() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+[defaults]
+apply = ["PATH", "source"]
+profiles = ["work"]
+dir = "plugins"
+unknown = true
+
+[plugins.one]
+github = "rossmacarthur/one"
+
+[plugins.two]
+github = "rossmacarthur/two"
+apply = ["source"]
+
+[plugins.three]
+inline = "echo 'three'"
+"#,
         )
-        .unwrap_err();
+        .unwrap();
+        let mut warnings = Vec::new();
+        let config = normalize(raw, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
         assert_eq!(
-            err.to_string(),
-            "the `branch`, `tag`, and `rev` fields are not supported by this plugin type"
+            warnings[0].to_string(),
+            "unused config key: `defaults.unknown`"
         );
+        match &config.plugins[..] {
+            [Plugin::External(one), Plugin::External(two), Plugin::Inline(three)] => {
+                assert_eq!(one.apply, Some(vec_into!["PATH", "source"]));
+                assert_eq!(one.profiles, Some(vec_into!["work"]));
+                assert_eq!(one.dir.as_deref(), Some("plugins"));
+                assert_eq!(two.apply, Some(vec_into!["source"]));
+                assert_eq!(two.profiles, Some(vec_into!["work"]));
+                assert_eq!(three.apply, None);
+                assert_eq!(three.profiles, Some(vec_into!["work"]));
+            }
+            plugins => panic!("unexpected plugins {plugins:?}"),
+        }
     }
 
     #[test]
-    fn normalize_plugin_remote_with_ssh() {
-        let raw_plugin = RawPlugin {
-            remote: Some(
-                Url::parse(
-                    "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh",
-                )
-                .unwrap(),
-            ),
-            proto: Some(GitProtocol::Https),
-            ..Default::default()
-        };
-        let err = normalize_plugin(
-            raw_plugin,
-            "test".to_string(),
-            Shell::default(),
-            &IndexMap::new(),
-            &mut Vec::new(),
+    fn normalize_strict_unused_keys() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+strict = true
+unknown = true
+
+[defaults]
+unknown = true
+
+[plugins.test]
+githb = "rossmacarthur/test"
+github = "rossmacarthur/test"
+"#,
         )
-        .unwrap_err();
+        .unwrap();
+        let mut warnings = Vec::new();
+        let err = normalize(raw, &mut warnings).unwrap_err();
         assert_eq!(
             err.to_string(),
-            "the `proto` field is not supported by this plugin type"
+            "found unused config keys and `strict` is enabled: `unknown`, `defaults.unknown`, \
+             `plugins.test.githb`"
         );
+        assert!(warnings.is_empty());
     }
 
     #[test]
-    fn normalize_plugin_local() {
-        let name = "test".to_string();
-        let expected = Plugin::External(ExternalPlugin {
-            name: name.clone(),
-            source: Source::Local {
-                dir: "/home/temp".into(),
-            },
-            dir: None,
-            uses: None,
-            apply: None,
-            profiles: None,
-            hooks: None,
-        });
-        let raw_plugin = RawPlugin {
-            local: Some("/home/temp".into()),
-            ..Default::default()
+    fn normalize_template_includes() {
+        let normalize_err = |s: &str| {
+            let raw = toml::from_str::<RawConfig>(s).unwrap();
+            normalize(raw, &mut Vec::new())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
         };
-        let plugin = normalize_plugin(
+        assert_eq!(
+            normalize_err(
+                r#"
+[templates]
+defer = 'zsh-defer {% include "source" %}'
+lazy = '{% include "defer" %}'
+"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            normalize_err(
+                r#"
+[templates]
+defer = '{% include "missing" %}'
+"#
+            ),
+            Err("template `defer` includes unknown template `missing`".into())
+        );
+        assert_eq!(
+            normalize_err(
+                r#"
+[templates]
+source = '{% include "source" %}'
+"#
+            ),
+            Err(
+                "template `source` includes itself, use a different name to extend the \
+                 built-in `source` template"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn normalize_apply_groups() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+apply = ["@sourced"]
+
+[apply_groups]
+deferred = ["defer", "@sourced"]
+sourced = ["source"]
+
+[templates]
+defer = "{{ hooks?.pre | nl }}zsh-defer source \"{{ file }}\""
+
+[plugins.one]
+github = "rossmacarthur/one"
+apply = ["@deferred", "PATH"]
+
+[plugins.two]
+github = "rossmacarthur/two"
+apply = ["+@deferred"]
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        assert_eq!(config.apply, Some(vec_into!["source"]));
+        let applies: Vec<_> = config
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                Plugin::External(plugin) => plugin.apply.clone().unwrap(),
+                plugin => panic!("unexpected plugin {plugin:?}"),
+            })
+            .collect();
+        assert_eq!(
+            applies,
+            [
+                vec_into!["defer", "source", "PATH"],
+                vec_into!["source", "defer"],
+            ] as [Vec<String>; 2]
+        );
+    }
+
+    #[test]
+    fn normalize_apply_groups_errors() {
+        let normalize_err = |s: &str| {
+            let raw = toml::from_str::<RawConfig>(s).unwrap();
+            format!("{:#}", normalize(raw, &mut Vec::new()).unwrap_err())
+        };
+        assert_eq!(
+            normalize_err(
+                r#"
+[apply_groups]
+one = ["source", "@two"]
+two = ["@one"]
+"#
+            ),
+            "apply group `one` references itself"
+        );
+        assert_eq!(
+            normalize_err(
+                r#"
+[plugins.test]
+github = "rossmacarthur/test"
+apply = ["@missing"]
+"#
+            ),
+            "failed to normalize plugin `test`: unknown apply group `missing`"
+        );
+        assert_eq!(
+            normalize_err(
+                r#"
+[apply_groups]
+one = ["missing"]
+"#
+            ),
+            "failed to normalize apply group `one`: unknown template `missing`"
+        );
+    }
+
+    #[test]
+    fn normalize_use_apply() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+[apply_groups]
+completions = ["fpath"]
+
+[plugins.test]
+github = "rossmacarthur/test"
+use = ["{{ name }}.plugin.zsh", { match = "_*", apply = ["@completions"] }]
+"#,
+        )
+        .unwrap();
+
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+
+        let Plugin::External(plugin) = &config.plugins[0] else {
+            panic!("expected an external plugin");
+        };
+        assert_eq!(
+            plugin.uses,
+            Some(vec![
+                Use::from("{{ name }}.plugin.zsh"),
+                Use {
+                    pattern: "_*".into(),
+                    apply: Some(vec_into!["fpath"]),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn normalize_use_apply_errors() {
+        let normalize_err = |s: &str| {
+            let raw = toml::from_str::<RawConfig>(s).unwrap();
+            format!("{:#}", normalize(raw, &mut Vec::new()).unwrap_err())
+        };
+        assert_eq!(
+            normalize_err(
+                r#"
+[plugins.test]
+github = "rossmacarthur/test"
+use = [{ match = "_*", apply = ["missing"] }]
+"#
+            ),
+            "failed to normalize plugin `test`: unknown template `missing`"
+        );
+        assert_eq!(
+            normalize_err(
+                r#"
+[plugins.test]
+github = "rossmacarthur/test"
+use = ["*.zsh", { match = "!_*", apply = ["fpath"] }]
+"#
+            ),
+            "failed to normalize plugin `test`: the `use` glob `!_*` excludes files so it can't \
+             apply templates"
+        );
+    }
+
+    #[test]
+    fn normalize_apply_append() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+apply = ["source", "fpath"]
+
+[plugins.one]
+github = "rossmacarthur/one"
+apply = ["+PATH"]
+
+[plugins.two]
+github = "rossmacarthur/two"
+apply = ["+source", "+PATH"]
+
+[plugins.three]
+github = "rossmacarthur/three"
+apply = ["PATH"]
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        let applies: Vec<_> = config
+            .plugins
+            .iter()
+            .map(|plugin| match plugin {
+                Plugin::External(plugin) => plugin.apply.clone().unwrap(),
+                plugin => panic!("unexpected plugin {plugin:?}"),
+            })
+            .collect();
+        assert_eq!(
+            applies,
+            [
+                vec_into!["source", "fpath", "PATH"],
+                vec_into!["source", "fpath", "PATH"],
+                vec_into!["PATH"],
+            ] as [Vec<String>; 3]
+        );
+
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+[plugins.test]
+github = "rossmacarthur/test"
+apply = ["+PATH"]
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        match &config.plugins[..] {
+            [Plugin::External(test)] => {
+                assert_eq!(test.apply, Some(vec_into!["source", "PATH"]));
+            }
+            plugins => panic!("unexpected plugins {plugins:?}"),
+        }
+
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+[plugins.test]
+github = "rossmacarthur/test"
+apply = ["source", "+PATH"]
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "failed to normalize plugin `test`: templates prefixed with `+` can't be mixed with \
+             other templates in `apply`"
+        );
+
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+[plugins.test]
+inline = "echo 'this is a test'"
+apply = ["+PATH"]
+"#,
+        )
+        .unwrap();
+        let err = normalize(raw, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "failed to normalize plugin `test`: appending templates with `+` is not supported by \
+             inline plugins"
+        );
+    }
+
+    #[test]
+    fn normalize_matches_prepend() {
+        let raw = toml::from_str::<RawConfig>(
+            r#"
+shell = "nu"
+match = ["+*.sh", "+*.nu"]
+"#,
+        )
+        .unwrap();
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        assert_eq!(
+            config.matches,
+            Some(vec_into!["*.sh", "*.nu", "{{ name }}.nu"])
+        );
+
+        let raw = toml::from_str::<RawConfig>("shell = 'nu'\nmatch = ['*.sh']").unwrap();
+        let config = normalize(raw, &mut Vec::new()).unwrap();
+        assert_eq!(config.matches, Some(vec_into!["*.sh"]));
+
+        let raw = toml::from_str::<RawConfig>("match = ['*.sh', '+*.zsh']").unwrap();
+        let err = normalize(raw, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "failed to normalize `match`: patterns prefixed with `+` can't be mixed with other \
+             patterns in `match`"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_github_with_ssh_user_port_and_host() {
+        let name = "test".to_string();
+        let expected = Plugin::External(ExternalPlugin {
+            name: name.clone(),
+            source: Source::Git {
+                url: Url::parse("ssh://gituser@git.example.com:2222/rossmacarthur/sheldon-test")
+                    .unwrap(),
+                reference: None,
+                options: Box::default(),
+            },
+            dir: None,
+            uses: None,
+            apply: None,
+            profiles: None,
+            enabled: None,
+            hooks: None,
+            vars: None,
+        });
+        let raw_plugin = RawPlugin {
+            github: Some(GitHubRepository {
+                owner: "rossmacarthur".to_string(),
+                name: "sheldon-test".to_string(),
+            }),
+            proto: Some(GitProtocol::Ssh),
+            host: Some("git.example.com".to_string()),
+            ssh_user: Some("gituser".to_string()),
+            ssh_port: Some(2222),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            name,
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(plugin, expected);
+    }
+
+    #[test]
+    fn normalize_plugin_github_with_ssh_user_and_https() {
+        let raw_plugin = RawPlugin {
+            github: Some(GitHubRepository {
+                owner: "rossmacarthur".to_string(),
+                name: "sheldon-test".to_string(),
+            }),
+            ssh_user: Some("gituser".to_string()),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `ssh_user` and `ssh_port` fields are only supported when `proto` is `ssh`"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_bitbucket_with_https() {
+        let name = "test".to_string();
+        let expected = Plugin::External(ExternalPlugin {
+            name: name.clone(),
+            source: Source::Git {
+                url: Url::parse("https://bitbucket.org/rossmacarthur/sheldon-test").unwrap(),
+                reference: None,
+                options: Box::default(),
+            },
+            dir: None,
+            uses: None,
+            apply: None,
+            profiles: None,
+            enabled: None,
+            hooks: None,
+            vars: None,
+        });
+        let raw_plugin = RawPlugin {
+            bitbucket: Some(BitbucketRepository {
+                owner: "rossmacarthur".to_string(),
+                name: "sheldon-test".to_string(),
+            }),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            name,
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(plugin, expected);
+    }
+
+    #[test]
+    fn normalize_plugin_bitbucket_with_ssh() {
+        let name = "test".to_string();
+        let expected = Plugin::External(ExternalPlugin {
+            name: name.clone(),
+            source: Source::Git {
+                url: Url::parse("ssh://git@bitbucket.org/rossmacarthur/sheldon-test").unwrap(),
+                reference: None,
+                options: Box::default(),
+            },
+            dir: None,
+            uses: None,
+            apply: None,
+            profiles: None,
+            enabled: None,
+            hooks: None,
+            vars: None,
+        });
+        let raw_plugin = RawPlugin {
+            bitbucket: Some(BitbucketRepository {
+                owner: "rossmacarthur".to_string(),
+                name: "sheldon-test".to_string(),
+            }),
+            proto: Some(GitProtocol::Ssh),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            name,
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(plugin, expected);
+    }
+
+    #[test]
+    fn normalize_plugin_remote() {
+        let name = "test".to_string();
+        let url = Url::parse(
+            "https://raw.githubusercontent.com/rossmacarthur/sheldon-test/master/test.plugin.zsh",
+        )
+        .unwrap();
+        let expected = Plugin::External(ExternalPlugin {
+            name: name.clone(),
+            source: Source::Remote {
+                url: url.clone(),
+                index: false,
+            },
+            dir: None,
+            uses: None,
+            apply: None,
+            profiles: None,
+            enabled: None,
+            hooks: None,
+            vars: None,
+        });
+        let raw_plugin = RawPlugin {
+            remote: Some(url.to_string()),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            name,
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(plugin, expected);
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_template() {
+        let raw_plugin = RawPlugin {
+            remote: Some("https://example.com/{{ vars.version }}/{{ name }}.zsh".into()),
+            vars: Some(BTreeMap::from([("version".into(), "v1.2.3".into())])),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { url, .. },
+                ..
+            }) => assert_eq!(url.as_str(), "https://example.com/v1.2.3/test.zsh"),
+            plugin => panic!("unexpected plugin: {plugin:?}"),
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_template_env() {
+        env::set_var("SHELDON_TEST_REMOTE_VERSION", "v4.5.6");
+        let raw_plugin = RawPlugin {
+            remote: Some("https://example.com/{{ env.SHELDON_TEST_REMOTE_VERSION }}.zsh".into()),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { url, .. },
+                ..
+            }) => assert_eq!(url.as_str(), "https://example.com/v4.5.6.zsh"),
+            plugin => panic!("unexpected plugin: {plugin:?}"),
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_remote_without_template_is_not_rendered() {
+        let raw_plugin = RawPlugin {
+            remote: Some("https://example.com/plugin.zsh?q=%7D%7D}}".into()),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { url, .. },
+                ..
+            }) => assert_eq!(url.as_str(), "https://example.com/plugin.zsh?q=%7D%7D}}"),
+            plugin => panic!("unexpected plugin: {plugin:?}"),
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_template_errors() {
+        let normalize = |remote: &str| {
+            let raw_plugin = RawPlugin {
+                remote: Some(remote.into()),
+                ..Default::default()
+            };
+            normalize_plugin(
+                raw_plugin,
+                "test".to_string(),
+                Shell::default(),
+                None,
+                &RawDefaults::default(),
+                &IndexMap::new(),
+                &mut Vec::new(),
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert_eq!(
+            normalize("https://example.com/{{ vars.version }}/test.zsh"),
+            "failed to render `remote` URL for plugin `test`"
+        );
+        assert_eq!(
+            normalize("{{ name }}.zsh"),
+            "`remote` URL `test.zsh` for plugin `test` is not valid"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_rewrites_github_blob_url() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let mut warnings = Vec::new();
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut warnings,
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { url, .. },
+                ..
+            }) => assert_eq!(
+                url.as_str(),
+                "https://raw.githubusercontent.com/rossmacarthur/sheldon-test/master/test.plugin.zsh"
+            ),
+            plugin => panic!("unexpected plugin: {plugin:?}"),
+        }
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "rewrote GitHub blob URL \
+             `https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh` to \
+             `https://raw.githubusercontent.com/rossmacarthur/sheldon-test/master/test.plugin.zsh` \
+             for plugin `test`, set `plugins.test.rewrite = false` to disable this"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_index() {
+        let raw_plugin = RawPlugin {
+            remote: Some("https://example.com/plugins/index.txt".into()),
+            remote_index: Some(true),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".into(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert!(matches!(
+            plugin,
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { index: true, .. },
+                ..
+            })
+        ));
+
+        let raw_plugin = RawPlugin {
+            github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            remote_index: Some(true),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".into(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `remote_index` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_rewrite_disabled() {
+        let url =
+            Url::parse("https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh")
+                .unwrap();
+        let raw_plugin = RawPlugin {
+            remote: Some(url.to_string()),
+            rewrite: Some(false),
+            ..Default::default()
+        };
+        let mut warnings = Vec::new();
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut warnings,
+        )
+        .unwrap();
+        match plugin {
+            Plugin::External(ExternalPlugin {
+                source: Source::Remote { url: actual, .. },
+                ..
+            }) => assert_eq!(actual, url),
+            plugin => panic!("unexpected plugin: {plugin:?}"),
+        }
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn normalize_plugin_git_with_rewrite() {
+        let raw_plugin = RawPlugin {
+            github: Some("rossmacarthur/sheldon-test".parse().unwrap()),
+            rewrite: Some(false),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `rewrite` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn github_raw_url_ignores_other_urls() {
+        for url in [
+            "https://github.com/rossmacarthur/sheldon-test",
+            "https://github.com/rossmacarthur/sheldon-test/blob/master",
+            "https://github.com/rossmacarthur/sheldon-test/raw/master/test.plugin.zsh",
+            "https://example.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh",
+        ] {
+            assert_eq!(github_raw_url(&Url::parse(url).unwrap()), None);
+        }
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_reference() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh"
+                    .to_string(),
+            ),
+            reference: Some(GitReference::Tag("v0.1.0".to_string())),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `branch`, `tag`, and `rev` fields are not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_mirrors() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh"
+                    .to_string(),
+            ),
+            mirrors: Some(vec![
+                Url::parse("https://example.com/test.plugin.zsh").unwrap()
+            ]),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `mirrors` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_post_clone() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                "https://raw.githubusercontent.com/rossmacarthur/sheldon-test/master/test.plugin.zsh"
+                    .to_string(),
+            ),
+            post_clone: Some(vec_into!["make"]),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `post_clone` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_remote_with_ssh() {
+        let raw_plugin = RawPlugin {
+            remote: Some(
+                "https://github.com/rossmacarthur/sheldon-test/blob/master/test.plugin.zsh"
+                    .to_string(),
+            ),
+            proto: Some(GitProtocol::Https),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the `proto` field is not supported by this plugin type"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_local() {
+        let name = "test".to_string();
+        let expected = Plugin::External(ExternalPlugin {
+            name: name.clone(),
+            source: Source::Local {
+                dir: "/home/temp".into(),
+            },
+            dir: None,
+            uses: None,
+            apply: None,
+            profiles: None,
+            enabled: None,
+            hooks: None,
+            vars: None,
+        });
+        let raw_plugin = RawPlugin {
+            local: Some("/home/temp".into()),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -663,7 +2229,9 @@ mod tests {
         let expected = Plugin::Inline(InlinePlugin {
             name: name.clone(),
             raw: "echo 'this is a test'\n".to_string(),
+            apply: None,
             profiles: None,
+            enabled: None,
             hooks: None,
         });
         let raw_plugin = RawPlugin {
@@ -674,6 +2242,8 @@ mod tests {
             raw_plugin,
             name,
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
@@ -683,6 +2253,60 @@ mod tests {
 
     #[test]
     fn normalize_plugin_inline_apply() {
+        let templates = IndexMap::from([("defer".into(), Template::from("zsh-defer {{ raw }}"))]);
+        let raw_plugin = RawPlugin {
+            inline: Some("compinit".to_string()),
+            apply: Some(vec_into!["defer"]),
+            ..Default::default()
+        };
+        let plugin = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &templates,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            plugin,
+            Plugin::Inline(InlinePlugin {
+                name: "test".into(),
+                raw: "compinit".into(),
+                apply: Some("defer".into()),
+                profiles: None,
+                enabled: None,
+                hooks: None,
+            })
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_inline_apply_multiple() {
+        let raw_plugin = RawPlugin {
+            inline: Some("echo 'this is a test'\n".to_string()),
+            apply: Some(vec_into!["source", "PATH"]),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "inline plugins can only apply a single template"
+        );
+    }
+
+    #[test]
+    fn normalize_plugin_inline_apply_unknown() {
         let raw_plugin = RawPlugin {
             inline: Some("echo 'this is a test'\n".to_string()),
             apply: Some(vec_into!["test"]),
@@ -692,13 +2316,35 @@ mod tests {
             raw_plugin,
             "test".to_string(),
             Shell::default(),
+            None,
+            &RawDefaults::default(),
+            &IndexMap::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "unknown template `test`");
+    }
+
+    #[test]
+    fn normalize_plugin_inline_vars() {
+        let raw_plugin = RawPlugin {
+            inline: Some("echo 'this is a test'\n".to_string()),
+            vars: Some(BTreeMap::from([("theme".into(), "dark".into())])),
+            ..Default::default()
+        };
+        let err = normalize_plugin(
+            raw_plugin,
+            "test".to_string(),
+            Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
         .unwrap_err();
         assert_eq!(
             err.to_string(),
-            "the `apply` field is not supported by inline plugins"
+            "the `vars` field is not supported by inline plugins"
         );
     }
 
@@ -716,6 +2362,8 @@ mod tests {
             raw_plugin,
             "test".to_string(),
             Shell::default(),
+            None,
+            &RawDefaults::default(),
             &IndexMap::new(),
             &mut Vec::new(),
         )
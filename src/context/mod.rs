@@ -1,9 +1,11 @@
 //! Contextual information.
 
 mod message;
+mod progress;
 #[cfg(test)]
 mod tests;
 
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 
 use anyhow::Error;
@@ -11,8 +13,10 @@ use serde::{Deserialize, Serialize};
 pub use yansi::Color;
 use yansi::Paint;
 
+use crate::config::Shell;
 use crate::context::message::{Message, ToMessage};
-use crate::lock::LockMode;
+pub use crate::context::progress::{capture, Progress};
+use crate::lock::{InstallError, LockMode};
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Context {
@@ -22,6 +26,8 @@ pub struct Context {
     pub data_dir: PathBuf,
     pub config_file: PathBuf,
     pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Shell>,
 
     #[serde(skip)]
     pub lock_file: PathBuf,
@@ -35,6 +41,16 @@ pub struct Context {
     pub interactive: bool,
     #[serde(skip)]
     pub lock_mode: Option<LockMode>,
+    #[serde(skip)]
+    pub strict_verify: bool,
+    #[serde(skip)]
+    pub offline: bool,
+    #[serde(skip)]
+    pub timings: bool,
+    #[serde(skip)]
+    pub jobs: Option<NonZeroUsize>,
+    #[serde(skip)]
+    pub max_download_size: Option<u64>,
 }
 
 /// The output style.
@@ -44,22 +60,19 @@ pub struct Output {
     pub verbosity: Verbosity,
     /// Whether to not use ANSI color codes.
     pub no_color: bool,
+    /// Whether to output errors as JSON.
+    pub json_errors: bool,
 }
 
 /// The requested verbosity of output.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd)]
 pub enum Verbosity {
     Quiet,
+    #[default]
     Normal,
     Verbose,
 }
 
-impl Default for Verbosity {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
-
 impl Context {
     /// The location of the configuration directory.
     pub fn config_dir(&self) -> &Path {
@@ -76,6 +89,11 @@ impl Context {
         &self.config_file
     }
 
+    /// Whether the config file should be read from the standard input.
+    pub fn config_from_stdin(&self) -> bool {
+        self.config_file == Path::new("-")
+    }
+
     /// The location of the lock file.
     pub fn lock_file(&self) -> &Path {
         &self.lock_file
@@ -140,11 +158,11 @@ impl Context {
     }
 
     fn log_header_impl(&self, prefix: &str, msg: Message<'_>) {
-        if self.output.no_color {
-            eprintln!("{} {}", prefix.to_uppercase(), msg);
+        progress::print_line(if self.output.no_color {
+            format!("{} {}", prefix.to_uppercase(), msg)
         } else {
-            eprintln!("{} {}", Paint::magenta(prefix).bold(), msg);
-        }
+            format!("{} {}", Paint::magenta(prefix).bold(), msg)
+        });
     }
 
     pub fn log_status(&self, prefix: &str, msg: impl ToMessage) {
@@ -159,10 +177,9 @@ impl Context {
         }
     }
 
+    /// Warnings are always logged, even when quiet.
     pub fn log_warning(&self, prefix: &str, msg: impl ToMessage) {
-        if self.verbosity() >= Verbosity::Normal {
-            self.log_impl(Color::Yellow, prefix, msg.to_message(self));
-        }
+        self.log_impl(Color::Yellow, prefix, msg.to_message(self));
     }
 
     pub fn log_verbose_warning(&self, prefix: &str, msg: impl ToMessage) {
@@ -172,15 +189,34 @@ impl Context {
     }
 
     fn log_impl(&self, color: Color, prefix: &str, msg: Message<'_>) {
-        if self.output.no_color {
-            eprintln!("{: >10} {}", prefix.to_uppercase(), msg);
+        progress::print_line(if self.output.no_color {
+            format!("{: >10} {}", prefix.to_uppercase(), msg)
         } else {
-            eprintln!(
+            format!(
                 "{} {}",
                 Paint::new(format!("{prefix: >10}")).fg(color).bold(),
                 msg
-            );
-        }
+            )
+        });
+    }
+
+    /// Print the lines that were captured with [`capture`].
+    ///
+    /// The lines are printed together so that they are not interleaved with
+    /// lines printed by other threads.
+    pub fn log_captured(&self, lines: Vec<String>) {
+        progress::print_lines(lines);
+    }
+
+    /// Start showing the progress of installing the given number of sources.
+    ///
+    /// Nothing is shown when quiet, when color is disabled, or when stderr is
+    /// not a terminal.
+    pub fn progress(&self, total: usize) -> Progress {
+        Progress::new(
+            total,
+            self.verbosity() >= Verbosity::Normal && !self.output.no_color,
+        )
     }
 
     pub fn log_error(&self, err: &Error) {
@@ -190,6 +226,52 @@ impl Context {
     pub fn log_error_as_warning(&self, err: &Error) {
         log_error_as_warning(self.output.no_color, err);
     }
+
+    /// Log all the given errors, as a single JSON array if requested.
+    pub fn log_errors(&self, errs: &[Error]) {
+        if self.output.json_errors {
+            eprintln!("{}", errors_to_json(errs));
+        } else {
+            for err in errs {
+                self.log_error(err);
+            }
+        }
+    }
+}
+
+/// An error as it is output in JSON.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct JsonError<'a> {
+    /// The plugin that failed, if the error is associated with one.
+    plugin: Option<&'a str>,
+    /// The top level error message.
+    message: String,
+    /// The messages of the underlying causes.
+    chain: Vec<String>,
+}
+
+/// Convert the errors to a JSON array, with an entry for each plugin that
+/// each error is associated with.
+fn errors_to_json(errs: &[Error]) -> String {
+    let json_errors: Vec<_> = errs
+        .iter()
+        .flat_map(|err| {
+            let message = err.to_string();
+            let chain: Vec<_> = err.chain().skip(1).map(|c| c.to_string()).collect();
+            let plugins = match err.downcast_ref::<InstallError>() {
+                Some(ctx) if !ctx.plugins.is_empty() => {
+                    ctx.plugins.iter().map(|p| Some(p.as_str())).collect()
+                }
+                _ => vec![None],
+            };
+            plugins.into_iter().map(move |plugin| JsonError {
+                plugin,
+                message: message.clone(),
+                chain: chain.clone(),
+            })
+        })
+        .collect();
+    serde_json::to_string(&json_errors).expect("errors are serializable")
 }
 
 pub fn log_error(no_color: bool, err: &Error) {
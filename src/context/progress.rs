@@ -0,0 +1,159 @@
+//! A progress line shown while sources are installed in parallel.
+
+use std::cell::RefCell;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+
+use yansi::Paint;
+
+/// The state of the current progress line, if any.
+///
+/// All output to stderr while installing goes through this mutex so that
+/// status lines from different threads are never interleaved with the
+/// progress line.
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+thread_local! {
+    /// The lines logged on this thread while they are being captured.
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug)]
+struct State {
+    total: usize,
+    done: usize,
+    active: Vec<String>,
+}
+
+/// Tracks the progress of installing sources.
+///
+/// The line is only drawn when stderr is a terminal and color is enabled,
+/// because it relies on escape codes to redraw it, otherwise this does nothing.
+/// The line is cleared when this is dropped.
+#[derive(Debug)]
+pub struct Progress {
+    enabled: bool,
+}
+
+impl Progress {
+    pub(super) fn new(total: usize, enabled: bool) -> Self {
+        let enabled = enabled && io::stderr().is_terminal();
+        if enabled {
+            let state = State {
+                total,
+                done: 0,
+                active: Vec::new(),
+            };
+            draw(&state);
+            *STATE.lock().unwrap() = Some(state);
+        }
+        Self { enabled }
+    }
+
+    /// Mark the source with the given name as being installed.
+    pub fn start(&self, name: &str) {
+        if self.enabled {
+            update(|state| state.active.push(name.to_owned()));
+        }
+    }
+
+    /// Mark the source with the given name as done.
+    pub fn finish(&self, name: &str) {
+        if self.enabled {
+            update(|state| {
+                if let Some(i) = state.active.iter().position(|n| n == name) {
+                    state.active.remove(i);
+                }
+                state.done += 1;
+            });
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.enabled {
+            let mut state = STATE.lock().unwrap();
+            clear();
+            *state = None;
+        }
+    }
+}
+
+/// Run the given function, capturing the lines that it logs on this thread
+/// instead of printing them.
+pub fn capture<T, F: FnOnce() -> T>(f: F) -> (T, Vec<String>) {
+    let previous = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let result = f();
+    let lines = CAPTURED.with(|captured| captured.replace(previous));
+    (result, lines.unwrap_or_default())
+}
+
+/// Print the line to stderr without interfering with the progress line, unless
+/// lines are being captured on this thread.
+pub(super) fn print_line(line: String) {
+    let line = CAPTURED.with(|captured| match &mut *captured.borrow_mut() {
+        Some(lines) => {
+            lines.push(line);
+            None
+        }
+        None => Some(line),
+    });
+    if let Some(line) = line {
+        suspend(|| eprintln!("{line}"));
+    }
+}
+
+/// Print the lines to stderr together without interfering with the progress
+/// line.
+pub(super) fn print_lines(lines: Vec<String>) {
+    if !lines.is_empty() {
+        suspend(|| {
+            for line in lines {
+                eprintln!("{line}");
+            }
+        });
+    }
+}
+
+/// Run the given function, which prints to stderr, without interfering with
+/// the progress line.
+fn suspend<F: FnOnce()>(f: F) {
+    let state = STATE.lock().unwrap();
+    match &*state {
+        Some(state) => {
+            clear();
+            f();
+            draw(state);
+        }
+        None => f(),
+    }
+}
+
+fn update<F: FnOnce(&mut State)>(f: F) {
+    let mut state = STATE.lock().unwrap();
+    if let Some(state) = &mut *state {
+        f(state);
+        clear();
+        draw(state);
+    }
+}
+
+fn clear() {
+    eprint!("\r\x1b[2K");
+}
+
+fn draw(state: &State) {
+    let msg = message(state.done, state.total, &state.active);
+    eprint!("{} {}", Paint::cyan("Installing").bold(), msg);
+    io::stderr().flush().ok();
+}
+
+/// The message shown after the prefix on the progress line.
+pub(super) fn message(done: usize, total: usize, active: &[String]) -> String {
+    match active {
+        [] => format!("[{done}/{total}]"),
+        [name] => format!("[{done}/{total}] {name}"),
+        [name, rest @ ..] => format!("[{done}/{total}] {name} (+{} more)", rest.len()),
+    }
+}
@@ -32,3 +32,26 @@ fn context_replace_home() {
         assert_eq!(ctx.replace_home(p), Path::new(exp));
     }
 }
+
+#[test]
+fn context_errors_to_json() {
+    let install = anyhow::anyhow!("not found").context(InstallError {
+        message: "failed to install source `test`".into(),
+        plugins: vec!["one".into(), "two".into()],
+    });
+    let other = anyhow::anyhow!("permission denied").context("failed to read config");
+    assert_eq!(
+        errors_to_json(&[install, other]),
+        r#"[{"plugin":"one","message":"failed to install source `test`","chain":["not found"]},{"plugin":"two","message":"failed to install source `test`","chain":["not found"]},{"plugin":null,"message":"failed to read config","chain":["permission denied"]}]"#
+    );
+}
+
+#[test]
+fn progress_message() {
+    assert_eq!(progress::message(0, 3, &[]), "[0/3]");
+    assert_eq!(progress::message(1, 3, &["a".into()]), "[1/3] a");
+    assert_eq!(
+        progress::message(1, 3, &["a".into(), "b".into()]),
+        "[1/3] a (+1 more)"
+    );
+}
@@ -94,6 +94,14 @@ impl Editor {
         Ok(Self { bin, args })
     }
 
+    /// Create a new `Editor` from an explicit command, e.g. `code --wait`.
+    pub fn from_command(cmd: &str) -> Result<Self> {
+        let (bin, args) =
+            to_bin_and_args(cmd).ok_or_else(|| anyhow!("editor command must not be empty"))?;
+        which::which(&bin).with_context(|| format!("failed to find editor `{}`", bin.display()))?;
+        Ok(Self { bin, args })
+    }
+
     /// Open a file for editing with initial contents.
     pub fn edit(self, ctx: &Context, path: &Path, contents: &str) -> Result<Child> {
         let (overwrite, temp) = match TempPath::new(path) {
@@ -0,0 +1,210 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::context::Context;
+use crate::lock::file::{LockedExternalPlugin, LockedPlugin};
+use crate::lock::LockedConfig;
+
+impl LockedConfig {
+    /// Describe the changes to the plugins from the previous `LockedConfig`, if
+    /// any, to this one.
+    ///
+    /// Each added plugin is prefixed with `+`, each removed plugin with `-`,
+    /// and each changed plugin with `~` followed by what changed. Returns an
+    /// empty string if nothing changed.
+    pub fn diff(&self, ctx: &Context, previous: Option<&LockedConfig>) -> Result<String> {
+        let previous = previous.map_or(&[][..], |p| &p.plugins);
+        let mut s = String::new();
+        for old in previous {
+            if find(&self.plugins, old.name()).is_none() {
+                writeln!(s, "- {}", old.name())?;
+            }
+        }
+        for plugin in &self.plugins {
+            let name = plugin.name();
+            match find(previous, name) {
+                None => writeln!(s, "+ {name}")?,
+                Some(old) if old == plugin => {}
+                Some(old) => {
+                    writeln!(s, "~ {name}")?;
+                    for change in changes(ctx, old, plugin) {
+                        writeln!(s, "    {change}")?;
+                    }
+                }
+            }
+        }
+        Ok(s)
+    }
+}
+
+fn find<'a>(plugins: &'a [LockedPlugin], name: &str) -> Option<&'a LockedPlugin> {
+    plugins.iter().find(|p| p.name() == name)
+}
+
+/// Describe the changes between two versions of the same plugin.
+fn changes(ctx: &Context, old: &LockedPlugin, new: &LockedPlugin) -> Vec<String> {
+    let (old, new) = match (old, new) {
+        (LockedPlugin::External(old), LockedPlugin::External(new)) => (old, new),
+        (LockedPlugin::Inline(_), LockedPlugin::Inline(_)) => {
+            return vec!["inline script".into()];
+        }
+        (LockedPlugin::Inline(_), LockedPlugin::External(_)) => {
+            return vec!["inline -> external".into()];
+        }
+        (LockedPlugin::External(_), LockedPlugin::Inline(_)) => {
+            return vec!["external -> inline".into()];
+        }
+    };
+
+    let mut changes = Vec::new();
+    if old.source_dir != new.source_dir {
+        changes.push(format!(
+            "source_dir: {} -> {}",
+            ctx.replace_home(&old.source_dir).display(),
+            ctx.replace_home(&new.source_dir).display()
+        ));
+    }
+    if old.revision != new.revision {
+        changes.push(format!(
+            "revision: {} -> {}",
+            short_revision(old),
+            short_revision(new)
+        ));
+    }
+    for file in &old.files {
+        if !new.files.contains(file) {
+            changes.push(format!("- {}", ctx.replace_home(file).display()));
+        }
+    }
+    for file in &new.files {
+        if !old.files.contains(file) {
+            changes.push(format!("+ {}", ctx.replace_home(file).display()));
+        }
+    }
+    if old.apply != new.apply {
+        changes.push(format!(
+            "apply: {} -> {}",
+            old.apply.join(", "),
+            new.apply.join(", ")
+        ));
+    }
+    if changes.is_empty() {
+        changes.push("options".into());
+    }
+    changes
+}
+
+fn short_revision(plugin: &LockedExternalPlugin) -> &str {
+    match &plugin.revision {
+        Some(revision) => &revision[..revision.len().min(7)],
+        None => "none",
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use crate::config::InlinePlugin;
+    use crate::lock::file::LOCKFILE_VERSION;
+
+    fn locked_config(ctx: &Context, plugins: Vec<LockedPlugin>) -> LockedConfig {
+        LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins,
+            templates: crate::config::Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        }
+    }
+
+    fn external(source_dir: &Path, name: &str, revision: &str, files: &[&str]) -> LockedPlugin {
+        LockedPlugin::External(Box::new(LockedExternalPlugin {
+            name: name.into(),
+            source_dir: source_dir.to_path_buf(),
+            url: None,
+            host: None,
+            default_branch: None,
+            reference: None,
+            mirror: None,
+            revision: Some(revision.into()),
+            post_clone: Vec::new(),
+            plugin_dir: None,
+            files: files.iter().map(|f| source_dir.join(f)).collect(),
+            apply: vec_into!["source"],
+            apply_rules: false,
+            file_apply: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            vars: BTreeMap::new(),
+        }))
+    }
+
+    fn inline(name: &str, raw: &str) -> LockedPlugin {
+        LockedPlugin::Inline(InlinePlugin {
+            name: name.into(),
+            raw: raw.into(),
+            apply: None,
+            profiles: None,
+            enabled: None,
+            hooks: None,
+        })
+    }
+
+    #[test]
+    fn locked_config_diff() {
+        let ctx = Context {
+            home: "/home".into(),
+            ..Default::default()
+        };
+        let dir = Path::new("/home/repos/test");
+        let old = locked_config(
+            &ctx,
+            vec![
+                external(dir, "test", "be8fde277e76", &["a.zsh", "b.zsh"]),
+                inline("same", "echo 'same'"),
+                inline("removed", "echo 'removed'"),
+                inline("script", "echo 'old'"),
+            ],
+        );
+        let new = locked_config(
+            &ctx,
+            vec![
+                external(dir, "test", "09ead574b20b", &["b.zsh", "c.zsh"]),
+                inline("same", "echo 'same'"),
+                inline("script", "echo 'new'"),
+                inline("added", "echo 'added'"),
+            ],
+        );
+
+        assert_eq!(
+            new.diff(&ctx, Some(&old)).unwrap(),
+            "- removed
+~ test
+    revision: be8fde2 -> 09ead57
+    - ~/repos/test/a.zsh
+    + ~/repos/test/c.zsh
+~ script
+    inline script
++ added
+"
+        );
+        assert_eq!(new.diff(&ctx, Some(&new)).unwrap(), "");
+        assert_eq!(
+            old.diff(&ctx, None).unwrap(),
+            "+ test\n+ same\n+ removed\n+ script\n"
+        );
+    }
+}
@@ -8,32 +8,59 @@ use anyhow::{Context as ResultExt, Error, Result};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
-use crate::config::InlinePlugin;
+use crate::config::{GitReference, InlinePlugin, Rule, Template};
 use crate::context::Context;
+use crate::lock::timings::Timings;
+
+/// The version of the lock file format.
+///
+/// This must be incremented whenever [`LockedConfig`] changes in a way that
+/// lock files written by other versions can't be used as is, so that they are
+/// regenerated. Adding a `#[serde(default)]` field that nothing requires to be
+/// present doesn't need a new version.
+pub const LOCKFILE_VERSION: u32 = 2;
 
 /// A locked `Config`.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LockedConfig {
+    /// The version of the lock file format.
+    #[serde(default)]
+    pub lockfile_version: u32,
     /// The global context that was used to generated this `LockedConfig`.
     #[serde(flatten)]
     pub ctx: Context,
+    /// A hash of the contents of the config file this was generated from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+    /// The version of the shell when this was locked, if it is tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell_version: Option<String>,
     /// Each locked plugin.
     pub plugins: Vec<LockedPlugin>,
+    /// Rules for which templates to apply to matching files.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    /// Hooks executed before and after all plugins.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hooks: BTreeMap<String, String>,
     /// A map of name to template.
     ///
     /// Note: this field must come last in the struct for it to serialize
     /// properly.
-    pub templates: IndexMap<String, String>,
+    pub templates: IndexMap<String, Template>,
     /// Any errors that occurred while generating this `LockedConfig`.
     #[serde(skip)]
     pub errors: Vec<Error>,
+    /// How long each plugin took to install and render.
+    #[serde(skip)]
+    pub timings: Timings,
 }
 
 /// A locked `Plugin`.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum LockedPlugin {
-    External(LockedExternalPlugin),
+    External(Box<LockedExternalPlugin>),
     Inline(InlinePlugin),
 }
 
@@ -44,14 +71,44 @@ pub struct LockedExternalPlugin {
     pub name: String,
     /// The directory that this plugin's source resides in.
     pub source_dir: PathBuf,
+    /// The URL that this plugin's source was retrieved from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The host that this plugin's source was retrieved from, if any.
+    pub host: Option<String>,
+    /// The default branch that was checked out, if no Git reference was given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_branch: Option<String>,
+    /// The Git reference that was checked out, if one was given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<GitReference>,
+    /// The mirror that the Git repository was retrieved from, if it was not
+    /// retrieved from the main URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<String>,
+    /// The commit that the Git repository was checked out at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The commands that were run after the Git repository was checked out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_clone: Vec<String>,
     /// The directory that this plugin resides in (inside the source directory).
     pub plugin_dir: Option<PathBuf>,
     /// The files to use in the plugin directory.
     pub files: Vec<PathBuf>,
     /// What templates to apply to each file.
     pub apply: Vec<String>,
+    /// Whether the global rules take precedence over `apply` for this plugin.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub apply_rules: bool,
+    /// What templates to apply to particular files instead of `apply`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub file_apply: BTreeMap<PathBuf, Vec<String>>,
     /// Hooks executed during template evaluation.
     pub hooks: BTreeMap<String, String>,
+    /// Custom values available to templates under the `vars` namespace.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
 }
 
 impl LockedConfig {
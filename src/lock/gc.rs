@@ -0,0 +1,127 @@
+use anyhow::{Context as ResultExt, Result};
+use indexmap::IndexSet;
+
+use crate::context::Context;
+use crate::lock::file::LockedPlugin;
+use crate::lock::LockedConfig;
+use crate::util;
+use crate::util::git;
+
+impl LockedConfig {
+    /// Run Git garbage collection on each cloned source.
+    ///
+    /// Returns the total number of bytes reclaimed.
+    pub fn gc(&self, ctx: &Context) -> Result<u64> {
+        // Only sources that we cloned ourselves, local sources are left alone.
+        let dirs: IndexSet<_> = self
+            .plugins
+            .iter()
+            .filter_map(|plugin| match plugin {
+                LockedPlugin::External(plugin) => Some(plugin.source_dir.as_path()),
+                LockedPlugin::Inline(_) => None,
+            })
+            .filter(|dir| dir.starts_with(ctx.clone_dir()) && git::open(dir).is_ok())
+            .collect();
+
+        let mut reclaimed = 0;
+        for dir in dirs {
+            let before = util::dir_size(dir);
+            git::gc(dir).with_context(|| {
+                format!(
+                    "failed to garbage collect `{}`",
+                    ctx.replace_home(dir).display()
+                )
+            })?;
+            let saved = before.saturating_sub(util::dir_size(dir));
+            reclaimed += saved;
+            ctx.log_status(
+                "Collected",
+                &format!(
+                    "{} ({} reclaimed)",
+                    ctx.replace_home(dir).display(),
+                    util::format_bytes(saved)
+                ),
+            );
+        }
+        Ok(reclaimed)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+
+    use crate::lock::file::{LockedExternalPlugin, LOCKFILE_VERSION};
+
+    fn git_commit_plugin(dir: &Path) {
+        let repo = git2::Repository::init(dir).unwrap();
+        fs::write(dir.join("test.plugin.zsh"), "echo 'testing...'\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("test.plugin.zsh")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn locked_config_gc() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let source_dir = ctx.clone_dir().join("example.com/test");
+        fs::create_dir_all(&source_dir).unwrap();
+        git_commit_plugin(&source_dir);
+
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: "test".into(),
+                source_dir: source_dir.clone(),
+                url: None,
+                host: Some("example.com".into()),
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![source_dir.join("test.plugin.zsh")],
+                apply: vec_into!["source"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            }))],
+            templates: crate::config::Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        locked.gc(&ctx).unwrap();
+
+        let repo = git2::Repository::open(&source_dir).unwrap();
+        assert!(repo.head().unwrap().target().is_some());
+        assert!(locked.verify(&ctx));
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            format!(
+                "source \"{}\"\n",
+                source_dir.join("test.plugin.zsh").display()
+            )
+        );
+    }
+}
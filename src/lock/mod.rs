@@ -1,21 +1,36 @@
+mod diff;
 mod file;
+mod gc;
 mod plugin;
+mod restore;
 mod script;
 pub mod source;
+mod timings;
+mod which;
 
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 
-use anyhow::{Context as ResultExt, Result};
-use indexmap::{indexmap, IndexMap};
+use anyhow::{anyhow, bail, Context as ResultExt, Error, Result};
+use indexmap::{indexmap, IndexMap, IndexSet};
 use itertools::{Either, Itertools};
 use once_cell::sync::Lazy;
 use rayon::prelude::*;
+use regex_macro::regex;
+use url::Url;
 
-use crate::config::{Config, MatchesProfile, Plugin, Shell};
-use crate::context::Context;
+use crate::config::{Config, ExternalPlugin, MatchesProfile, Plugin, Shell, Source, Template};
+use crate::context::{capture, Context};
 pub use crate::lock::file::LockedConfig;
+use crate::lock::file::LOCKFILE_VERSION;
 use crate::lock::file::{LockedExternalPlugin, LockedPlugin};
+pub use crate::lock::script::Annotate;
+use crate::lock::timings::Timings;
+use crate::util;
 
 /// Behaviour when locking a config file.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -28,17 +43,56 @@ pub enum LockMode {
     Reinstall,
 }
 
+/// Context attached to an error that occurred while installing plugins.
+///
+/// This records which plugins were affected so that they can be reported.
+#[derive(Debug)]
+pub struct InstallError {
+    /// The message for this context.
+    pub message: String,
+    /// The names of the plugins that failed to install.
+    pub plugins: Vec<String>,
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The maximum number of sources to install concurrently.
+///
+/// Installing a source is almost entirely network bound so this is independent
+/// of the number of CPUs.
+const MAX_JOBS: usize = 16;
+
 /// Read a [`LockedConfig`] from the given path.
 pub fn from_path<P>(path: P) -> Result<LockedConfig>
 where
     P: AsRef<Path>,
 {
     let path = path.as_ref();
-    let locked: LockedConfig = toml::from_str(&String::from_utf8_lossy(
+    let contents = String::from_utf8_lossy(
         &fs::read(path)
             .with_context(|| format!("failed to read locked config from `{}`", path.display()))?,
-    ))
-    .context("failed to deserialize locked config")?;
+    )
+    .into_owned();
+    // Check the version before deserializing because the rest of the file might
+    // have a different shape.
+    let table: toml::Table = toml::from_str(&contents).context("failed to parse locked config")?;
+    let version = match table.get("lockfile_version") {
+        Some(toml::Value::Integer(version)) => *version,
+        Some(_) => bail!("locked config has an invalid `lockfile_version`"),
+        None => 0,
+    };
+    if version != i64::from(LOCKFILE_VERSION) {
+        bail!(
+            "locked config has version {version} but version {LOCKFILE_VERSION} is required, run \
+             `sheldon lock` to regenerate it"
+        );
+    }
+    let locked: LockedConfig =
+        toml::from_str(&contents).context("failed to deserialize locked config")?;
     Ok(locked)
 }
 
@@ -46,23 +100,21 @@ where
 ///
 /// This method installs all necessary remote dependencies of plugins,
 /// validates that local plugins are present, and checks that templates
-/// can compile.
-pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
+/// can compile. Plugins that look to be accidentally duplicated are reported
+/// as warnings.
+pub fn config(ctx: &Context, config: Config, warnings: &mut Vec<Error>) -> Result<LockedConfig> {
     let Config {
         shell,
+        track_shell_version,
         matches,
         apply,
         templates,
+        rules,
+        hooks,
         plugins,
     } = config;
 
-    let templates = {
-        let mut map = shell.default_templates().clone();
-        for (name, template) in templates {
-            map.insert(name, template);
-        }
-        map
-    };
+    let templates = shell.merge_templates(templates);
 
     // Partition the plugins into external and inline plugins.
     let (externals, inlines): (Vec<_>, Vec<_>) =
@@ -75,7 +127,7 @@ pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
             });
     let inlines = inlines
         .into_iter()
-        .filter(|(_, p)| p.matches_profile(ctx))
+        .filter(|(_, p)| p.enabled != Some(false) && p.matches_profile(ctx))
         .map(|(i, p)| (i, LockedPlugin::Inline(p)));
 
     // Create a map of unique `Source` to `Vec<Plugin>`
@@ -92,44 +144,82 @@ pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
     let apply = apply.as_ref().unwrap_or_else(|| Shell::default_apply());
     let count = map.len();
     let mut errors = Vec::new();
+    let timings = Timings::default();
 
-    let plugins = if count == 0 {
+    let mut plugins = if count == 0 {
         inlines
             .into_iter()
             .map(|(_, locked)| locked)
             .collect::<Vec<_>>()
     } else {
-        // Install the sources in parallel.
-        map.into_par_iter()
-            .map(|(source, plugins)| {
-                let source_name = source.to_string();
-                let plugins: Vec<_> = plugins
-                    .into_iter()
-                    .filter(|(_, p)| p.matches_profile(ctx))
-                    .collect();
-
-                if plugins.is_empty() {
-                    ctx.log_status("Skipped", &source_name);
-                    Ok(vec![])
-                } else {
-                    let source = source::lock(ctx, source)
-                        .with_context(|| format!("failed to install source `{source_name}`"))?;
-
-                    let mut locked = Vec::with_capacity(plugins.len());
-                    for (index, plugin) in plugins {
-                        let name = plugin.name.clone();
-                        let plugin = plugin::lock(ctx, source.clone(), matches, apply, plugin)
-                            .with_context(|| format!("failed to install plugin `{name}`"));
-                        locked.push((index, plugin));
-                    }
-                    Ok(locked)
+        let progress = ctx.progress(count);
+        let install = |(source, plugins): (Source, Vec<(usize, ExternalPlugin)>)| -> Result<_> {
+            let source_name = source.to_string();
+            let plugins: Vec<_> = plugins
+                .into_iter()
+                .filter(|(_, p)| p.enabled != Some(false) && p.matches_profile(ctx))
+                .collect();
+
+            if plugins.is_empty() {
+                ctx.log_status("Skipped", &source_name);
+                progress.finish(&source_name);
+                Ok(vec![])
+            } else {
+                let now = Instant::now();
+                progress.start(&source_name);
+                let source = source::lock(ctx, source);
+                progress.finish(&source_name);
+                let source = source.with_context(|| InstallError {
+                    message: format!("failed to install source `{source_name}`"),
+                    plugins: plugins.iter().map(|(_, p)| p.name.clone()).collect(),
+                })?;
+                let names = plugins.iter().map(|(_, p)| p.name.as_str()).join(", ");
+                timings.record(names, "install", now.elapsed());
+
+                let mut locked = Vec::with_capacity(plugins.len());
+                for (index, plugin) in plugins {
+                    let name = plugin.name.clone();
+                    let plugin = plugin::lock(ctx, source.clone(), matches, apply, &rules, plugin)
+                        .with_context(|| InstallError {
+                            message: format!("failed to install plugin `{name}`"),
+                            plugins: vec![name],
+                        });
+                    locked.push((index, plugin));
                 }
+                Ok(locked)
+            }
+        };
+        let jobs = ctx.jobs.map_or(MAX_JOBS, NonZeroUsize::get).min(count);
+        let installed = if jobs == 1 {
+            // Install the sources one at a time in order which makes the output
+            // deterministic.
+            map.into_iter().map(install).collect::<Vec<_>>()
+        } else {
+            // Install the sources in parallel. We use a dedicated pool because
+            // the global one is sized by the number of CPUs which would
+            // serialize downloads on small machines.
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .context("failed to build thread pool")?;
+            // The output of each source is captured and logged as soon as the
+            // source is finished so that it isn't interleaved with the output
+            // of other sources.
+            pool.install(|| {
+                map.into_par_iter()
+                    .map(|entry| {
+                        let (result, lines) = capture(|| install(entry));
+                        ctx.log_captured(lines);
+                        result
+                    })
+                    .collect::<Vec<_>>()
             })
+        };
+        installed
             // The result of this is basically an `Iter<Result<Vec<(usize, Result)>, _>>`
             // The first thing we need to do is to filter out the failures and record the
             // errors that occurred while installing the source in our `errors` list.
             // Finally, we flatten the sub lists into a single iterator.
-            .collect::<Vec<_>>()
             .into_iter()
             .filter_map(|result| match result {
                 Ok(ok) => Some(ok),
@@ -149,7 +239,7 @@ pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
             .collect::<Vec<_>>()
             .into_iter()
             .filter_map(|(index, result)| match result {
-                Ok(plugin) => Some((index, LockedPlugin::External(plugin))),
+                Ok(plugin) => Some((index, LockedPlugin::External(Box::new(plugin)))),
                 Err(err) => {
                     errors.push(err);
                     None
@@ -161,17 +251,48 @@ pub fn config(ctx: &Context, config: Config) -> Result<LockedConfig> {
             .collect::<Vec<_>>()
     };
 
+    skip_non_utf8_files(&mut plugins, warnings);
+    check_duplicates(&plugins, warnings);
+    check_templates(&plugins, &templates, warnings);
+
+    let shell_version = if track_shell_version {
+        match shell_version(shell) {
+            Ok(version) => Some(version),
+            Err(err) => {
+                warnings.push(err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let config_hash = if ctx.config_from_stdin() {
+        None
+    } else {
+        util::hash_file(ctx.config_file()).ok()
+    };
+
     Ok(LockedConfig {
-        ctx: ctx.clone(),
+        lockfile_version: LOCKFILE_VERSION,
+        ctx: Context {
+            shell: Some(shell),
+            ..ctx.clone()
+        },
+        config_hash,
+        shell_version,
         templates,
+        rules,
+        hooks,
         errors,
+        timings,
         plugins,
     })
 }
 
 impl Shell {
     /// The default files to match on for this shell.
-    fn default_matches(&self) -> &[String] {
+    pub fn default_matches(&self) -> &[String] {
         static DEFAULT_MATCHES_BASH: Lazy<Vec<String>> = Lazy::new(|| {
             vec_into![
                 "{{ name }}.plugin.bash",
@@ -196,6 +317,8 @@ impl Shell {
                 "*.fish"
             ]
         });
+        static DEFAULT_MATCHES_NU: Lazy<Vec<String>> =
+            Lazy::new(|| vec_into!["{{ name }}.nu", "*.nu"]);
         static DEFAULT_MATCHES_ZSH: Lazy<Vec<String>> = Lazy::new(|| {
             vec_into![
                 "{{ name }}.plugin.zsh",
@@ -211,41 +334,62 @@ impl Shell {
         match self {
             Self::Bash => &DEFAULT_MATCHES_BASH,
             Self::Fish => &DEFAULT_MATCHES_FISH,
+            Self::Nu => &DEFAULT_MATCHES_NU,
             Self::Zsh => &DEFAULT_MATCHES_ZSH,
         }
     }
 
     /// The default templates for this shell.
-    pub fn default_templates(&self) -> &IndexMap<String, String> {
-        static DEFAULT_TEMPLATES_BASH: Lazy<IndexMap<String, String>> = Lazy::new(|| {
+    pub fn default_templates(&self) -> &IndexMap<String, Template> {
+        static DEFAULT_TEMPLATES_BASH: Lazy<IndexMap<String, Template>> = Lazy::new(|| {
             indexmap_into! {
                 "PATH" => "export PATH=\"{{ dir }}:$PATH\"",
                 "source" => "{{ hooks?.pre | nl }}{% for file in files %}source \"{{ file }}\"\n{% endfor %}{{ hooks?.post | nl }}"
             }
         });
-        static DEFAULT_TEMPLATES_FISH: Lazy<IndexMap<String, String>> = Lazy::new(|| {
+        static DEFAULT_TEMPLATES_FISH: Lazy<IndexMap<String, Template>> = Lazy::new(|| {
             indexmap_into! {
                 "add_path" => "fish_add_path \"{{ dir }}\"",
                 "source" => "{{ hooks?.pre | nl }}{% for file in files %}source \"{{ file }}\"\n{% endfor %}{{ hooks?.post | nl }}"
             }
         });
-        static DEFAULT_TEMPLATES_ZSH: Lazy<IndexMap<String, String>> = Lazy::new(|| {
+        static DEFAULT_TEMPLATES_NU: Lazy<IndexMap<String, Template>> = Lazy::new(|| {
+            indexmap_into! {
+                "PATH" => "$env.PATH = ($env.PATH | prepend \"{{ dir }}\")",
+                "source" => "{{ hooks?.pre | nl }}{% for file in files %}source \"{{ file }}\"\n{% endfor %}{{ hooks?.post | nl }}"
+            }
+        });
+        static DEFAULT_TEMPLATES_ZSH: Lazy<IndexMap<String, Template>> = Lazy::new(|| {
             indexmap_into! {
                 "PATH" => "export PATH=\"{{ dir }}:$PATH\"",
                 "path" => "path=( \"{{ dir }}\" $path )",
                 "fpath" => "fpath=( \"{{ dir }}\" $fpath )",
-                "source" => "{{ hooks?.pre | nl }}{% for file in files %}source \"{{ file }}\"\n{% endfor %}{{ hooks?.post | nl }}"
+                "source" => "{{ hooks?.pre | nl }}{% for file in files %}source \"{{ file }}\"\n{% endfor %}{{ hooks?.post | nl }}",
+                "theme" => "{{ hooks?.pre | nl }}setopt prompt_subst\n{% for file in files %}source \"{{ file }}\"\n{% endfor %}{{ hooks?.post | nl }}"
             }
         });
         match self {
             Self::Bash => &DEFAULT_TEMPLATES_BASH,
             Self::Fish => &DEFAULT_TEMPLATES_FISH,
+            Self::Nu => &DEFAULT_TEMPLATES_NU,
             Self::Zsh => &DEFAULT_TEMPLATES_ZSH,
         }
     }
 
+    /// The default templates for this shell merged with the given templates.
+    ///
+    /// A given template replaces the default template with the same name.
+    pub fn merge_templates<I>(&self, templates: I) -> IndexMap<String, Template>
+    where
+        I: IntoIterator<Item = (String, Template)>,
+    {
+        let mut map = self.default_templates().clone();
+        map.extend(templates);
+        map
+    }
+
     /// The default template names to apply.
-    fn default_apply() -> &'static Vec<String> {
+    pub fn default_apply() -> &'static Vec<String> {
         static DEFAULT_APPLY: Lazy<Vec<String>> = Lazy::new(|| vec_into!["source"]);
         &DEFAULT_APPLY
     }
@@ -253,10 +397,19 @@ impl Shell {
 
 impl LockedConfig {
     /// Verify that the `LockedConfig` is okay.
+    ///
+    /// If strict verification is enabled this also checks that each Git
+    /// source is still checked out at the locked revision, or at the locked
+    /// reference for lock files without a revision.
     pub fn verify(&self, ctx: &Context) -> bool {
         if !is_context_equal(&self.ctx, ctx) {
             return false;
         }
+        if let (Some(shell), Some(version)) = (self.ctx.shell, &self.shell_version) {
+            if shell_version(shell).ok().as_ref() != Some(version) {
+                return false;
+            }
+        }
         for plugin in &self.plugins {
             match plugin {
                 LockedPlugin::External(plugin) => {
@@ -268,12 +421,173 @@ impl LockedConfig {
                             return false;
                         }
                     }
+                    if ctx.strict_verify {
+                        let checked_out = match &plugin.revision {
+                            Some(revision) => source::is_at_revision(&plugin.source_dir, revision),
+                            None if plugin.reference.is_some()
+                                || plugin.default_branch.is_some() =>
+                            {
+                                source::is_checked_out(&plugin.source_dir, plugin.reference.clone())
+                            }
+                            None => true,
+                        };
+                        if !checked_out {
+                            return false;
+                        }
+                    }
                 }
                 LockedPlugin::Inline(_) => {}
             }
         }
         true
     }
+
+    /// The sources in the clone and download directories that were installed
+    /// for this config.
+    ///
+    /// For remote sources this is the downloaded file, because they share
+    /// their directory, for remote index sources it is the index file and the
+    /// directory of files it lists, otherwise it is the source directory.
+    pub fn installed(&self, ctx: &Context) -> IndexSet<PathBuf> {
+        let mut paths = IndexSet::new();
+        for plugin in &self.plugins {
+            let LockedPlugin::External(plugin) = plugin else {
+                continue;
+            };
+            let dir = plugin.source_dir.as_path();
+            if dir.starts_with(ctx.clone_dir()) || source::is_archive_dir(ctx, dir) {
+                paths.insert(dir.to_path_buf());
+            } else if let Some(index) = remote_index_file(ctx, plugin) {
+                paths.insert(dir.to_path_buf());
+                paths.insert(index);
+            } else if dir.starts_with(ctx.download_dir()) {
+                paths.extend(plugin.files.iter().cloned());
+            }
+        }
+        paths
+    }
+}
+
+/// The index file of a remote index plugin, if it is one.
+fn remote_index_file(ctx: &Context, plugin: &LockedExternalPlugin) -> Option<PathBuf> {
+    let url = Url::parse(plugin.url.as_deref()?).ok()?;
+    let (dir, file) = source::remote_index_dir_and_file(ctx, &url).ok()?;
+    (dir == plugin.source_dir).then_some(file)
+}
+
+/// Detect the version of the given shell.
+///
+/// This is the first line of the output of `<shell> --version`.
+fn shell_version(shell: Shell) -> Result<String> {
+    let program = shell.to_string();
+    let output = process::Command::new(&program)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("failed to detect the version of `{program}`"))?;
+    if !output.status.success() {
+        bail!(
+            "failed to detect the version of `{program}`, `{program} --version` failed ({})",
+            output.status
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or_default().trim().to_string())
+}
+
+/// Remove the files of external plugins whose paths are not valid UTF-8.
+///
+/// These can't be rendered in the script, so rather than failing the whole
+/// lock each one is skipped with a warning.
+fn skip_non_utf8_files(plugins: &mut [LockedPlugin], warnings: &mut Vec<Error>) {
+    for plugin in plugins {
+        if let LockedPlugin::External(plugin) = plugin {
+            plugin.files.retain(|file| {
+                let valid = file.to_str().is_some();
+                if !valid {
+                    warnings.push(anyhow!(
+                        "skipped file `{}` in plugin `{}` because its path is not valid UTF-8",
+                        file.display(),
+                        plugin.name
+                    ));
+                }
+                valid
+            });
+        }
+    }
+}
+
+/// Check for external plugins that have the same source and use some of the
+/// same files, this is most likely a copy-paste mistake.
+fn check_duplicates(plugins: &[LockedPlugin], warnings: &mut Vec<Error>) {
+    let externals: Vec<_> = plugins
+        .iter()
+        .filter_map(|plugin| match plugin {
+            LockedPlugin::External(plugin) => Some(plugin),
+            LockedPlugin::Inline(_) => None,
+        })
+        .collect();
+    for (i, plugin) in externals.iter().enumerate() {
+        if let Some(other) = externals[..i].iter().find(|other| {
+            other.source_dir == plugin.source_dir
+                && other.files.iter().any(|f| plugin.files.contains(f))
+        }) {
+            warnings.push(anyhow!(
+                "plugin `{}` has the same source and files as plugin `{}`",
+                plugin.name,
+                other.name
+            ));
+        }
+    }
+}
+
+/// Check for plugins that apply a template that renders each file when the
+/// plugin has no files, the template would then render nothing.
+///
+/// Templates that a `use` glob applies to particular files are not checked,
+/// they are only ever applied to files that were matched.
+fn check_templates(
+    plugins: &[LockedPlugin],
+    templates: &IndexMap<String, Template>,
+    warnings: &mut Vec<Error>,
+) {
+    let renders_files = |name: &&String| {
+        templates
+            .get(name.as_str())
+            .is_some_and(|t| uses_files(&t.value))
+    };
+    for plugin in plugins {
+        match plugin {
+            LockedPlugin::External(plugin) if plugin.files.is_empty() => {
+                for name in plugin.apply.iter().filter(renders_files) {
+                    warnings.push(anyhow!(
+                        "plugin `{}` applies template `{name}` which renders each file, but no \
+                         files were matched\nSet the `use` field to select files, or apply a \
+                         directory template like `PATH` instead",
+                        plugin.name
+                    ));
+                }
+            }
+            LockedPlugin::Inline(plugin) => {
+                if let Some(name) = plugin.apply.as_ref().filter(renders_files) {
+                    warnings.push(anyhow!(
+                        "inline plugin `{}` applies template `{name}` which renders each file, \
+                         but inline plugins have no files\nUse `{{{{ raw }}}}` in the template \
+                         to render the inline source instead",
+                        plugin.name
+                    ));
+                }
+            }
+            LockedPlugin::External(_) => {}
+        }
+    }
+}
+
+/// Whether a template references the `files` variable in any of its
+/// expressions or blocks.
+fn uses_files(template: &str) -> bool {
+    regex!(r"(?s)\{[{%](.*?)[%}]\}")
+        .captures_iter(template)
+        .any(|caps| regex!(r"(?:^|[^\w.])files\b").is_match(&caps[1]))
 }
 
 fn is_context_equal(left: &Context, right: &Context) -> bool {
@@ -283,6 +597,17 @@ fn is_context_equal(left: &Context, right: &Context) -> bool {
         && left.data_dir == right.data_dir
         && left.config_file == right.config_file
         && left.profile == right.profile
+        && left.shell == right.shell
+}
+
+impl LockedPlugin {
+    /// Return the name of this plugin.
+    fn name(&self) -> &str {
+        match self {
+            Self::External(plugin) => &plugin.name,
+            Self::Inline(plugin) => &plugin.name,
+        }
+    }
 }
 
 impl LockedExternalPlugin {
@@ -298,13 +623,13 @@ impl LockedExternalPlugin {
 
 #[cfg(test)]
 mod tests {
-    use url::Url;
 
     use super::*;
 
+    use std::collections::BTreeMap;
     use std::io::prelude::*;
 
-    use crate::config::{ExternalPlugin, Source};
+    use crate::config::{ExternalPlugin, InlinePlugin, Source, Use};
     use crate::context::Output;
     use crate::util::build;
 
@@ -320,12 +645,19 @@ mod tests {
                 data_dir: root.to_path_buf(),
                 config_dir: root.to_path_buf(),
                 profile: Some("profile".into()),
+                shell: Some(Shell::Zsh),
                 output: Output {
                     verbosity: crate::context::Verbosity::Quiet,
                     no_color: true,
+                    json_errors: false,
                 },
                 interactive: true,
                 lock_mode: None,
+                strict_verify: false,
+                offline: false,
+                timings: false,
+                jobs: None,
+                max_download_size: None,
             }
         }
     }
@@ -337,13 +669,16 @@ mod tests {
         let ctx = Context::testing(dir);
         let cfg = Config {
             shell: Shell::Zsh,
+            track_shell_version: false,
             matches: None,
             apply: None,
             templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
             plugins: Vec::new(),
         };
 
-        let locked = config(&ctx, cfg).unwrap();
+        let locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
 
         assert_eq!(locked.ctx, ctx);
         assert_eq!(locked.plugins, Vec::new());
@@ -354,26 +689,81 @@ mod tests {
         assert_eq!(locked.errors.len(), 0);
     }
 
+    #[test]
+    fn lock_config_track_shell_version() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context {
+            shell: Some(Shell::Bash),
+            ..Context::testing(dir)
+        };
+        let cfg = Config {
+            shell: Shell::Bash,
+            track_shell_version: true,
+            ..Config::default()
+        };
+
+        let mut locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
+        assert_eq!(locked.ctx.shell, Some(Shell::Bash));
+        assert!(locked.shell_version.as_ref().unwrap().contains("bash"));
+        assert!(locked.verify(&ctx));
+
+        let path = dir.join("plugins.lock");
+        locked.to_path(&path).unwrap();
+        let read = from_path(&path).unwrap();
+        assert_eq!(read.ctx.shell, locked.ctx.shell);
+        assert_eq!(read.shell_version, locked.shell_version);
+
+        locked.shell_version = Some("GNU bash, version 1.0".into());
+        assert!(!locked.verify(&ctx));
+    }
+
+    #[test]
+    fn lock_config_verify_shell() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let cfg = Config {
+            shell: Shell::Bash,
+            ..Config::default()
+        };
+
+        let locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
+        assert_eq!(locked.ctx.shell, Some(Shell::Bash));
+        // The context is for zsh so the lock file must be regenerated.
+        assert!(!locked.verify(&ctx));
+        let ctx = Context {
+            shell: Some(Shell::Bash),
+            ..ctx
+        };
+        assert!(locked.verify(&ctx));
+    }
+
     #[test]
     fn locked_config_clean() {
         let temp = tempfile::tempdir().expect("create temporary directory");
         let ctx = Context::testing(temp.path());
         let cfg = Config {
             shell: Shell::Zsh,
+            track_shell_version: false,
             matches: None,
             apply: None,
             templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
             plugins: vec![Plugin::External(ExternalPlugin {
                 name: "test".to_string(),
                 source: Source::Git {
                     url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                     reference: None,
+                    options: Box::default(),
                 },
                 dir: None,
                 uses: None,
                 apply: None,
                 profiles: None,
+                enabled: None,
                 hooks: None,
+                vars: None,
             })],
         };
         let test_dir = ctx.clone_dir().join("github.com/rossmacarthur/another-dir");
@@ -382,6 +772,7 @@ mod tests {
         {
             fs::OpenOptions::new()
                 .create(true)
+                .truncate(true)
                 .write(true)
                 .open(&test_file)
                 .unwrap();
@@ -391,7 +782,7 @@ mod tests {
         assert!(warnings.is_empty());
         assert!(!test_file.exists());
         assert!(!test_dir.exists());
-        let _locked_cfg = config(&ctx, cfg).unwrap();
+        let _locked_cfg = config(&ctx, cfg, &mut Vec::new()).unwrap();
         assert!(ctx
             .clone_dir()
             .join("github.com/rossmacarthur/sheldon-test")
@@ -402,10 +793,621 @@ mod tests {
             .exists());
     }
 
+    #[test]
+    fn locked_config_clean_nested() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut files = Vec::new();
+        for root in [ctx.clone_dir(), ctx.download_dir()] {
+            for i in 0..10 {
+                let dir = root.join(format!("github.com/rossmacarthur/stale-{i}/nested"));
+                fs::create_dir_all(&dir).unwrap();
+                for name in ["a.zsh", "b.zsh"] {
+                    let file = dir.join(name);
+                    fs::write(&file, "").unwrap();
+                    files.push(file);
+                }
+            }
+        }
+
+        let mut warnings = Vec::new();
+        crate::config::clean(&ctx, &mut warnings, &Config::default()).unwrap();
+
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert!(files.iter().all(|f| !f.exists()));
+        assert!(!ctx.clone_dir().join("github.com").exists());
+        assert!(!ctx.download_dir().join("github.com").exists());
+        assert!(ctx.clone_dir().exists());
+        assert!(ctx.download_dir().exists());
+    }
+
+    #[test]
+    fn locked_config_prune() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let url =
+            |name: &str| Url::parse(&format!("https://github.com/rossmacarthur/{name}")).unwrap();
+        let cfg = Config {
+            shell: Shell::Zsh,
+            track_shell_version: false,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            plugins: vec![Plugin::External(ExternalPlugin {
+                name: "kept".to_string(),
+                source: Source::Remote {
+                    url: url("kept.zsh"),
+                    index: false,
+                },
+                dir: None,
+                uses: None,
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })],
+        };
+        let download_dir = ctx.download_dir().join("github.com/rossmacarthur");
+        let clone_dir = ctx.clone_dir().join("github.com/rossmacarthur/removed");
+        let unknown_dir = ctx.clone_dir().join("github.com/rossmacarthur/unknown");
+        let local_dir = temp.path().join("local");
+        for dir in [&download_dir, &clone_dir, &unknown_dir, &local_dir] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for file in ["kept.zsh", "removed.zsh"] {
+            fs::write(download_dir.join(file), "").unwrap();
+        }
+        let plugin = |name: &str, source_dir: &Path, files: Vec<PathBuf>| {
+            LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: name.into(),
+                source_dir: source_dir.to_path_buf(),
+                url: None,
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files,
+                apply: vec_into!["source"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            }))
+        };
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![
+                plugin("kept", &download_dir, vec![download_dir.join("kept.zsh")]),
+                plugin(
+                    "removed-remote",
+                    &download_dir,
+                    vec![download_dir.join("removed.zsh")],
+                ),
+                plugin("removed-git", &clone_dir, Vec::new()),
+                plugin("local", &local_dir, Vec::new()),
+            ],
+            rules: Vec::new(),
+            templates: IndexMap::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Timings::default(),
+        };
+
+        let mut warnings = Vec::new();
+        let removed =
+            crate::config::prune(&ctx, &mut warnings, &cfg, locked.installed(&ctx)).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(removed, 2);
+        assert!(download_dir.join("kept.zsh").exists());
+        assert!(!download_dir.join("removed.zsh").exists());
+        assert!(!clone_dir.exists());
+        assert!(unknown_dir.exists());
+        assert!(local_dir.exists());
+    }
+
+    #[test]
+    fn locked_config_prune_remote_index() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let url =
+            |name: &str| Url::parse(&format!("https://github.com/rossmacarthur/{name}")).unwrap();
+        let cfg = Config {
+            shell: Shell::Zsh,
+            track_shell_version: false,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            plugins: vec![Plugin::External(ExternalPlugin {
+                name: "kept".to_string(),
+                source: Source::Remote {
+                    url: url("kept.txt"),
+                    index: true,
+                },
+                dir: None,
+                uses: None,
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })],
+        };
+        let mut plugins = Vec::new();
+        let mut paths = Vec::new();
+        for name in ["kept", "removed"] {
+            let url = url(&format!("{name}.txt"));
+            let (dir, index) = source::remote_index_dir_and_file(&ctx, &url).unwrap();
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(&index, "a.zsh\n").unwrap();
+            fs::write(dir.join("a.zsh"), "").unwrap();
+            plugins.push(LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: name.into(),
+                source_dir: dir.clone(),
+                url: Some(url.to_string()),
+                host: url.host_str().map(String::from),
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![dir.join("a.zsh")],
+                apply: vec_into!["source"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            })));
+            paths.push((dir, index));
+        }
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins,
+            rules: Vec::new(),
+            templates: IndexMap::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Timings::default(),
+        };
+
+        let mut warnings = Vec::new();
+        let removed =
+            crate::config::prune(&ctx, &mut warnings, &cfg, locked.installed(&ctx)).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(removed, 2);
+        let (kept_dir, kept_index) = &paths[0];
+        assert!(kept_index.exists());
+        assert!(kept_dir.join("a.zsh").exists());
+        let (removed_dir, removed_index) = &paths[1];
+        assert!(!removed_index.exists());
+        assert!(!removed_dir.exists());
+    }
+
+    #[test]
+    fn lock_config_duplicate_source_and_files() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.plugin.zsh"), "echo 'testing...'\n").unwrap();
+        let plugin = |name: &str, uses: Option<Vec<Use>>| {
+            Plugin::External(ExternalPlugin {
+                name: name.into(),
+                source: Source::Local { dir: dir.clone() },
+                dir: None,
+                uses,
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })
+        };
+        let cfg = Config {
+            shell: Shell::Zsh,
+            track_shell_version: false,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            plugins: vec![
+                plugin("one", None),
+                plugin("two", None),
+                plugin("three", Some(vec_into!["*.txt"])),
+            ],
+        };
+        fs::write(dir.join("test.txt"), "").unwrap();
+
+        let mut warnings = Vec::new();
+        let locked = config(&ctx, cfg, &mut warnings).unwrap();
+
+        assert_eq!(locked.plugins.len(), 3);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "plugin `two` has the same source and files as plugin `one`"
+        );
+    }
+
+    #[test]
+    fn lock_config_local_file() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let file = temp.path().join("scripts/test.sh");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "echo 'testing...'\n").unwrap();
+        fs::write(temp.path().join("scripts/other.sh"), "").unwrap();
+        let cfg = Config {
+            shell: Shell::Zsh,
+            track_shell_version: false,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            plugins: vec![Plugin::External(ExternalPlugin {
+                name: "test".into(),
+                source: Source::Local { dir: file.clone() },
+                dir: None,
+                uses: None,
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })],
+        };
+
+        let locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
+
+        match &locked.plugins[..] {
+            [LockedPlugin::External(plugin)] => {
+                assert_eq!(plugin.source_dir, temp.path().join("scripts"));
+                assert_eq!(plugin.files, vec![file]);
+            }
+            plugins => panic!("unexpected plugins {plugins:?}"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_config_skips_non_utf8_files() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        let valid = dir.join("a.zsh");
+        let invalid = dir.join(OsStr::from_bytes(b"b\xff.zsh"));
+        fs::write(&valid, "").unwrap();
+        fs::write(&invalid, "").unwrap();
+        let cfg = Config {
+            plugins: vec![Plugin::External(ExternalPlugin {
+                name: "test".into(),
+                source: Source::Local { dir },
+                dir: None,
+                uses: Some(vec_into!["*.zsh"]),
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })],
+            ..Config::default()
+        };
+
+        let mut warnings = Vec::new();
+        let locked = config(&ctx, cfg, &mut warnings).unwrap();
+
+        match &locked.plugins[..] {
+            [LockedPlugin::External(plugin)] => assert_eq!(plugin.files, vec![valid]),
+            plugins => panic!("unexpected plugins {plugins:?}"),
+        }
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            format!(
+                "skipped file `{}` in plugin `test` because its path is not valid UTF-8",
+                invalid.display()
+            )
+        );
+        assert!(locked.script(&ctx, &mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn lock_config_template_renders_no_files() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("README.md"), "").unwrap();
+        let external = |name: &str, apply: Vec<String>| {
+            Plugin::External(ExternalPlugin {
+                name: name.into(),
+                source: Source::Local { dir: dir.clone() },
+                dir: None,
+                uses: None,
+                apply: Some(apply),
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })
+        };
+        let cfg = Config {
+            shell: Shell::Zsh,
+            track_shell_version: false,
+            matches: None,
+            apply: None,
+            templates: indexmap! {
+                "dotfiles".into() => Template {
+                    value: "export PATH=\"{{ dir }}/dotfiles:$PATH\"".into(),
+                    when_host: None,
+                },
+            },
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            plugins: vec![
+                external("one", vec_into!["source"]),
+                external("two", vec_into!["PATH"]),
+                external("four", vec_into!["dotfiles"]),
+                Plugin::Inline(InlinePlugin {
+                    name: "three".into(),
+                    raw: "echo 'three'".into(),
+                    apply: Some("source".into()),
+                    profiles: None,
+                    enabled: None,
+                    hooks: None,
+                }),
+            ],
+        };
+
+        let mut warnings = Vec::new();
+        config(&ctx, cfg, &mut warnings).unwrap();
+
+        let warnings: Vec<_> = warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            warnings,
+            [
+                "plugin `one` applies template `source` which renders each file, but no files \
+                 were matched\nSet the `use` field to select files, or apply a directory \
+                 template like `PATH` instead",
+                "inline plugin `three` applies template `source` which renders each file, but \
+                 inline plugins have no files\nUse `{{ raw }}` in the template to render the \
+                 inline source instead",
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_files_only_matches_the_files_variable() {
+        for template in [
+            "{% for file in files %}source \"{{ file }}\"\n{% endfor %}",
+            "{%- for f in files -%}{{ f }}{%- endfor -%}",
+            "{{ files | length }}",
+        ] {
+            assert!(uses_files(template), "{template}");
+        }
+        for template in [
+            "echo 'files'",
+            "export PATH=\"{{ dir }}/dotfiles:$PATH\"",
+            "{% for p in profiles %}{{ p }}{% endfor %}",
+            "{{ hooks.files }}",
+        ] {
+            assert!(!uses_files(template), "{template}");
+        }
+    }
+
+    #[test]
+    fn lock_config_skips_disabled_plugins() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.plugin.zsh"), "").unwrap();
+        let cfg = Config {
+            shell: Shell::Zsh,
+            track_shell_version: false,
+            matches: None,
+            apply: None,
+            templates: IndexMap::new(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            plugins: vec![
+                Plugin::External(ExternalPlugin {
+                    name: "external".into(),
+                    source: Source::Local { dir },
+                    dir: None,
+                    uses: None,
+                    apply: None,
+                    profiles: None,
+                    enabled: Some(false),
+                    hooks: None,
+                    vars: None,
+                }),
+                Plugin::Inline(InlinePlugin {
+                    name: "disabled".into(),
+                    raw: "echo 'disabled'".into(),
+                    apply: None,
+                    profiles: None,
+                    enabled: Some(false),
+                    hooks: None,
+                }),
+                Plugin::Inline(InlinePlugin {
+                    name: "enabled".into(),
+                    raw: "echo 'enabled'".into(),
+                    apply: None,
+                    profiles: None,
+                    enabled: Some(true),
+                    hooks: None,
+                }),
+            ],
+        };
+
+        let locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
+
+        assert_eq!(locked.plugins.len(), 1);
+        assert!(matches!(
+            &locked.plugins[0],
+            LockedPlugin::Inline(p) if p.name == "enabled"
+        ));
+    }
+
+    #[test]
+    fn lock_config_remote_sources_concurrently() {
+        use std::net::TcpListener;
+        use std::sync::{Arc, Condvar, Mutex};
+        use std::{thread, time};
+
+        const COUNT: usize = 8;
+
+        // A minimal HTTP server that holds each request until all of them are
+        // in flight, or a timeout passes, and records the peak number in flight.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let in_flight = Arc::new((Mutex::new((0, 0)), Condvar::new()));
+        let server = Arc::clone(&in_flight);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).unwrap();
+                    let (lock, cvar) = &*server;
+                    let mut state = lock.lock().unwrap();
+                    state.0 += 1;
+                    state.1 = state.1.max(state.0);
+                    cvar.notify_all();
+                    let (mut state, _) = cvar
+                        .wait_timeout_while(state, time::Duration::from_secs(10), |s| s.1 < COUNT)
+                        .unwrap();
+                    state.0 -= 1;
+                    drop(state);
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\necho\n")
+                        .unwrap();
+                });
+            }
+        });
+
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let plugins = (0..COUNT)
+            .map(|i| {
+                Plugin::External(ExternalPlugin {
+                    name: format!("test{i}"),
+                    source: Source::Remote {
+                        url: Url::parse(&format!("http://{addr}/test{i}.zsh")).unwrap(),
+                        index: false,
+                    },
+                    dir: None,
+                    uses: None,
+                    apply: None,
+                    profiles: None,
+                    enabled: None,
+                    hooks: None,
+                    vars: None,
+                })
+            })
+            .collect();
+        let cfg = Config {
+            plugins,
+            ..Config::default()
+        };
+
+        let locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
+
+        assert_eq!(locked.errors.len(), 0);
+        assert_eq!(locked.plugins.len(), COUNT);
+        assert_eq!(locked.timings.sorted().len(), COUNT);
+        let peak = in_flight.0.lock().unwrap().1;
+        assert_eq!(peak, COUNT, "only {peak} downloads were in flight at once");
+    }
+
+    #[test]
+    fn lock_config_apply_order() {
+        type Apply = Option<&'static [&'static str]>;
+
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let dir = temp.path().join("plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.plugin.zsh"), "").unwrap();
+        let file = dir.join("test.plugin.zsh");
+        let path = format!("export PATH=\"{}:$PATH\"\n", dir.display());
+        let source = format!("source \"{}\"\n", file.display());
+
+        // (global apply, plugin apply, expected templates in order)
+        let cases: [(Apply, Apply, &[&str]); 6] = [
+            (None, None, &["source"]),
+            (Some(&["PATH", "source"]), None, &["PATH", "source"]),
+            (Some(&["source", "PATH"]), None, &["source", "PATH"]),
+            (
+                Some(&["PATH", "source"]),
+                Some(&["source", "PATH"]),
+                &["source", "PATH"],
+            ),
+            (Some(&["PATH", "source"]), Some(&["source"]), &["source"]),
+            (None, Some(&["PATH"]), &["PATH"]),
+        ];
+
+        for (global, plugin, expected) in cases {
+            let cfg = Config {
+                apply: global.map(|a| a.iter().map(|s| s.to_string()).collect()),
+                plugins: vec![Plugin::External(ExternalPlugin {
+                    name: "test".to_string(),
+                    source: Source::Local { dir: dir.clone() },
+                    dir: None,
+                    uses: None,
+                    apply: plugin.map(|a| a.iter().map(|s| s.to_string()).collect()),
+                    profiles: None,
+                    enabled: None,
+                    hooks: None,
+                    vars: None,
+                })],
+                ..Config::default()
+            };
+
+            let locked = config(&ctx, cfg, &mut Vec::new()).unwrap();
+            let script = locked.script(&ctx, &mut Vec::new()).unwrap();
+
+            let expected_script: String = expected
+                .iter()
+                .map(|name| match *name {
+                    "PATH" => path.as_str(),
+                    _ => source.as_str(),
+                })
+                .collect();
+            assert_eq!(
+                script, expected_script,
+                "global: {global:?}, plugin: {plugin:?}"
+            );
+        }
+    }
+
     #[test]
     fn locked_config_to_and_from_path() {
         let mut temp = tempfile::NamedTempFile::new().unwrap();
-        let content = r#"version = "<version>"
+        let content = r#"lockfile_version = 2
+version = "<version>"
 home = "<home>"
 config_dir = "<config>"
 data_dir = "<data>"
@@ -421,4 +1423,95 @@ plugins = []
         locked_config.to_path(&path).unwrap();
         assert_eq!(fs::read_to_string(&path).unwrap(), content);
     }
+
+    #[test]
+    fn locked_config_from_path_version_mismatch() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let content = r#"version = "<version>"
+home = "<home>"
+config_dir = "<config>"
+data_dir = "<data>"
+config_file = "<config>/plugins.toml"
+plugins = []
+
+[templates]
+"#;
+        temp.write_all(content.as_bytes()).unwrap();
+        let err = from_path(temp.into_temp_path()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "locked config has version 0 but version 2 is required, run `sheldon lock` to \
+             regenerate it"
+        );
+    }
+
+    #[test]
+    fn locked_config_verify_strict_revision() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        let dir = temp.path().join("repo");
+        fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            let output = process::Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .expect("run git");
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        let commit = [
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "--allow-empty",
+            "--message",
+            "test",
+        ];
+        git(&["init", "--quiet"]);
+        git(&commit);
+        git(&commit);
+        let file = dir.join("test.plugin.zsh");
+        fs::write(&file, "").unwrap();
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: "test".into(),
+                source_dir: dir.clone(),
+                url: None,
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: Some(git(&["rev-parse", "HEAD"])),
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![file],
+                apply: vec_into!["source"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            }))],
+            rules: Vec::new(),
+            templates: IndexMap::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Timings::default(),
+        };
+
+        ctx.strict_verify = true;
+        assert!(locked.verify(&ctx));
+
+        git(&["checkout", "--quiet", "HEAD~1"]);
+        assert!(!locked.verify(&ctx));
+        ctx.strict_verify = false;
+        assert!(locked.verify(&ctx));
+    }
 }
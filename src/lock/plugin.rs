@@ -1,11 +1,14 @@
 use std::collections::BTreeMap;
+use std::iter;
 use std::path::{Path, PathBuf};
+use std::slice;
 
 use anyhow::{bail, Context as ResultExt, Result};
+use itertools::Itertools;
 use maplit::hashmap;
 use serde::Serialize;
 
-use crate::config::{ExternalPlugin, Source};
+use crate::config::{ExternalPlugin, Rule, Source};
 use crate::context::Context;
 use crate::lock::file::LockedExternalPlugin;
 use crate::lock::source::LockedSource;
@@ -17,6 +20,7 @@ pub fn lock(
     locked_source: LockedSource,
     global_matches: &[String],
     global_apply: &[String],
+    global_rules: &[Rule],
     plugin: ExternalPlugin,
 ) -> Result<LockedExternalPlugin> {
     let ExternalPlugin {
@@ -26,36 +30,82 @@ pub fn lock(
         uses,
         apply,
         hooks,
+        vars,
         profiles: _,
+        enabled: _,
     } = plugin;
 
+    let (url, host) = match &source {
+        Source::Git { url, .. } | Source::Remote { url, .. } => {
+            (Some(url.to_string()), url.host_str().map(String::from))
+        }
+        Source::Local { .. } => (None, None),
+    };
+    let (reference, post_clone) = match &source {
+        Source::Git {
+            reference, options, ..
+        } => (reference.clone(), options.post_clone.clone()),
+        Source::Remote { .. } | Source::Local { .. } => (None, Vec::new()),
+    };
+    let apply_rules = apply.is_none() && !global_rules.is_empty();
     let apply = apply.unwrap_or_else(|| global_apply.to_vec());
     let hooks = hooks.unwrap_or(BTreeMap::new());
+    let vars = vars.unwrap_or(BTreeMap::new());
 
-    Ok(if let Source::Remote { .. } = source {
-        let LockedSource { dir, file } = locked_source;
+    // Remote sources and Local sources that are a single file are used as is.
+    Ok(if let Some(file) = locked_source.file {
         LockedExternalPlugin {
             name,
-            source_dir: dir,
+            source_dir: locked_source.dir,
+            url,
+            host,
+            default_branch: None,
+            reference,
+            mirror: None,
+            revision: None,
+            post_clone,
             plugin_dir: None,
-            files: vec![file.unwrap()],
+            files: vec![file],
             apply,
+            apply_rules,
+            file_apply: BTreeMap::new(),
             hooks,
+            vars,
         }
     } else {
         // Data to use in template rendering
         let mut data = hashmap! {
-            "data_dir" => ctx
-                .data_dir()
-                .to_str()
-                .context("data directory is not valid UTF-8")?,
-            "name" => &name
+            "data_dir" => Some(
+                ctx.data_dir()
+                    .to_str()
+                    .context("data directory is not valid UTF-8")?,
+            ),
+            "config_dir" => Some(
+                ctx.config_dir()
+                    .to_str()
+                    .context("config directory is not valid UTF-8")?,
+            ),
+            "name" => Some(name.as_str()),
+            "profile" => ctx.profile(),
         };
 
-        let source_dir = locked_source.dir;
+        let LockedSource {
+            dir: source_dir,
+            default_branch,
+            mirror,
+            revision,
+            ..
+        } = locked_source;
         let plugin_dir = if let Some(dir) = dir {
             let rendered = render_template(&dir, &data)?;
-            Some(source_dir.join(rendered))
+            let plugin_dir = source_dir.join(&rendered);
+            if plugin_dir.is_dir() {
+                Some(plugin_dir)
+            } else if is_glob(&rendered) {
+                Some(match_dir(&source_dir, &rendered, &name)?)
+            } else {
+                bail!("directory `{rendered}` does not exist in source `{name}`");
+            }
         } else {
             None
         };
@@ -63,36 +113,70 @@ pub fn lock(
         let dir_as_str = dir
             .to_str()
             .context("plugin directory is not valid UTF-8")?;
-        data.insert("dir", dir_as_str);
+        data.insert("dir", Some(dir_as_str));
 
         let mut files = Vec::new();
+        let mut file_apply = BTreeMap::new();
 
         // If the plugin defined what files to use, we do all of them.
-        if let Some(uses) = &uses {
+        let tried = if let Some(uses) = &uses {
             let patterns = uses
                 .iter()
-                .map(|u| render_template(u, &data))
+                .map(|u| render_template(&u.pattern, &data))
                 .collect::<Result<Vec<_>>>()?;
             if !match_globs(dir, &patterns, &mut files)? {
+                log_globbed(ctx, &name, &patterns, &files, dir);
                 bail!("failed to find any files matching any of `{:?}`", patterns);
             }
+            // Files matched by a glob with its own templates use those instead,
+            // the first such glob that matches a file wins.
+            let excludes = patterns.iter().filter(|p| p.starts_with('!'));
+            for (u, pattern) in uses.iter().zip(&patterns) {
+                if let Some(apply) = &u.apply {
+                    let globs: Vec<_> = iter::once(pattern)
+                        .chain(excludes.clone())
+                        .cloned()
+                        .collect();
+                    let mut matched = Vec::new();
+                    match_globs(dir, &globs, &mut matched)?;
+                    for file in matched {
+                        file_apply.entry(file).or_insert_with(|| apply.clone());
+                    }
+                }
+            }
+            patterns
         // Otherwise we try to figure out which files to use...
         } else {
+            let mut tried = Vec::new();
             for g in global_matches {
                 let pattern = render_template(g, &data)?;
-                if match_globs(dir, &[pattern], &mut files)? {
+                let matched = match_globs(dir, slice::from_ref(&pattern), &mut files)?;
+                tried.push(pattern);
+                if matched {
                     break;
                 }
             }
-        }
+            tried
+        };
+        log_globbed(ctx, &name, &tried, &files, dir);
 
         LockedExternalPlugin {
             name,
             source_dir,
+            url,
+            host,
+            default_branch,
+            reference,
+            mirror: mirror.map(String::from),
+            revision,
+            post_clone,
             plugin_dir,
             files,
             apply,
+            apply_rules,
+            file_apply,
             hooks,
+            vars,
         }
     })
 }
@@ -109,6 +193,51 @@ where
         .with_context(|| format!("failed to render template `{template}`"))
 }
 
+/// Log which glob patterns were tried for a plugin, in order, and the files
+/// that were matched by the last one.
+fn log_globbed(ctx: &Context, name: &str, patterns: &[String], files: &[PathBuf], dir: &Path) {
+    let patterns = patterns.iter().map(|p| format!("`{p}`")).join(", ");
+    if files.is_empty() {
+        ctx.log_verbose_status("Globbed", &format!("{name}: {patterns} (no matches)"));
+    } else {
+        ctx.log_verbose_status("Globbed", &format!("{name}: {patterns}"));
+        let files = files
+            .iter()
+            .map(|f| f.strip_prefix(dir).unwrap_or(f).display())
+            .join(", ");
+        ctx.log_verbose_status("Using", &format!("{name}: {files}"));
+    }
+}
+
+/// Whether the given `dir` contains any glob syntax.
+fn is_glob(dir: &str) -> bool {
+    dir.contains(['*', '?', '[', '{'])
+}
+
+/// Find the single directory in the source directory matching the given glob
+/// pattern.
+fn match_dir(source_dir: &Path, pattern: &str, name: &str) -> Result<PathBuf> {
+    let mut directories: Vec<_> =
+        globwalk::GlobWalkerBuilder::from_patterns(source_dir, &[pattern])
+            .file_type(globwalk::FileType::DIR)
+            .build()
+            .with_context(|| format!("failed to parse glob pattern `{pattern}`"))?
+            .filter_map(|result| result.ok().map(|entry| entry.into_path()))
+            .collect();
+    if directories.len() == 1 {
+        Ok(directories.remove(0))
+    } else {
+        bail!(
+            "`{pattern}` matches {} directories in source `{name}`",
+            directories.len()
+        )
+    }
+}
+
+/// Find the files in the directory matching the given glob patterns.
+///
+/// Patterns with a leading `!` exclude any files that they match from the
+/// files matched by the other patterns.
 fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Result<bool> {
     let debug = || {
         patterns
@@ -117,8 +246,23 @@ fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Res
             .collect::<Vec<_>>()
             .join(", ")
     };
+    let (excludes, includes): (Vec<_>, Vec<_>) = patterns.iter().partition(|p| p.starts_with('!'));
+    if includes.is_empty() {
+        return Ok(false);
+    }
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in excludes {
+        builder.add(
+            globset::Glob::new(&pattern[1..])
+                .with_context(|| format!("failed to parse glob pattern `{pattern}`"))?,
+        );
+    }
+    let excludes = builder
+        .build()
+        .with_context(|| format!("failed to parse glob patterns: {}", debug()))?;
+
     let mut matched = false;
-    for entry in globwalk::GlobWalkerBuilder::from_patterns(dir, patterns)
+    for entry in globwalk::GlobWalkerBuilder::from_patterns(dir, &includes)
         .sort_by(|a, b| a.file_name().cmp(b.file_name()))
         .build()
         .with_context(|| format!("failed to parse glob patterns: {}", debug()))?
@@ -131,7 +275,11 @@ fn match_globs(dir: &Path, patterns: &[String], files: &mut Vec<PathBuf>) -> Res
                 .with_context(|| format!("failed to read symlink `{}`", entry.path().display()))
                 .with_context(|| format!("failed to match patterns: {}", debug()))?;
         }
-        files.push(entry.into_path());
+        let path = entry.into_path();
+        if excludes.is_match(path.strip_prefix(dir).unwrap_or(&path)) {
+            continue;
+        }
+        files.push(path);
         matched = true;
     }
     Ok(matched)
@@ -147,9 +295,216 @@ mod tests {
 
     use url::Url;
 
-    use crate::config::GitReference;
+    use crate::config::{GitReference, Use};
     use crate::lock::source;
 
+    #[test]
+    fn match_globs_with_excludes() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        for name in ["a.zsh", "b.zsh", "a.test.zsh", "README.md"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let mut files = Vec::new();
+
+        let matched =
+            match_globs(dir, &["*.zsh".into(), "!*.test.zsh".into()], &mut files).unwrap();
+
+        assert!(matched);
+        assert_eq!(files, vec![dir.join("a.zsh"), dir.join("b.zsh")]);
+    }
+
+    #[test]
+    fn match_globs_all_excluded() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        std::fs::write(dir.join("a.test.zsh"), "").unwrap();
+        let mut files = Vec::new();
+
+        for patterns in [
+            vec!["*.zsh".into(), "!*.test.zsh".into()],
+            vec!["!*.test.zsh".into()],
+        ] {
+            assert!(!match_globs(dir, &patterns, &mut files).unwrap());
+        }
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn match_globs_recursive() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        for path in [
+            "src/a.zsh",
+            "src/lib/b.zsh",
+            "src/lib/nested/c.zsh",
+            "d.zsh",
+        ] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let mut files = Vec::new();
+
+        assert!(match_globs(dir, &["src/**/*.zsh".into()], &mut files).unwrap());
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                dir.join("src/a.zsh"),
+                dir.join("src/lib/b.zsh"),
+                dir.join("src/lib/nested/c.zsh"),
+            ]
+        );
+
+        let mut files = Vec::new();
+        let patterns = vec!["**/*.zsh".into(), "!src/lib/**".into()];
+        assert!(match_globs(dir, &patterns, &mut files).unwrap());
+        files.sort();
+        assert_eq!(files, vec![dir.join("d.zsh"), dir.join("src/a.zsh")]);
+    }
+
+    #[test]
+    fn external_plugin_lock_use_apply() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        for name in ["test.plugin.zsh", "_test", "_test.bak"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let plugin = ExternalPlugin {
+            name: "test".to_string(),
+            source: Source::Local {
+                dir: dir.to_path_buf(),
+            },
+            dir: None,
+            uses: Some(vec![
+                "{{ name }}.plugin.zsh".into(),
+                Use {
+                    pattern: "_{{ name }}*".into(),
+                    apply: Some(vec_into!["fpath"]),
+                },
+                "!*.bak".into(),
+            ]),
+            apply: None,
+            hooks: None,
+            vars: None,
+            profiles: None,
+            enabled: None,
+        };
+        let locked_source = LockedSource {
+            dir: dir.to_path_buf(),
+            file: None,
+            default_branch: None,
+            mirror: None,
+            revision: None,
+        };
+
+        let global_apply = vec_into!["source"];
+        let locked = lock(&ctx, locked_source, &[], &global_apply, &[], plugin).unwrap();
+
+        assert_eq!(
+            locked.files,
+            vec![dir.join("_test"), dir.join("test.plugin.zsh")]
+        );
+        assert_eq!(locked.apply, vec!["source"]);
+        assert_eq!(
+            locked.file_apply,
+            BTreeMap::from([(dir.join("_test"), vec_into!["fpath"])])
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_missing_dir() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let plugin = ExternalPlugin {
+            name: "test".to_string(),
+            source: Source::Local {
+                dir: dir.to_path_buf(),
+            },
+            dir: Some("{{ name }}/functions".into()),
+            uses: None,
+            apply: None,
+            hooks: None,
+            vars: None,
+            profiles: None,
+            enabled: None,
+        };
+        let locked_source = LockedSource {
+            dir: dir.to_path_buf(),
+            file: None,
+            default_branch: None,
+            mirror: None,
+            revision: None,
+        };
+
+        let err = lock(&ctx, locked_source, &[], &[], &[], plugin).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "directory `test/functions` does not exist in source `test`"
+        );
+    }
+
+    #[test]
+    fn external_plugin_lock_dir_glob() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        std::fs::create_dir_all(dir.join("versions/v1/other")).unwrap();
+        std::fs::create_dir_all(dir.join("versions/v2/plugin")).unwrap();
+        std::fs::write(dir.join("versions/v2/plugin/test.plugin.zsh"), "").unwrap();
+        let plugin = |dir: &str| ExternalPlugin {
+            name: "test".to_string(),
+            source: Source::Local {
+                dir: temp.path().to_path_buf(),
+            },
+            dir: Some(dir.into()),
+            uses: None,
+            apply: None,
+            hooks: None,
+            vars: None,
+            profiles: None,
+            enabled: None,
+        };
+        let locked_source = || LockedSource {
+            dir: dir.to_path_buf(),
+            file: None,
+            default_branch: None,
+            mirror: None,
+            revision: None,
+        };
+
+        let locked = lock(
+            &ctx,
+            locked_source(),
+            &["*.plugin.zsh".into()],
+            &[],
+            &[],
+            plugin("versions/*/plugin"),
+        )
+        .unwrap();
+        assert_eq!(locked.plugin_dir, Some(dir.join("versions/v2/plugin")));
+        assert_eq!(
+            locked.files,
+            vec![dir.join("versions/v2/plugin/test.plugin.zsh")]
+        );
+
+        let err = lock(&ctx, locked_source(), &[], &[], &[], plugin("versions/*")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`versions/*` matches 2 directories in source `test`"
+        );
+
+        let err = lock(&ctx, locked_source(), &[], &[], &[], plugin("other/*")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`other/*` matches 0 directories in source `test`"
+        );
+    }
+
     #[test]
     fn external_plugin_lock_git_with_uses() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -160,17 +515,20 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                options: Box::default(),
             },
             dir: None,
             uses: Some(vec!["*.md".into(), "{{ name }}.plugin.zsh".into()]),
             apply: None,
             hooks: None,
+            vars: None,
             profiles: None,
+            enabled: None,
         };
         let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
         let clone_dir = dir.join("repos/github.com/rossmacarthur/sheldon-test");
 
-        let locked = lock(&ctx, locked_source, &[], &["hello".into()], plugin).unwrap();
+        let locked = lock(&ctx, locked_source, &[], &["hello".into()], &[], plugin).unwrap();
 
         assert_eq!(locked.name, String::from("test"));
         assert_eq!(locked.dir(), clone_dir);
@@ -194,12 +552,15 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             hooks: None,
+            vars: None,
             profiles: None,
+            enabled: None,
         };
         let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
         let clone_dir = dir.join("repos/github.com/rossmacarthur/sheldon-test");
@@ -209,6 +570,7 @@ mod tests {
             locked_source,
             &["*.plugin.zsh".to_string()],
             &["hello".to_string()],
+            &[],
             plugin,
         )
         .unwrap();
@@ -229,12 +591,15 @@ mod tests {
             source: Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.1.0".to_string())),
+                options: Box::default(),
             },
             dir: None,
             uses: None,
             apply: None,
             hooks: None,
+            vars: None,
             profiles: None,
+            enabled: None,
         };
         let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
         let clone_dir = dir.join("repos/github.com/rossmacarthur/sheldon-test");
@@ -244,6 +609,7 @@ mod tests {
             locked_source,
             &["*doesnotexist*".to_string()],
             &["PATH".to_string()],
+            &[],
             plugin,
         )
         .unwrap();
@@ -266,17 +632,28 @@ mod tests {
                     "https://github.com/rossmacarthur/sheldon-test/raw/master/test.plugin.zsh",
                 )
                 .unwrap(),
+                index: false,
             },
             dir: None,
             uses: None,
             apply: None,
             hooks: None,
+            vars: None,
             profiles: None,
+            enabled: None,
         };
         let locked_source = source::lock(&ctx, plugin.source.clone()).unwrap();
         let download_dir = dir.join("downloads/github.com/rossmacarthur/sheldon-test/raw/master");
 
-        let locked = lock(&ctx, locked_source, &[], &["hello".to_string()], plugin).unwrap();
+        let locked = lock(
+            &ctx,
+            locked_source,
+            &[],
+            &["hello".to_string()],
+            &[],
+            plugin,
+        )
+        .unwrap();
 
         assert_eq!(locked.name, String::from("test"));
         assert_eq!(locked.dir(), download_dir);
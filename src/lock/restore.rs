@@ -0,0 +1,256 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context as ResultExt, Error, Result};
+use indexmap::IndexSet;
+use url::Url;
+
+use crate::context::Context;
+use crate::lock::file::{LockedExternalPlugin, LockedPlugin};
+use crate::lock::{source, InstallError, LockedConfig};
+
+impl LockedConfig {
+    /// Reinstall each source exactly as it was recorded in the lock file.
+    ///
+    /// Git sources are cloned and checked out at the locked revision and remote
+    /// sources are downloaded to the locked file, or directory for a remote
+    /// index. Local sources are left alone.
+    /// Returns the errors that occurred for each source.
+    pub fn restore(&self, ctx: &Context) -> Vec<Error> {
+        let mut seen = IndexSet::new();
+        let mut errors = Vec::new();
+        for plugin in &self.plugins {
+            let LockedPlugin::External(plugin) = plugin else {
+                continue;
+            };
+            if !seen.insert(plugin.source_dir.as_path()) {
+                continue;
+            }
+            // Local sources have no URL.
+            let Some(url) = &plugin.url else {
+                ctx.log_verbose_status("Skipped", plugin.source_dir.as_path());
+                continue;
+            };
+            if let Err(err) = restore_source(ctx, plugin, url) {
+                errors.push(err.context(InstallError {
+                    message: format!("failed to restore source `{url}`"),
+                    plugins: self.plugins_with_source(&plugin.source_dir),
+                }));
+            }
+        }
+        errors
+    }
+
+    /// The names of the plugins with the given source directory.
+    fn plugins_with_source(&self, source_dir: &Path) -> Vec<String> {
+        self.plugins
+            .iter()
+            .filter_map(|plugin| match plugin {
+                LockedPlugin::External(p) if p.source_dir == source_dir => Some(p.name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn restore_source(ctx: &Context, plugin: &LockedExternalPlugin, url: &str) -> Result<()> {
+    let url = parse_url(url)?;
+    if let Some(revision) = &plugin.revision {
+        let mirrors = plugin
+            .mirror
+            .as_deref()
+            .map(parse_url)
+            .transpose()?
+            .into_iter()
+            .collect::<Vec<_>>();
+        source::restore_git(
+            ctx,
+            &plugin.source_dir,
+            &url,
+            &mirrors,
+            revision,
+            &plugin.post_clone,
+        )
+    } else if plugin.source_dir.starts_with(ctx.download_dir()) {
+        let (dir, index) = source::remote_index_dir_and_file(ctx, &url)?;
+        if plugin.source_dir == dir {
+            return source::restore_remote_index(ctx, dir, index, &url);
+        }
+        let file = match plugin.files.as_slice() {
+            [file] => file.clone(),
+            _ => bail!("expected a single downloaded file"),
+        };
+        source::restore_remote(ctx, plugin.source_dir.clone(), file, &url)
+    } else {
+        Err(anyhow!(
+            "no revision is recorded, run `sheldon lock` to regenerate the lock file"
+        ))
+    }
+}
+
+fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).with_context(|| format!("failed to parse URL `{url}`"))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use crate::lock::file::LOCKFILE_VERSION;
+
+    fn git_commit(dir: &Path, contents: &str) -> git2::Oid {
+        let repo = git2::Repository::open(dir)
+            .or_else(|_| git2::Repository::init(dir))
+            .unwrap();
+        fs::write(dir.join("test.plugin.zsh"), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("test.plugin.zsh")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parents = match repo.head().ok().and_then(|h| h.peel_to_commit().ok()) {
+            Some(parent) => vec![parent],
+            None => vec![],
+        };
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, contents, &tree, &parents)
+            .unwrap()
+    }
+
+    fn locked_config(ctx: &Context, plugin: LockedExternalPlugin) -> LockedConfig {
+        LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(plugin))],
+            templates: crate::config::Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        }
+    }
+
+    fn external_plugin(
+        source_dir: &Path,
+        url: &Url,
+        revision: Option<String>,
+        file: &Path,
+    ) -> LockedExternalPlugin {
+        LockedExternalPlugin {
+            name: "test".into(),
+            source_dir: source_dir.to_path_buf(),
+            url: Some(url.to_string()),
+            host: None,
+            default_branch: None,
+            reference: None,
+            mirror: None,
+            revision,
+            post_clone: Vec::new(),
+            plugin_dir: None,
+            files: vec![file.to_path_buf()],
+            apply: vec_into!["source"],
+            apply_rules: false,
+            file_apply: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            vars: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn locked_config_restore_git() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let first = git_commit(&upstream, "echo 'first'\n");
+        git_commit(&upstream, "echo 'second'\n");
+
+        let url = Url::from_directory_path(&upstream).unwrap();
+        let source_dir = ctx.clone_dir().join("localhost/upstream");
+        let file = source_dir.join("test.plugin.zsh");
+        let locked = locked_config(
+            &ctx,
+            external_plugin(&source_dir, &url, Some(first.to_string()), &file),
+        );
+
+        let errors = locked.restore(&ctx);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'first'\n");
+        assert!(source::is_at_revision(&source_dir, &first.to_string()));
+
+        // Restoring again when the working tree was changed checks out the
+        // locked revision again.
+        let repo = git2::Repository::open(&source_dir).unwrap();
+        let head = repo.revparse_single("origin/HEAD").unwrap();
+        repo.reset(&head, git2::ResetType::Hard, None).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'second'\n");
+        let errors = locked.restore(&ctx);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "echo 'first'\n");
+    }
+
+    #[test]
+    fn locked_config_restore_git_runs_post_clone() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let first = git_commit(&upstream, "echo 'first'\n");
+        git_commit(&upstream, "echo 'second'\n");
+
+        let url = Url::from_directory_path(&upstream).unwrap();
+        let source_dir = ctx.clone_dir().join("localhost/upstream");
+        let file = source_dir.join("test.plugin.zsh");
+        let built = source_dir.join("built");
+        let mut plugin = external_plugin(&source_dir, &url, Some(first.to_string()), &file);
+        plugin.post_clone = vec_into!["echo built > built"];
+        let locked = locked_config(&ctx, plugin);
+
+        // Run after cloning.
+        let errors = locked.restore(&ctx);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(built.exists());
+
+        // Not run when the repository is already at the locked revision.
+        fs::remove_file(&built).unwrap();
+        let errors = locked.restore(&ctx);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(!built.exists());
+
+        // Run after checking out the locked revision again.
+        let repo = git2::Repository::open(&source_dir).unwrap();
+        let head = repo.revparse_single("origin/HEAD").unwrap();
+        repo.reset(&head, git2::ResetType::Hard, None).unwrap();
+        let errors = locked.restore(&ctx);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(built.exists());
+    }
+
+    #[test]
+    fn locked_config_restore_without_revision() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let url = Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap();
+        let source_dir = ctx
+            .clone_dir()
+            .join("github.com/rossmacarthur/sheldon-test");
+        let file = source_dir.join("test.plugin.zsh");
+        let locked = locked_config(&ctx, external_plugin(&source_dir, &url, None, &file));
+
+        let errors = locked.restore(&ctx);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            format!("{:#}", errors[0]),
+            "failed to restore source `https://github.com/rossmacarthur/sheldon-test`: no \
+             revision is recorded, run `sheldon lock` to regenerate the lock file"
+        );
+    }
+}
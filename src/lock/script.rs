@@ -1,24 +1,79 @@
-use anyhow::{Context as ResultExt, Error, Result};
+use anyhow::{bail, Context as ResultExt, Error, Result};
+use globset::GlobMatcher;
 use once_cell::sync::Lazy;
 use serde::Serialize;
 use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::Mutex;
+use std::time::Instant;
+use yansi::Paint;
 
 use crate::context::Context;
-use crate::lock::file::LockedPlugin;
+use crate::lock::file::{LockedExternalPlugin, LockedPlugin};
 use crate::lock::LockedConfig;
 
 #[derive(Debug, Serialize)]
 struct ExternalData<'a> {
     name: &'a str,
+    data_dir: &'a str,
+    config_dir: &'a str,
+    profile: Option<&'a str>,
     dir: &'a str,
     files: Vec<&'a str>,
     hooks: &'a BTreeMap<String, String>,
+    vars: &'a BTreeMap<String, String>,
+}
+
+/// How to annotate the generated script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Annotate {
+    /// Add a plain comment before each plugin.
+    Plain,
+    /// Add a colored comment before each plugin.
+    Colored,
 }
 
 impl LockedConfig {
     /// Generate the script.
     pub fn script(&self, ctx: &Context, warnings: &mut Vec<Error>) -> Result<String> {
+        self.render(ctx, None, None, warnings)
+    }
+
+    /// Generate the script with a comment before each plugin.
+    pub fn annotated_script(
+        &self,
+        ctx: &Context,
+        annotate: Annotate,
+        warnings: &mut Vec<Error>,
+    ) -> Result<String> {
+        self.render(ctx, Some(annotate), None, warnings)
+    }
+
+    /// Generate the script for only the plugins with a name matching the given
+    /// glob pattern.
+    pub fn filtered_script(
+        &self,
+        ctx: &Context,
+        pattern: &str,
+        annotate: Option<Annotate>,
+        warnings: &mut Vec<Error>,
+    ) -> Result<String> {
+        let matcher = globset::Glob::new(pattern)
+            .with_context(|| format!("failed to parse glob `{pattern}`"))?
+            .compile_matcher();
+        if !self.plugins.iter().any(|p| matcher.is_match(p.name())) {
+            bail!("no plugins match `{pattern}`");
+        }
+        self.render(ctx, annotate, Some(&matcher), warnings)
+    }
+
+    fn render(
+        &self,
+        ctx: &Context,
+        annotate: Option<Annotate>,
+        filter: Option<&GlobMatcher>,
+        warnings: &mut Vec<Error>,
+    ) -> Result<String> {
         static USED_GET: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
 
         let mut engine = upon::Engine::new();
@@ -42,40 +97,80 @@ impl LockedConfig {
         // Compile the templates
         for (name, template) in &self.templates {
             engine
-                .add_template(name, template)
+                .add_template(name, &template.value)
                 .with_context(|| format!("failed to compile template `{name}`"))?;
         }
 
+        // Compile the rules
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for rule in &self.rules {
+            let matcher = globset::Glob::new(&rule.pattern)
+                .with_context(|| format!("failed to parse rule glob `{}`", rule.pattern))?
+                .compile_matcher();
+            rules.push((matcher, &rule.apply));
+        }
+
+        let data_dir = ctx
+            .data_dir()
+            .to_str()
+            .context("data directory is not valid UTF-8")?;
+        let config_dir = ctx
+            .config_dir()
+            .to_str()
+            .context("config directory is not valid UTF-8")?;
+
         let mut script = String::new();
 
-        for plugin in &self.plugins {
+        if let Some(pre) = self.hooks.get("pre") {
+            push_line(&mut script, pre);
+        }
+
+        let plugins = self
+            .plugins
+            .iter()
+            .filter(|p| filter.map_or(true, |m| m.is_match(p.name())));
+
+        for plugin in plugins {
+            if let Some(annotate) = annotate {
+                let comment = format!("# plugin: {}", plugin.name());
+                let comment = match annotate {
+                    Annotate::Plain => comment,
+                    Annotate::Colored => Paint::new(comment).dimmed().to_string(),
+                };
+                push_line(&mut script, &comment);
+            }
+            let now = Instant::now();
             match plugin {
                 LockedPlugin::External(plugin) => {
-                    // Data to use in template rendering
-                    let mut files = Vec::new();
-                    for f in &plugin.files {
-                        files.push(f.to_str().context("plugin directory is not valid UTF-8")?);
-                    }
-                    let data = ExternalData {
-                        name: &plugin.name,
-                        dir: plugin
-                            .dir()
-                            .to_str()
-                            .context("plugin directory is not valid UTF-8")?,
-                        files,
-                        hooks: &plugin.hooks,
-                    };
+                    for (apply, files) in group_files(plugin, &rules)? {
+                        // Data to use in template rendering
+                        let data = ExternalData {
+                            name: &plugin.name,
+                            data_dir,
+                            config_dir,
+                            profile: ctx.profile(),
+                            dir: plugin
+                                .dir()
+                                .to_str()
+                                .context("plugin directory is not valid UTF-8")?,
+                            files,
+                            hooks: &plugin.hooks,
+                            vars: &plugin.vars,
+                        };
 
-                    for name in &plugin.apply {
-                        let out = &engine
-                            .get_template(name)
-                            .unwrap()
-                            .render(&data)
-                            .to_string()
-                            .with_context(|| format!("failed to render template `{name}`"))?;
-                        script.push_str(out);
-                        if !out.ends_with('\n') {
-                            script.push('\n');
+                        for name in apply {
+                            if let Some(when_host) = &self.templates[name].when_host {
+                                if plugin.host.as_ref() != Some(when_host) {
+                                    continue;
+                                }
+                            }
+                            let out = &engine
+                                .get_template(name)
+                                .unwrap()
+                                .render(&data)
+                                .to_string()
+                                .with_context(|| format!("failed to render template `{name}`"))?;
+                            push_line(&mut script, out);
                         }
                     }
                     ctx.log_verbose_status("Rendered", &plugin.name);
@@ -84,6 +179,9 @@ impl LockedConfig {
                     // Data to use in template rendering
                     let data = upon::value! {
                         name: &plugin.name,
+                        data_dir: data_dir,
+                        config_dir: config_dir,
+                        profile: ctx.profile(),
                         hooks: &plugin.hooks,
                     };
                     let out = engine
@@ -96,13 +194,34 @@ impl LockedConfig {
                         .with_context(|| {
                             format!("failed to render inline plugin `{}`", &plugin.name)
                         })?;
-                    script.push_str(&out);
-                    if !out.ends_with('\n') {
-                        script.push('\n');
-                    }
+                    let out = match &plugin.apply {
+                        Some(name) => {
+                            let data = upon::value! {
+                                name: &plugin.name,
+                                data_dir: data_dir,
+                                config_dir: config_dir,
+                                profile: ctx.profile(),
+                                raw: &out,
+                                hooks: &plugin.hooks,
+                            };
+                            engine
+                                .get_template(name)
+                                .unwrap()
+                                .render(&data)
+                                .to_string()
+                                .with_context(|| format!("failed to render template `{name}`"))?
+                        }
+                        None => out,
+                    };
+                    push_line(&mut script, &out);
                     ctx.log_verbose_status("Inlined", &plugin.name);
                 }
             }
+            self.timings.record(plugin.name(), "render", now.elapsed());
+        }
+
+        if let Some(post) = self.hooks.get("post") {
+            push_line(&mut script, post);
         }
 
         if *USED_GET.lock().unwrap() {
@@ -116,3 +235,579 @@ impl LockedConfig {
         Ok(script)
     }
 }
+
+/// Group the plugin's files by the templates that should be applied to them.
+///
+/// Files matched by a `use` glob with its own templates always use those.
+/// Unless the plugin uses the global rules all other files are in a single
+/// group with the plugin's templates. Otherwise each file uses the templates of
+/// the first rule that matches it, falling back to the plugin's templates.
+/// Groups are ordered by their first file.
+fn group_files<'a>(
+    plugin: &'a LockedExternalPlugin,
+    rules: &[(GlobMatcher, &'a Vec<String>)],
+) -> Result<Vec<(&'a Vec<String>, Vec<&'a str>)>> {
+    let mut groups: Vec<(&Vec<String>, Vec<&str>)> = Vec::new();
+    for f in &plugin.files {
+        let file = f.to_str().context("plugin directory is not valid UTF-8")?;
+        let apply = if let Some(apply) = plugin.file_apply.get(f) {
+            apply
+        } else if plugin.apply_rules {
+            let path = f.strip_prefix(plugin.dir()).unwrap_or(f);
+            rules
+                .iter()
+                .find(|(matcher, _)| rule_matches(matcher, path))
+                .map_or(&plugin.apply, |(_, apply)| apply)
+        } else {
+            &plugin.apply
+        };
+        match groups.iter_mut().find(|(a, _)| *a == apply) {
+            Some((_, files)) => files.push(file),
+            None => groups.push((apply, vec![file])),
+        }
+    }
+    if groups.is_empty() {
+        groups.push((&plugin.apply, Vec::new()));
+    }
+    Ok(groups)
+}
+
+/// Whether the rule matches the given path relative to the plugin directory.
+fn rule_matches(matcher: &GlobMatcher, path: &Path) -> bool {
+    if matcher.glob().glob().contains('/') {
+        matcher.is_match(path)
+    } else {
+        path.file_name().is_some_and(|name| matcher.is_match(name))
+    }
+}
+
+/// Append the output to the script, making sure it ends with a newline.
+fn push_line(script: &mut String, out: &str) {
+    script.push_str(out);
+    if !out.ends_with('\n') {
+        script.push('\n');
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::config::{InlinePlugin, Rule, Shell, Template};
+    use crate::lock::file::{LockedExternalPlugin, LOCKFILE_VERSION};
+
+    fn plugin(name: &str, host: &str) -> LockedPlugin {
+        let source_dir = PathBuf::from("/plugins").join(name);
+        LockedPlugin::External(Box::new(LockedExternalPlugin {
+            name: name.into(),
+            host: Some(host.into()),
+            default_branch: None,
+            reference: None,
+            mirror: None,
+            revision: None,
+            post_clone: Vec::new(),
+            plugin_dir: None,
+            files: vec![source_dir.join(format!("{name}.plugin.zsh"))],
+            source_dir,
+            url: None,
+            apply: vec_into!["gist", "source"],
+            apply_rules: false,
+            file_apply: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            vars: BTreeMap::new(),
+        }))
+    }
+
+    #[test]
+    fn locked_config_script_when_host() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut templates = Shell::Zsh.default_templates().clone();
+        templates.insert(
+            "gist".into(),
+            Template {
+                value: "echo 'gist {{ name }}'".into(),
+                when_host: Some("gist.github.com".into()),
+            },
+        );
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![
+                plugin("snippet", "gist.github.com"),
+                plugin("repo", "github.com"),
+            ],
+            templates,
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "echo 'gist snippet'
+source \"/plugins/snippet/snippet.plugin.zsh\"
+source \"/plugins/repo/repo.plugin.zsh\"
+"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_vars() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut templates = Shell::Zsh.default_templates().clone();
+        templates.insert("theme".into(), "load-theme {{ vars?.theme }}".into());
+        let source_dir = PathBuf::from("/plugins/test");
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: "test".into(),
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: Vec::new(),
+                source_dir,
+                url: None,
+                apply: vec_into!["theme"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::from([("theme".into(), "dark".into())]),
+            }))],
+            rules: Vec::new(),
+            templates,
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "load-theme dark\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_theme() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let source_dir = PathBuf::from("/plugins/test");
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: "test".into(),
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![source_dir.join("test.zsh-theme")],
+                source_dir,
+                url: None,
+                apply: vec_into!["theme"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            }))],
+            rules: Vec::new(),
+            templates: Shell::Zsh.default_templates().clone(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "setopt prompt_subst\nsource \"/plugins/test/test.zsh-theme\"\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_include() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let source_dir = PathBuf::from("/plugins/test");
+        let mut templates = Shell::Zsh.default_templates().clone();
+        templates.insert(
+            "interactive".into(),
+            Template {
+                value: "if [[ -o interactive ]]; then\n{% include \"source\" %}fi".into(),
+                when_host: None,
+            },
+        );
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: "test".into(),
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![source_dir.join("test.plugin.zsh")],
+                source_dir,
+                url: None,
+                apply: vec_into!["interactive"],
+                apply_rules: false,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::from([("pre".into(), "echo pre".into())]),
+                vars: BTreeMap::new(),
+            }))],
+            rules: Vec::new(),
+            templates,
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "if [[ -o interactive ]]; then\necho pre\nsource \"/plugins/test/test.plugin.zsh\"\nfi\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_rules() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let source_dir = PathBuf::from("/plugins/test");
+        let plugin = |name: &str, apply_rules| {
+            LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: name.into(),
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![
+                    source_dir.join("_test"),
+                    source_dir.join("test.plugin.zsh"),
+                    source_dir.join("functions/_other"),
+                    source_dir.join("lib/util.zsh"),
+                ],
+                source_dir: source_dir.clone(),
+                url: None,
+                apply: vec_into!["source"],
+                apply_rules,
+                file_apply: BTreeMap::new(),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            }))
+        };
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![plugin("ruled", true), plugin("explicit", false)],
+            rules: vec![
+                Rule {
+                    pattern: "_*".into(),
+                    apply: vec_into!["fpath"],
+                },
+                Rule {
+                    pattern: "lib/*.zsh".into(),
+                    apply: vec_into!["PATH", "source"],
+                },
+            ],
+            templates: Shell::Zsh.default_templates().clone(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            r#"fpath=( "/plugins/test" $fpath )
+source "/plugins/test/test.plugin.zsh"
+export PATH="/plugins/test:$PATH"
+source "/plugins/test/lib/util.zsh"
+source "/plugins/test/_test"
+source "/plugins/test/test.plugin.zsh"
+source "/plugins/test/functions/_other"
+source "/plugins/test/lib/util.zsh"
+"#
+        );
+    }
+
+    #[test]
+    fn locked_config_script_file_apply() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let source_dir = PathBuf::from("/plugins/test");
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::External(Box::new(LockedExternalPlugin {
+                name: "test".into(),
+                host: None,
+                default_branch: None,
+                reference: None,
+                mirror: None,
+                revision: None,
+                post_clone: Vec::new(),
+                plugin_dir: None,
+                files: vec![
+                    source_dir.join("_test"),
+                    source_dir.join("completions/_other"),
+                    source_dir.join("test.plugin.zsh"),
+                ],
+                source_dir: source_dir.clone(),
+                url: None,
+                apply: vec_into!["source"],
+                apply_rules: true,
+                file_apply: BTreeMap::from([(
+                    source_dir.join("completions/_other"),
+                    vec_into!["fpath"],
+                )]),
+                hooks: BTreeMap::new(),
+                vars: BTreeMap::new(),
+            }))],
+            rules: vec![Rule {
+                pattern: "_*".into(),
+                apply: vec_into!["PATH"],
+            }],
+            templates: Shell::Zsh.default_templates().clone(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            r#"export PATH="/plugins/test:$PATH"
+fpath=( "/plugins/test" $fpath )
+source "/plugins/test/test.plugin.zsh"
+"#
+        );
+    }
+
+    #[test]
+    fn locked_config_script_dirs_and_profile() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mut ctx = Context::testing(temp.path());
+        ctx.data_dir = "/data".into();
+        ctx.config_dir = "/config".into();
+        let mut templates = Shell::Zsh.default_templates().clone();
+        templates.insert(
+            "dirs".into(),
+            "echo {{ name }} {{ data_dir }} {{ config_dir }} {{ profile }}".into(),
+        );
+        let source_dir = PathBuf::from("/plugins/test");
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![
+                LockedPlugin::External(Box::new(LockedExternalPlugin {
+                    name: "external".into(),
+                    host: None,
+                    default_branch: None,
+                    reference: None,
+                    mirror: None,
+                    revision: None,
+                    post_clone: Vec::new(),
+                    plugin_dir: None,
+                    files: Vec::new(),
+                    source_dir,
+                    url: None,
+                    apply: vec_into!["dirs"],
+                    apply_rules: false,
+                    file_apply: BTreeMap::new(),
+                    hooks: BTreeMap::new(),
+                    vars: BTreeMap::new(),
+                })),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "inline".into(),
+                    raw: "echo {{ name }} {{ data_dir }} {{ config_dir }} {{ profile }}".into(),
+                    apply: None,
+                    profiles: None,
+                    enabled: None,
+                    hooks: None,
+                }),
+            ],
+            templates,
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "echo external /data /config profile\necho inline /data /config profile\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_global_hooks() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::Inline(InlinePlugin {
+                name: "test".into(),
+                raw: "echo 'test'".into(),
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+            })],
+            templates: Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::from([
+                ("pre".into(), "zmodload zsh/zprof".into()),
+                ("post".into(), "zprof".into()),
+            ]),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "zmodload zsh/zprof\necho 'test'\nzprof\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_script_inline_apply() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let mut templates = Shell::Zsh.default_templates().clone();
+        templates.insert("defer".into(), Template::from("zsh-defer {{ raw }}"));
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::Inline(InlinePlugin {
+                name: "compinit".into(),
+                raw: "{{ name }} -C".into(),
+                apply: Some("defer".into()),
+                profiles: None,
+                enabled: None,
+                hooks: None,
+            })],
+            templates,
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        assert_eq!(
+            locked.script(&ctx, &mut Vec::new()).unwrap(),
+            "zsh-defer compinit -C\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_annotated_script() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![LockedPlugin::Inline(InlinePlugin {
+                name: "test".into(),
+                raw: "echo 'test'".into(),
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+            })],
+            templates: Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        let plain = locked
+            .annotated_script(&ctx, Annotate::Plain, &mut Vec::new())
+            .unwrap();
+        assert_eq!(plain, "# plugin: test\necho 'test'\n");
+
+        let colored = locked
+            .annotated_script(&ctx, Annotate::Colored, &mut Vec::new())
+            .unwrap();
+        assert!(colored.contains("\x1b["));
+        assert!(colored.ends_with("echo 'test'\n"));
+    }
+
+    #[test]
+    fn locked_config_filtered_script() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let inline = |name: &str| {
+            LockedPlugin::Inline(InlinePlugin {
+                name: name.into(),
+                raw: format!("echo '{name}'"),
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+            })
+        };
+        let locked = LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![inline("zsh-a"), inline("other"), inline("zsh-b")],
+            templates: Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        };
+
+        let script = locked
+            .filtered_script(&ctx, "zsh-*", None, &mut Vec::new())
+            .unwrap();
+        assert_eq!(script, "echo 'zsh-a'\necho 'zsh-b'\n");
+
+        let err = locked
+            .filtered_script(&ctx, "nope", None, &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no plugins match `nope`");
+    }
+}
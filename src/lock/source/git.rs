@@ -1,16 +1,20 @@
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use anyhow::{Context as ResultExt, Result};
+use anyhow::{anyhow, bail, Context as ResultExt, Result};
 use url::Url;
 
 use crate::config::GitReference;
-use crate::context::Context;
-use crate::lock::source::LockedSource;
+use crate::context::{Context, Verbosity};
+use crate::lock::source::{locks_dir, LockedSource};
 use crate::lock::LockMode;
 use crate::util::git;
 use crate::util::TempPath;
 
+/// The maximum number of commit summaries to log when a source is updated.
+const MAX_CHANGES: usize = 10;
+
 #[derive(Clone, Debug)]
 pub enum GitCheckout {
     /// Checkout the latest of the default branch (HEAD).
@@ -24,71 +28,323 @@ pub enum GitCheckout {
 }
 
 /// Clones a Git repository and checks it out at a particular revision.
-pub fn lock(ctx: &Context, dir: PathBuf, url: &Url, c: GitCheckout) -> Result<LockedSource> {
+///
+/// If the repository can't be cloned or fetched from the URL then each of the
+/// mirrors is tried in turn. The post clone commands are run whenever the
+/// repository is cloned or checked out at a different revision.
+pub fn lock(
+    ctx: &Context,
+    dir: PathBuf,
+    url: &Url,
+    mirrors: &[Url],
+    post_clone: &[String],
+    c: GitCheckout,
+) -> Result<LockedSource> {
+    // Different sources can share a directory, for example the same repository
+    // with a different reference, so only one of them may use it at a time.
+    let _guard = git::lock_dir(&locks_dir(ctx), &dir)?;
+    if ctx.offline {
+        let repo = git::open(&dir)
+            .with_context(|| format!("`{url}` is not cloned and can't be cloned offline"))?;
+        let (oid, default_branch) = checkout(ctx, &repo, url, c, post_clone)?;
+        return Ok(LockedSource {
+            dir,
+            file: None,
+            default_branch,
+            mirror: None,
+            revision: Some(oid.to_string()),
+        });
+    }
     match ctx.lock_mode() {
         LockMode::Normal => match git::open(&dir) {
             Ok(repo) => {
-                if checkout(ctx, &repo, url, c.clone()).is_err() {
-                    git::fetch(&repo)?;
-                    checkout(ctx, &repo, url, c)?;
-                }
-                Ok(LockedSource { dir, file: None })
+                // Only fetch if the reference can't be resolved or checked out
+                // with what is already in the repository. The first attempt
+                // might have moved the HEAD before failing, so the original
+                // HEAD is used for both.
+                let from = head(&repo)?;
+                let (moved, mirror) = match move_head(&repo, from, &c) {
+                    Ok(moved) => (moved, None),
+                    Err(_) => {
+                        let mirror = git::fetch(&repo, mirrors)?;
+                        (move_head(&repo, from, &c)?, mirror)
+                    }
+                };
+                let url = mirror.as_ref().unwrap_or(url);
+                let (oid, default_branch) =
+                    finish_checkout(ctx, &repo, url, &c, moved, post_clone)?;
+                Ok(LockedSource {
+                    dir,
+                    file: None,
+                    default_branch,
+                    mirror,
+                    revision: Some(oid.to_string()),
+                })
             }
-            Err(_) => install(ctx, dir, url, c),
+            Err(_) => install(ctx, dir, url, mirrors, post_clone, c),
         },
         LockMode::Update => match git::open(&dir) {
             Ok(repo) => {
-                git::fetch(&repo)?;
-                checkout(ctx, &repo, url, c)?;
-                Ok(LockedSource { dir, file: None })
+                let mirror = git::fetch(&repo, mirrors)?;
+                let url = mirror.as_ref().unwrap_or(url);
+                let (oid, default_branch) = checkout(ctx, &repo, url, c, post_clone)?;
+                Ok(LockedSource {
+                    dir,
+                    file: None,
+                    default_branch,
+                    mirror,
+                    revision: Some(oid.to_string()),
+                })
             }
-            Err(_) => install(ctx, dir, url, c),
+            Err(_) => install(ctx, dir, url, mirrors, post_clone, c),
         },
-        LockMode::Reinstall => install(ctx, dir, url, c),
+        LockMode::Reinstall => install(ctx, dir, url, mirrors, post_clone, c),
     }
 }
 
 /// Checks if a repository is correctly checked out, if not checks it out.
+///
+/// Returns the commit that is checked out, and the name of the default branch
+/// if that is what was checked out.
 fn checkout(
     ctx: &Context,
     repo: &git2::Repository,
     url: &Url,
     checkout: GitCheckout,
-) -> Result<()> {
-    let current_oid = repo.head()?.target().context("current HEAD as no target")?;
-    let expected_oid = checkout.resolve(repo)?;
-    if current_oid == expected_oid {
+    post_clone: &[String],
+) -> Result<(git2::Oid, Option<String>)> {
+    let moved = move_head(repo, head(repo)?, &checkout)?;
+    finish_checkout(ctx, repo, url, &checkout, moved, post_clone)
+}
+
+/// The result of moving the HEAD of a repository to a checkout.
+struct MovedHead {
+    /// The commit that was checked out before.
+    from: git2::Oid,
+    /// The commit that is checked out now.
+    to: git2::Oid,
+    /// The name of the default branch if that is what was checked out.
+    default_branch: Option<String>,
+}
+
+/// Returns the commit that is currently checked out.
+fn head(repo: &git2::Repository) -> Result<git2::Oid> {
+    repo.head()?.target().context("current HEAD as no target")
+}
+
+/// Resolves the checkout and checks it out if the given commit isn't it.
+fn move_head(
+    repo: &git2::Repository,
+    from: git2::Oid,
+    checkout: &GitCheckout,
+) -> Result<MovedHead> {
+    let (to, default_branch) = checkout.resolve(repo)?;
+    if from != to {
+        git::checkout(repo, to)?;
+        git::submodule_update(repo).context("failed to recursively update")?;
+    }
+    Ok(MovedHead {
+        from,
+        to,
+        default_branch,
+    })
+}
+
+/// Runs the post clone commands if the HEAD moved and logs the checkout.
+///
+/// If a command fails the repository is moved back to the previous commit so
+/// that the commands are run again the next time this is locked.
+fn finish_checkout(
+    ctx: &Context,
+    repo: &git2::Repository,
+    url: &Url,
+    checkout: &GitCheckout,
+    moved: MovedHead,
+    post_clone: &[String],
+) -> Result<(git2::Oid, Option<String>)> {
+    let MovedHead {
+        from,
+        to,
+        default_branch,
+    } = moved;
+    if from == to {
         ctx.log_status("Checked", &format!("{url}{checkout}"));
-    } else {
-        git::checkout(repo, expected_oid)?;
+        return Ok((to, default_branch));
+    }
+    if let Err(err) = run_post_clone(ctx, repo_dir(repo)?, post_clone) {
+        git::checkout(repo, from)?;
         git::submodule_update(repo).context("failed to recursively update")?;
-        ctx.log_status(
-            "Updated",
-            &format!(
-                "{}{} ({} to {})",
-                url,
-                checkout,
-                &current_oid.to_string()[..7],
-                &expected_oid.to_string()[..7]
-            ),
+        return Err(err);
+    }
+    ctx.log_status(
+        "Updated",
+        &format!(
+            "{}{} ({} to {})",
+            url,
+            checkout,
+            &from.to_string()[..7],
+            &to.to_string()[..7]
+        ),
+    );
+    if ctx.verbosity() >= Verbosity::Verbose {
+        log_changes(ctx, repo, from, to);
+    }
+    Ok((to, default_branch))
+}
+
+/// Log the summaries of the commits between the previous and new revision.
+fn log_changes(ctx: &Context, repo: &git2::Repository, from: git2::Oid, to: git2::Oid) {
+    let summaries = match git::log(repo, from, to) {
+        Ok(summaries) => summaries,
+        Err(err) => {
+            ctx.log_error_as_warning(&err);
+            return;
+        }
+    };
+    for summary in summaries.iter().take(MAX_CHANGES) {
+        ctx.log_verbose_status("", summary);
+    }
+    if summaries.len() > MAX_CHANGES {
+        ctx.log_verbose_status(
+            "",
+            &format!("... and {} more", summaries.len() - MAX_CHANGES),
         );
     }
-    Ok(())
 }
 
-fn install(ctx: &Context, dir: PathBuf, url: &Url, checkout: GitCheckout) -> Result<LockedSource> {
+/// Whether the repository in the given directory is checked out at the given
+/// reference, or at the default branch if there is none.
+pub fn is_checked_out(dir: &Path, reference: Option<GitReference>) -> bool {
+    let check = || -> Result<bool> {
+        let repo = git::open(dir)?;
+        let current_oid = repo.head()?.target().context("current HEAD as no target")?;
+        let (expected_oid, _) = GitCheckout::from(reference).resolve(&repo)?;
+        Ok(current_oid == expected_oid)
+    };
+    check().unwrap_or(false)
+}
+
+/// Whether the repository in the given directory is checked out at the given
+/// commit.
+pub fn is_at_revision(dir: &Path, revision: &str) -> bool {
+    let check = || -> Result<bool> {
+        let repo = git::open(dir)?;
+        let current_oid = repo.head()?.target().context("current HEAD as no target")?;
+        Ok(current_oid.to_string() == revision)
+    };
+    check().unwrap_or(false)
+}
+
+/// Clones a Git repository, or fetches it if the commit is missing, and checks
+/// it out at the given commit.
+///
+/// Unlike [`lock`] this doesn't resolve any reference. The post clone commands
+/// are run whenever the repository is cloned or checked out at a different
+/// commit.
+pub fn restore(
+    ctx: &Context,
+    dir: &Path,
+    url: &Url,
+    mirrors: &[Url],
+    revision: &str,
+    post_clone: &[String],
+) -> Result<()> {
+    let oid = git2::Oid::from_str(revision)
+        .with_context(|| format!("failed to parse revision `{revision}`"))?;
+    let checkout = GitCheckout::Rev(revision.to_string());
+    let _guard = git::lock_dir(&locks_dir(ctx), dir)?;
+    if let Ok(repo) = git::open(dir) {
+        let current_oid = repo.head()?.target().context("current HEAD as no target")?;
+        if current_oid == oid {
+            ctx.log_status("Checked", &format!("{url}{checkout}"));
+            return Ok(());
+        }
+        if repo.find_commit(oid).is_err() {
+            git::fetch(&repo, mirrors)?;
+        }
+        git::checkout(&repo, oid)?;
+        git::submodule_update(&repo).context("failed to recursively update")?;
+        if let Err(err) = run_post_clone(ctx, dir, post_clone) {
+            git::checkout(&repo, current_oid)?;
+            git::submodule_update(&repo).context("failed to recursively update")?;
+            return Err(err);
+        }
+        ctx.log_status("Updated", &format!("{url}{checkout}"));
+        return Ok(());
+    }
     let temp_dir =
-        TempPath::new_force(&dir).context("failed to prepare temporary clone directory")?;
+        TempPath::new_force(dir).context("failed to prepare temporary clone directory")?;
     {
-        let repo = git::clone(url, temp_dir.path())?;
-        git::checkout(&repo, checkout.resolve(&repo)?)?;
+        let (repo, _) = git::clone(url, mirrors, temp_dir.path())?;
+        git::checkout(&repo, oid)?;
         git::submodule_update(&repo).context("failed to recursively update")?;
+        run_post_clone(ctx, temp_dir.path(), post_clone)?;
     } // `repo` must be dropped before renaming the directory
+    temp_dir
+        .rename(dir)
+        .context("failed to rename temporary clone directory")?;
+    ctx.log_status("Cloned", &format!("{url}{checkout}"));
+    Ok(())
+}
+
+fn install(
+    ctx: &Context,
+    dir: PathBuf,
+    url: &Url,
+    mirrors: &[Url],
+    post_clone: &[String],
+    checkout: GitCheckout,
+) -> Result<LockedSource> {
+    let temp_dir =
+        TempPath::new_force(&dir).context("failed to prepare temporary clone directory")?;
+    let (oid, default_branch, mirror) = {
+        let (repo, mirror) = git::clone(url, mirrors, temp_dir.path())?;
+        let (oid, default_branch) = checkout.resolve(&repo)?;
+        git::checkout(&repo, oid)?;
+        git::submodule_update(&repo).context("failed to recursively update")?;
+        run_post_clone(ctx, temp_dir.path(), post_clone)?;
+        (oid, default_branch, mirror)
+    }; // `repo` must be dropped before renaming the directory
     temp_dir
         .rename(&dir)
         .context("failed to rename temporary clone directory")?;
+    let url = mirror.as_ref().unwrap_or(url);
     ctx.log_status("Cloned", &format!("{url}{checkout}"));
-    Ok(LockedSource { dir, file: None })
+    Ok(LockedSource {
+        dir,
+        file: None,
+        default_branch,
+        mirror,
+        revision: Some(oid.to_string()),
+    })
+}
+
+/// The working directory of the repository.
+fn repo_dir(repo: &git2::Repository) -> Result<&Path> {
+    repo.workdir()
+        .context("repository has no working directory")
+}
+
+/// Run each of the post clone commands in the given directory.
+fn run_post_clone(ctx: &Context, dir: &Path, commands: &[String]) -> Result<()> {
+    for command in commands {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(dir)
+            .output()
+            .with_context(|| format!("failed to run post clone command `{command}`"))?;
+        if !output.status.success() {
+            let msg = format!("post clone command `{command}` failed ({})", output.status);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.trim().is_empty() {
+                bail!(msg);
+            }
+            return Err(anyhow!(stderr.trim().to_string()).context(msg));
+        }
+        ctx.log_verbose_status("Ran", command);
+    }
+    Ok(())
 }
 
 impl fmt::Display for GitCheckout {
@@ -112,13 +368,14 @@ impl From<Option<GitReference>> for GitCheckout {
 }
 
 impl GitCheckout {
-    /// Resolve `GitCheckout` to a Git object identifier.
-    fn resolve(&self, repo: &git2::Repository) -> Result<git2::Oid> {
+    /// Resolve `GitCheckout` to a Git object identifier, along with the name
+    /// of the default branch if this is the default branch checkout.
+    fn resolve(&self, repo: &git2::Repository) -> Result<(git2::Oid, Option<String>)> {
         match self {
             Self::DefaultBranch => git::resolve_head(repo),
-            Self::Branch(s) => git::resolve_branch(repo, s),
-            Self::Rev(s) => git::resolve_rev(repo, s),
-            Self::Tag(s) => git::resolve_tag(repo, s),
+            Self::Branch(s) => Ok((git::resolve_branch(repo, s)?, None)),
+            Self::Rev(s) => Ok((git::resolve_rev(repo, s)?, None)),
+            Self::Tag(s) => Ok((git::resolve_tag(repo, s)?, None)),
         }
     }
 }
@@ -149,7 +406,7 @@ mod tests {
         let repo = git_clone_sheldon_test(&temp);
 
         let checkout = GitCheckout::Branch("feature".to_string());
-        let oid = checkout.resolve(&repo).expect("lock git checkout");
+        let (oid, _) = checkout.resolve(&repo).expect("lock git checkout");
         assert_eq!(oid.to_string(), "09ead574b20bb573ae0a53c1a5c546181cfa41c8");
 
         let checkout = GitCheckout::Branch("not-a-branch".to_string());
@@ -163,7 +420,7 @@ mod tests {
         let repo = git_clone_sheldon_test(&temp);
 
         let checkout = GitCheckout::Rev("ad149784a".to_string());
-        let oid = checkout.resolve(&repo).unwrap();
+        let (oid, _) = checkout.resolve(&repo).unwrap();
         assert_eq!(oid.to_string(), "ad149784a1538291f2477fb774eeeed4f4d29e45");
 
         let checkout = GitCheckout::Rev("2c4ed7710".to_string());
@@ -177,7 +434,7 @@ mod tests {
         let repo = git_clone_sheldon_test(&temp);
 
         let checkout = GitCheckout::Tag("v0.1.0".to_string());
-        let oid = checkout.resolve(&repo).unwrap();
+        let (oid, _) = checkout.resolve(&repo).unwrap();
         assert_eq!(oid.to_string(), "be8fde277e76f35efbe46848fb352cee68549962");
 
         let checkout = GitCheckout::Tag("v0.2.0".to_string());
@@ -196,6 +453,459 @@ mod tests {
         git2::Repository::open(dir).expect("open sheldon-test git repository")
     }
 
+    #[test]
+    fn lock_git_records_default_branch() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        for args in [
+            &["init", "--quiet", "--initial-branch", "master"][..],
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "--message",
+                "initial",
+            ],
+            &["branch", "feature"],
+        ] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .expect("run git");
+            assert!(status.success());
+        }
+        let dir = temp.path().join("clone");
+        let mut ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(&upstream).unwrap();
+
+        let locked = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert_eq!(locked.default_branch.as_deref(), Some("master"));
+        let head = git2::Repository::open(&dir)
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap();
+        assert_eq!(locked.revision, Some(head.to_string()));
+
+        ctx.lock_mode = Some(LockMode::Update);
+        let locked = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert_eq!(locked.default_branch.as_deref(), Some("master"));
+
+        let checkout = GitCheckout::Branch("feature".into());
+        let locked = lock(&ctx, dir, &url, &[], &[], checkout).unwrap();
+        assert_eq!(locked.default_branch, None);
+    }
+
+    #[test]
+    fn lock_git_falls_back_to_mirror() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        for args in [
+            &["init", "--quiet", "--initial-branch", "master"][..],
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "--message",
+                "initial",
+            ],
+        ] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .expect("run git");
+            assert!(status.success());
+        }
+        let dir = temp.path().join("clone");
+        let mut ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(temp.path().join("missing")).unwrap();
+        let mirror = Url::from_file_path(&upstream).unwrap();
+        let mirrors = [mirror.clone()];
+
+        let locked = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &mirrors,
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert_eq!(locked.mirror, Some(mirror.clone()));
+        assert_eq!(locked.default_branch.as_deref(), Some("master"));
+        let repo = git2::Repository::open(&dir).unwrap();
+        assert_eq!(
+            repo.find_remote("origin").unwrap().url(),
+            Some(url.as_str())
+        );
+
+        ctx.lock_mode = Some(LockMode::Update);
+        let locked = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &mirrors,
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert_eq!(locked.mirror, Some(mirror));
+
+        let err = lock(&ctx, dir, &url, &[], &[], GitCheckout::DefaultBranch).unwrap_err();
+        assert_eq!(err.to_string(), "failed to git fetch");
+    }
+
+    #[test]
+    fn lock_git_rev_expression() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&upstream)
+                .output()
+                .expect("run git");
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "--quiet", "--initial-branch", "master"]);
+        for message in ["first", "second", "third"] {
+            git(&["commit", "--quiet", "--allow-empty", "--message", message]);
+        }
+        git(&["tag", "v0.1.0", "HEAD~1"]);
+        git(&["checkout", "--quiet", "-b", "feature"]);
+        git(&["commit", "--quiet", "--allow-empty", "--message", "fourth"]);
+        git(&["checkout", "--quiet", "master"]);
+        let dir = temp.path().join("clone");
+        let ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(&upstream).unwrap();
+
+        for (rev, expected) in [
+            ("HEAD~1", "master~1"),
+            ("feature^", "master"),
+            ("v0.1.0~1", "master~2"),
+        ] {
+            let checkout = GitCheckout::Rev(rev.into());
+            lock(&ctx, dir.clone(), &url, &[], &[], checkout.clone()).unwrap();
+            // Locking again must not move relative to the new checkout.
+            lock(&ctx, dir.clone(), &url, &[], &[], checkout).unwrap();
+            let repo = git2::Repository::open(&dir).unwrap();
+            assert_eq!(
+                repo.head().unwrap().target().unwrap().to_string(),
+                git(&["rev-parse", expected]),
+                "rev: {rev}"
+            );
+        }
+
+        let checkout = GitCheckout::Rev("HEAD~5".into());
+        let err = lock(&ctx, dir, &url, &[], &[], checkout).unwrap_err();
+        assert_eq!(err.to_string(), "failed to find revision `HEAD~5`");
+    }
+
+    #[test]
+    fn lock_git_post_clone() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .expect("run git");
+            assert!(status.success());
+        };
+        git(&["init", "--quiet", "--initial-branch", "master"]);
+        git(&["commit", "--quiet", "--allow-empty", "--message", "first"]);
+        let dir = temp.path().join("clone");
+        let built = dir.join("built");
+        let mut ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(&upstream).unwrap();
+        let post_clone = ["echo built > built".to_string()];
+
+        // Run after cloning.
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &post_clone,
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert!(built.exists());
+
+        // Not run when the repository is already checked out.
+        fs::remove_file(&built).unwrap();
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &post_clone,
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert!(!built.exists());
+
+        // Run after updating to a new revision.
+        git(&["commit", "--quiet", "--allow-empty", "--message", "second"]);
+        ctx.lock_mode = Some(LockMode::Update);
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &post_clone,
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert!(built.exists());
+
+        // A failure rolls back the update so that it is retried.
+        git(&["commit", "--quiet", "--allow-empty", "--message", "third"]);
+        let failing = ["echo oops >&2; exit 3".to_string()];
+        let repo = git2::Repository::open(&dir).unwrap();
+        let before = repo.head().unwrap().target().unwrap();
+        let err = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &failing,
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "post clone command `echo oops >&2; exit 3` failed (exit status: 3): oops"
+        );
+        assert_eq!(repo.head().unwrap().target().unwrap(), before);
+
+        // A failure after cloning leaves nothing behind.
+        let dir = temp.path().join("other");
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &failing,
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap_err();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn lock_git_post_clone_failure_is_not_retried() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&upstream)
+                .output()
+                .expect("run git");
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "--quiet", "--initial-branch", "master"]);
+        git(&["commit", "--quiet", "--allow-empty", "--message", "first"]);
+        let first = git(&["rev-parse", "HEAD"]);
+        git(&["commit", "--quiet", "--allow-empty", "--message", "second"]);
+        let dir = temp.path().join("clone");
+        let ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(&upstream).unwrap();
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+
+        let ran = temp.path().join("ran");
+        let failing = [format!("echo ran >> '{}'; exit 3", ran.display())];
+        let err = lock(&ctx, dir, &url, &[], &failing, GitCheckout::Rev(first)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "post clone command `{}` failed (exit status: 3)",
+                failing[0]
+            )
+        );
+        assert_eq!(fs::read_to_string(&ran).unwrap(), "ran\n");
+    }
+
+    #[test]
+    fn lock_git_offline() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        for args in [
+            &["init", "--quiet", "--initial-branch", "master"][..],
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "--message",
+                "initial",
+            ],
+        ] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .expect("run git");
+            assert!(status.success());
+        }
+        let dir = temp.path().join("clone");
+        let mut ctx = Context::testing(temp.path());
+        ctx.offline = true;
+        let url = Url::from_file_path(&upstream).unwrap();
+
+        let err = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("`{url}` is not cloned and can't be cloned offline")
+        );
+        assert!(!dir.exists());
+
+        ctx.offline = false;
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        fs::remove_dir_all(&upstream).unwrap();
+
+        ctx.offline = true;
+        let locked = lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert_eq!(locked.dir, dir);
+        assert_eq!(locked.default_branch.as_deref(), Some("master"));
+    }
+
+    #[test]
+    fn is_checked_out_detects_drift() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let commit = [
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "--allow-empty",
+            "--message",
+            "test",
+        ];
+        for args in [
+            &["init", "--quiet", "--initial-branch", "master"][..],
+            &commit,
+            &["tag", "v0.1.0"],
+            &commit,
+        ] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .expect("run git");
+            assert!(status.success());
+        }
+        let dir = temp.path().join("clone");
+        let ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(&upstream).unwrap();
+        let tag = Some(GitReference::Tag("v0.1.0".into()));
+
+        lock(
+            &ctx,
+            dir.clone(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
+        assert!(is_checked_out(&dir, None));
+        assert!(!is_checked_out(&dir, tag.clone()));
+        let revision = git2::Repository::open(&dir)
+            .unwrap()
+            .revparse_single("v0.1.0")
+            .unwrap()
+            .id()
+            .to_string();
+        assert!(!is_at_revision(&dir, &revision));
+
+        lock(&ctx, dir.clone(), &url, &[], &[], tag.clone().into()).unwrap();
+        assert!(is_checked_out(&dir, tag));
+        assert!(!is_checked_out(&dir, None));
+        assert!(is_at_revision(&dir, &revision));
+
+        assert!(!is_checked_out(&temp.path().join("missing"), None));
+    }
+
     #[test]
     fn lock_git_and_reinstall() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -203,7 +913,15 @@ mod tests {
         let mut ctx = Context::testing(dir);
         let url = Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap();
 
-        let locked = lock(&ctx, dir.to_path_buf(), &url, GitCheckout::DefaultBranch).unwrap();
+        let locked = lock(
+            &ctx,
+            dir.to_path_buf(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
 
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, None);
@@ -216,7 +934,15 @@ mod tests {
         let modified = fs::metadata(dir).unwrap().modified().unwrap();
         thread::sleep(time::Duration::from_secs(1));
         ctx.lock_mode = Some(LockMode::Reinstall);
-        let locked = lock(&ctx, dir.to_path_buf(), &url, GitCheckout::DefaultBranch).unwrap();
+        let locked = lock(
+            &ctx,
+            dir.to_path_buf(),
+            &url,
+            &[],
+            &[],
+            GitCheckout::DefaultBranch,
+        )
+        .unwrap();
         assert_eq!(locked.dir, dir);
         assert_eq!(locked.file, None);
         let repo = git2::Repository::open(dir).unwrap();
@@ -236,6 +962,8 @@ mod tests {
             &Context::testing(dir),
             dir.to_path_buf(),
             &Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
+            &[],
+            &[],
             GitCheckout::Rev("ad149784a1538291f2477fb774eeeed4f4d29e45".to_string()),
         )
         .unwrap();
@@ -260,6 +988,8 @@ mod tests {
             &Context::testing(dir),
             dir.to_path_buf(),
             &Url::parse("git://github.com/rossmacarthur/sheldon-test").unwrap(),
+            &[],
+            &[],
             GitCheckout::Rev("ad149784a1538291f2477fb774eeeed4f4d29e45".to_string()),
         )
         .unwrap();
@@ -273,4 +1003,117 @@ mod tests {
             "ad149784a1538291f2477fb774eeeed4f4d29e45"
         );
     }
+
+    #[test]
+    fn lock_git_shared_dir_concurrently() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let origin = temp.path().join("origin");
+        let repo = git2::Repository::init(&origin).unwrap();
+        fs::write(origin.join("test.plugin.zsh"), "echo 'testing...'\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("test.plugin.zsh")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let oid = repo
+            .commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+            .unwrap();
+        let commit = repo.find_object(oid, None).unwrap();
+        repo.tag_lightweight("v1", &commit, false).unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+
+        let ctx = Context::testing(temp.path());
+        let dir = temp.path().join("clone");
+        let url = Url::parse(&format!("file://{}", origin.display())).unwrap();
+        let checkouts = [
+            GitCheckout::DefaultBranch,
+            GitCheckout::Branch(branch),
+            GitCheckout::Tag("v1".to_string()),
+            GitCheckout::Rev(oid.to_string()),
+        ];
+
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..32)
+                .map(|i| {
+                    let c = checkouts[i % checkouts.len()].clone();
+                    let (ctx, dir, url) = (&ctx, &dir, &url);
+                    s.spawn(move || lock(ctx, dir.clone(), url, &[], &[], c))
+                })
+                .collect();
+            for handle in handles {
+                let locked = handle.join().unwrap().unwrap();
+                assert_eq!(locked.dir, dir);
+                assert_eq!(locked.revision, Some(oid.to_string()));
+            }
+        });
+        assert!(dir.join("test.plugin.zsh").exists());
+    }
+
+    #[test]
+    fn lock_git_then_clean_removes_unused_lock_files() {
+        use crate::config::{Config, ExternalPlugin, Plugin, Source};
+
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let upstream = temp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&upstream)
+                .status()
+                .expect("run git");
+            assert!(status.success());
+        };
+        git(&["init", "--quiet", "--initial-branch", "master"]);
+        git(&["commit", "--quiet", "--allow-empty", "--message", "first"]);
+        let ctx = Context::testing(temp.path());
+        let url = Url::from_file_path(&upstream).unwrap();
+        let kept_url = Url::parse("https://github.com/rossmacarthur/kept").unwrap();
+        let kept = crate::lock::source::git_dir(&ctx, &kept_url).unwrap();
+        let removed = ctx.clone_dir().join("localhost/removed");
+        for dir in [&kept, &removed] {
+            lock(
+                &ctx,
+                dir.clone(),
+                &url,
+                &[],
+                &[],
+                GitCheckout::DefaultBranch,
+            )
+            .unwrap();
+        }
+        let kept_lock = git::lock_file(&locks_dir(&ctx), &kept).unwrap();
+        let removed_lock = git::lock_file(&locks_dir(&ctx), &removed).unwrap();
+        assert!(kept_lock.exists());
+        assert!(removed_lock.exists());
+
+        let cfg = Config {
+            plugins: vec![Plugin::External(ExternalPlugin {
+                name: "kept".into(),
+                source: Source::Git {
+                    url: kept_url,
+                    reference: None,
+                    options: Box::default(),
+                },
+                dir: None,
+                uses: None,
+                apply: None,
+                profiles: None,
+                enabled: None,
+                hooks: None,
+                vars: None,
+            })],
+            ..Config::default()
+        };
+        let mut warnings = Vec::new();
+        crate::config::clean(&ctx, &mut warnings, &cfg).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert!(kept.exists());
+        assert!(!removed.exists());
+        let lock_files: Vec<_> = fs::read_dir(locks_dir(&ctx))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(lock_files, [kept_lock]);
+    }
 }
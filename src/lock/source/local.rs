@@ -1,17 +1,48 @@
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as ResultExt, Result};
 
 use crate::context::Context;
 use crate::lock::source::LockedSource;
+use crate::lock::LockMode;
+use crate::util;
+use crate::util::archive;
+use crate::util::{PathExt, TempPath};
 
 /// Checks that a Local source directory exists.
+///
+/// If the source is an archive file then it is extracted and the extracted
+/// directory is used instead. If the source is any other file then that file
+/// is used as the plugin's only file.
 pub fn lock(ctx: &Context, dir: PathBuf) -> Result<LockedSource> {
-    let dir = ctx.expand_tilde(dir);
+    let dir = expand(ctx, &dir)?;
 
-    if dir.exists() && dir.is_dir() {
+    if dir.is_file() && archive::is_archive(&dir) {
+        lock_archive(ctx, dir)
+    } else if dir.is_file() {
         ctx.log_status("Checked", dir.as_path());
-        Ok(LockedSource { dir, file: None })
+        let parent = dir
+            .parent()
+            .with_context(|| format!("`{}` has no parent directory", dir.display()))?
+            .to_path_buf();
+        Ok(LockedSource {
+            dir: parent,
+            file: Some(dir),
+            default_branch: None,
+            mirror: None,
+            revision: None,
+        })
+    } else if dir.exists() && dir.is_dir() {
+        ctx.log_status("Checked", dir.as_path());
+        Ok(LockedSource {
+            dir,
+            file: None,
+            default_branch: None,
+            mirror: None,
+            revision: None,
+        })
     } else if let Ok(walker) = globwalk::glob(dir.to_string_lossy()) {
         let mut directories: Vec<_> = walker
             .filter_map(|result| match result {
@@ -23,7 +54,13 @@ pub fn lock(ctx: &Context, dir: PathBuf) -> Result<LockedSource> {
         if directories.len() == 1 {
             let dir = directories.remove(0);
             ctx.log_status("Checked", dir.as_path());
-            Ok(LockedSource { dir, file: None })
+            Ok(LockedSource {
+                dir,
+                file: None,
+                default_branch: None,
+                mirror: None,
+                revision: None,
+            })
         } else {
             Err(anyhow!(
                 "`{}` matches {} directories",
@@ -36,10 +73,87 @@ pub fn lock(ctx: &Context, dir: PathBuf) -> Result<LockedSource> {
     }
 }
 
+/// Returns the directory that a Local source is extracted to, if the source is
+/// an archive file.
+pub fn archive_dir(ctx: &Context, dir: &Path) -> Option<PathBuf> {
+    let archive = expand(ctx, dir).ok()?;
+    (archive.is_file() && archive::is_archive(&archive)).then(|| extract_dir(ctx, &archive))
+}
+
+/// Whether the given directory is one that a Local source archive is
+/// extracted to.
+pub fn is_archive_dir(ctx: &Context, dir: &Path) -> bool {
+    dir.starts_with(ctx.download_dir().join("local"))
+}
+
+/// Expand environment variables and the tilde in a Local source directory.
+///
+/// A relative directory is resolved against the directory containing the
+/// config file, unless the config file is read from the standard input.
+fn expand(ctx: &Context, dir: &Path) -> Result<PathBuf> {
+    let expanded = util::expand_env_vars(dir)
+        .with_context(|| format!("failed to expand `{}`", dir.display()))?;
+    let expanded = ctx.expand_tilde(expanded);
+    match ctx.config_file().parent() {
+        Some(config_dir) if expanded.is_relative() && !ctx.config_from_stdin() => {
+            Ok(config_dir.join(expanded))
+        }
+        _ => Ok(expanded),
+    }
+}
+
+/// The stable directory in the download directory to extract an archive to.
+fn extract_dir(ctx: &Context, archive: &Path) -> PathBuf {
+    let archive = env::current_dir()
+        .map(|cwd| cwd.join(archive))
+        .unwrap_or_else(|_| archive.to_path_buf());
+    let mut dir = ctx.download_dir().join("local");
+    dir.extend(
+        archive
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_))),
+    );
+    dir
+}
+
+/// Extracts the archive unless it has already been extracted and hasn't
+/// changed since.
+fn lock_archive(ctx: &Context, archive: PathBuf) -> Result<LockedSource> {
+    let dir = extract_dir(ctx, &archive);
+
+    if matches!(ctx.lock_mode(), LockMode::Normal) && dir.exists() && !archive.newer_than(&dir) {
+        ctx.log_status("Checked", archive.as_path());
+    } else {
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create dir `{}`", parent.display()))?;
+        }
+        let temp_dir =
+            TempPath::new_force(&dir).context("failed to prepare temporary extract directory")?;
+        fs::create_dir(temp_dir.path())
+            .with_context(|| format!("failed to create dir `{}`", temp_dir.path().display()))?;
+        archive::extract(&archive, temp_dir.path())
+            .with_context(|| format!("failed to extract `{}`", archive.display()))?;
+        temp_dir
+            .rename(&dir)
+            .context("failed to rename temporary extract directory")?;
+        ctx.log_status("Extracted", archive.as_path());
+    }
+
+    Ok(LockedSource {
+        dir,
+        file: None,
+        default_branch: None,
+        mirror: None,
+        revision: None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::env;
     use std::process::Command;
 
     #[test]
@@ -54,6 +168,93 @@ mod tests {
         assert_eq!(locked.file, None);
     }
 
+    #[test]
+    fn lock_local_env_var() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        env::set_var("SHELDON_TEST_LOCK_LOCAL_ENV_VAR", dir);
+
+        let locked = lock(
+            &Context::testing(dir),
+            PathBuf::from("$SHELDON_TEST_LOCK_LOCAL_ENV_VAR"),
+        )
+        .unwrap();
+
+        assert_eq!(locked.dir, dir);
+        assert_eq!(locked.file, None);
+    }
+
+    #[test]
+    fn lock_local_env_var_not_set() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let err = lock(
+            &Context::testing(temp.path()),
+            PathBuf::from("${SHELDON_TEST_NOT_SET}/zsh"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "failed to expand `${SHELDON_TEST_NOT_SET}/zsh`: environment variable \
+             `SHELDON_TEST_NOT_SET` is not set"
+        );
+    }
+
+    #[test]
+    fn lock_local_archive() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("src/plugin")).unwrap();
+        fs::write(dir.join("src/plugin/test.plugin.zsh"), "").unwrap();
+        let status = Command::new("tar")
+            .args(["-czf", "plugin.tar.gz", "-C", "src", "plugin"])
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let archive = dir.join("plugin.tar.gz");
+        let ctx = Context::testing(dir);
+        let expected = extract_dir(&ctx, &archive);
+
+        let locked = lock(&ctx, archive.clone()).unwrap();
+
+        assert!(locked.dir.starts_with(ctx.download_dir()));
+        assert_eq!(locked.dir, expected);
+        assert_eq!(archive_dir(&ctx, &archive), Some(expected));
+        assert!(locked.dir.join("plugin/test.plugin.zsh").exists());
+
+        // An unchanged archive is not extracted again.
+        fs::remove_file(locked.dir.join("plugin/test.plugin.zsh")).unwrap();
+        let locked = lock(&ctx, archive).unwrap();
+        assert!(!locked.dir.join("plugin/test.plugin.zsh").exists());
+    }
+
+    #[test]
+    fn lock_local_file() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let file = dir.join("test.zsh");
+        fs::write(&file, "").unwrap();
+
+        let locked = lock(&Context::testing(dir), file.clone()).unwrap();
+
+        assert_eq!(locked.dir, dir);
+        assert_eq!(locked.file, Some(file));
+    }
+
+    #[test]
+    fn lock_local_relative_to_config_dir() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let plugin_dir = dir.join("vendored/plugin");
+        fs::create_dir_all(&plugin_dir).unwrap();
+
+        let locked = lock(&Context::testing(dir), "./vendored/plugin".into()).unwrap();
+
+        assert_eq!(locked.dir, dir.join("./vendored/plugin"));
+        assert!(locked.dir.is_dir());
+        assert_eq!(locked.file, None);
+    }
+
     fn git_clone_sheldon_test(temp: &tempfile::TempDir) -> git2::Repository {
         let dir = temp.path();
         Command::new("git")
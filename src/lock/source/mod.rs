@@ -8,9 +8,11 @@ use std::path::PathBuf;
 use anyhow::{Context as ResultExt, Result};
 use url::Url;
 
-use crate::config::Source;
+use crate::config::{GitOptions, Source};
 use crate::context::Context;
 use crate::lock::source::git::GitCheckout;
+pub use crate::lock::source::git::{is_at_revision, is_checked_out, restore as restore_git};
+pub use crate::lock::source::local::{archive_dir, is_archive_dir};
 
 /// A locked `Source`.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -19,29 +21,61 @@ pub struct LockedSource {
     pub dir: PathBuf,
     /// The downloaded file.
     pub file: Option<PathBuf>,
+    /// The name of the default branch, if that is what was checked out.
+    pub default_branch: Option<String>,
+    /// The mirror that the Git repository was cloned or fetched from, if any.
+    pub mirror: Option<Url>,
+    /// The commit that the Git repository is checked out at.
+    pub revision: Option<String>,
 }
 
 // Install a source.
 pub fn lock(ctx: &Context, src: Source) -> Result<LockedSource> {
     match src {
-        Source::Git { url, reference } => {
+        Source::Git {
+            url,
+            reference,
+            options,
+        } => {
             let dir = git_dir(ctx, &url)?;
-            git::lock(ctx, dir, &url, reference.into())
+            let GitOptions {
+                mirrors,
+                post_clone,
+            } = *options;
+            git::lock(ctx, dir, &url, &mirrors, &post_clone, reference.into())
         }
 
-        Source::Remote { url } => {
+        Source::Remote { url, index: false } => {
             let (dir, file) = remote_dir_and_file(ctx, &url)?;
             remote::lock(ctx, dir, file, &url)
         }
 
+        Source::Remote { url, index: true } => {
+            let (dir, file) = remote_index_dir_and_file(ctx, &url)?;
+            remote::lock_index(ctx, dir, file, &url)
+        }
+
         Source::Local { dir } => local::lock(ctx, dir),
     }
 }
 
+/// Download a remote source to the given file, unless it already exists.
+pub fn restore_remote(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<()> {
+    remote::lock(ctx, dir, file, url)?;
+    Ok(())
+}
+
+/// Download a remote index and the files it lists to the given directory,
+/// unless they already exist.
+pub fn restore_remote_index(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<()> {
+    remote::lock_index(ctx, dir, file, url)?;
+    Ok(())
+}
+
 impl fmt::Display for Source {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Git { url, reference } => {
+            Self::Git { url, reference, .. } => {
                 let checkout: GitCheckout = reference.clone().into();
                 write!(f, "{url}{checkout}")
             }
@@ -61,12 +95,16 @@ pub fn git_dir(ctx: &Context, url: &Url) -> Result<PathBuf> {
     Ok(dir)
 }
 
+/// The directory that the lock files of Git repository directories are kept in.
+pub fn locks_dir(ctx: &Context) -> PathBuf {
+    ctx.data_dir().join("locks")
+}
+
 pub fn remote_dir_and_file(ctx: &Context, url: &Url) -> Result<(PathBuf, PathBuf)> {
     let mut dir = ctx.download_dir().to_path_buf();
-    dir.push(
-        url.host_str()
-            .with_context(|| format!("URL `{url}` has no host"))?,
-    );
+    // URLs without a host, like `file://` URLs, are namespaced by the scheme
+    // so they can't clash with a host of the same name.
+    dir.push(url.host_str().unwrap_or_else(|| url.scheme()));
 
     let segments: Vec<_> = url
         .path_segments()
@@ -79,6 +117,15 @@ pub fn remote_dir_and_file(ctx: &Context, url: &Url) -> Result<(PathBuf, PathBuf
     Ok((dir, file))
 }
 
+/// The directory that the files listed in a remote index are downloaded to,
+/// next to the index file itself.
+pub fn remote_index_dir_and_file(ctx: &Context, url: &Url) -> Result<(PathBuf, PathBuf)> {
+    let (_, file) = remote_dir_and_file(ctx, url)?;
+    let mut dir = file.clone().into_os_string();
+    dir.push(".d");
+    Ok((dir.into(), file))
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Unit tests
 ////////////////////////////////////////////////////////////////////////////////
@@ -97,6 +144,7 @@ mod tests {
             Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: Some(GitReference::Tag("v0.3.0".to_string())),
+                options: Box::default(),
             }
             .to_string(),
             "https://github.com/rossmacarthur/sheldon-test@v0.3.0"
@@ -105,6 +153,7 @@ mod tests {
             Source::Git {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
                 reference: None,
+                options: Box::default(),
             }
             .to_string(),
             "https://github.com/rossmacarthur/sheldon-test"
@@ -113,6 +162,7 @@ mod tests {
             Source::Remote {
                 url: Url::parse("https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT")
                     .unwrap(),
+                index: false,
             }
             .to_string(),
             "https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT"
@@ -135,6 +185,7 @@ mod tests {
         let source = Source::Git {
             url: Url::parse("https://github.com/rossmacarthur/sheldon-test").unwrap(),
             reference: None,
+            options: Box::default(),
         };
         let locked = lock(&ctx, source).unwrap();
 
@@ -143,10 +194,36 @@ mod tests {
             LockedSource {
                 dir: dir.join("repos/github.com/rossmacarthur/sheldon-test"),
                 file: None,
+                default_branch: Some("master".into()),
+                mirror: None,
+                revision: None,
             }
         );
     }
 
+    #[test]
+    fn lock_with_remote_file_url() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let ctx = Context::testing(dir);
+        let mirror = dir.join("mirror");
+        std::fs::create_dir(&mirror).unwrap();
+        std::fs::write(mirror.join("test.plugin.zsh"), "echo 'testing...'\n").unwrap();
+
+        let url = Url::from_file_path(mirror.join("test.plugin.zsh")).unwrap();
+        let locked = lock(&ctx, Source::Remote { url, index: false }).unwrap();
+
+        let download_dir = dir
+            .join("downloads/file")
+            .join(mirror.strip_prefix("/").unwrap());
+        assert_eq!(locked.dir, download_dir);
+        assert_eq!(locked.file, Some(download_dir.join("test.plugin.zsh")));
+        assert_eq!(
+            std::fs::read_to_string(download_dir.join("test.plugin.zsh")).unwrap(),
+            "echo 'testing...'\n"
+        );
+    }
+
     #[test]
     fn lock_with_remote() {
         let temp = tempfile::tempdir().expect("create temporary directory");
@@ -156,6 +233,7 @@ mod tests {
         let source = Source::Remote {
             url: Url::parse("https://github.com/rossmacarthur/sheldon/raw/0.3.0/LICENSE-MIT")
                 .unwrap(),
+            index: false,
         };
         let locked = lock(&ctx, source).unwrap();
 
@@ -1,7 +1,7 @@
 use std::fs;
 use std::path::PathBuf;
 
-use anyhow::{Context as ResultExt, Result};
+use anyhow::{bail, Context as ResultExt, Result};
 use url::Url;
 
 use crate::context::Context;
@@ -16,8 +16,14 @@ pub fn lock(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<Loc
         return Ok(LockedSource {
             dir,
             file: Some(file),
+            default_branch: None,
+            mirror: None,
+            revision: None,
         });
     }
+    if ctx.offline {
+        bail!("`{url}` is not downloaded and can't be downloaded offline");
+    }
 
     let temp_file =
         TempPath::new_force(&file).context("failed to prepare temporary download directory")?;
@@ -27,7 +33,7 @@ pub fn lock(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<Loc
             .with_context(|| format!("failed to create dir `{}`", dir.display()))?;
         let temp_file_handle = fs::File::create(path)
             .with_context(|| format!("failed to create `{}`", path.display()))?;
-        util::download(url.as_ref(), temp_file_handle)
+        util::download(url.as_ref(), temp_file_handle, ctx.max_download_size)
             .with_context(|| format!("failed to download `{url}`"))?;
     }
     temp_file
@@ -38,9 +44,99 @@ pub fn lock(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<Loc
     Ok(LockedSource {
         dir,
         file: Some(file),
+        default_branch: None,
+        mirror: None,
+        revision: None,
     })
 }
 
+/// Downloads an index file and each of the files that it lists into the
+/// directory.
+///
+/// The index lists one URL per line, relative URLs are resolved against the
+/// URL of the index itself. Empty lines and lines starting with `#` are
+/// ignored. All the files are downloaded to a temporary directory first so
+/// that a failed download leaves any previous download alone.
+pub fn lock_index(ctx: &Context, dir: PathBuf, file: PathBuf, url: &Url) -> Result<LockedSource> {
+    let locked = LockedSource {
+        dir,
+        file: None,
+        default_branch: None,
+        mirror: None,
+        revision: None,
+    };
+    if matches!(ctx.lock_mode(), LockMode::Normal) && file.exists() && locked.dir.is_dir() {
+        ctx.log_status("Checked", url);
+        return Ok(locked);
+    }
+    if ctx.offline {
+        bail!("`{url}` is not downloaded and can't be downloaded offline");
+    }
+
+    let parent = file.parent().unwrap();
+    fs::create_dir_all(parent)
+        .with_context(|| format!("failed to create dir `{}`", parent.display()))?;
+    let temp_file =
+        TempPath::new_force(&file).context("failed to prepare temporary download file")?;
+    {
+        let path = temp_file.path();
+        let temp_file_handle = fs::File::create(path)
+            .with_context(|| format!("failed to create `{}`", path.display()))?;
+        util::download(url.as_ref(), temp_file_handle, ctx.max_download_size)
+            .with_context(|| format!("failed to download index `{url}`"))?;
+    }
+    let index = fs::read_to_string(temp_file.path())
+        .with_context(|| format!("failed to read index `{url}`"))?;
+    let urls = parse_index(url, &index)?;
+
+    let temp_dir = TempPath::new_force(&locked.dir)
+        .context("failed to prepare temporary download directory")?;
+    fs::create_dir_all(temp_dir.path())
+        .with_context(|| format!("failed to create dir `{}`", temp_dir.path().display()))?;
+    for file_url in &urls {
+        let name = file_url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .with_context(|| format!("`{file_url}` listed in index `{url}` has no file name"))?;
+        let path = temp_dir.path().join(name);
+        if path.exists() {
+            bail!("index `{url}` lists more than one file named `{name}`");
+        }
+        let handle = fs::File::create(&path)
+            .with_context(|| format!("failed to create `{}`", path.display()))?;
+        util::download(file_url.as_ref(), handle, ctx.max_download_size)
+            .with_context(|| format!("failed to download `{file_url}` listed in index `{url}`"))?;
+        ctx.log_verbose_status("Fetched", file_url);
+    }
+    temp_dir
+        .rename(&locked.dir)
+        .context("failed to rename temporary download directory")?;
+    temp_file
+        .rename(&file)
+        .context("failed to rename temporary download file")?;
+    ctx.log_status("Fetched", url);
+
+    Ok(locked)
+}
+
+/// Parse the URLs listed in an index.
+fn parse_index(url: &Url, index: &str) -> Result<Vec<Url>> {
+    let urls = index
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            url.join(line)
+                .with_context(|| format!("index `{url}` lists an invalid URL `{line}`"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if urls.is_empty() {
+        bail!("index `{url}` does not list any files");
+    }
+    Ok(urls)
+}
+
 #[cfg(test)]
 mod tests {
     use std::thread;
@@ -80,4 +176,92 @@ mod tests {
         );
         assert!(fs::metadata(&file).unwrap().modified().unwrap() > modified);
     }
+
+    #[test]
+    fn lock_remote_offline() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        let file = dir.join("test.txt");
+        let mut ctx = Context::testing(dir);
+        ctx.offline = true;
+        let url = Url::parse("https://example.com/test.txt").unwrap();
+
+        let err = lock(&ctx, dir.to_path_buf(), file.clone(), &url).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`https://example.com/test.txt` is not downloaded and can't be downloaded offline"
+        );
+
+        fs::write(&file, "cached").unwrap();
+        let locked = lock(&ctx, dir.to_path_buf(), file.clone(), &url).unwrap();
+        assert_eq!(locked.file, Some(file.clone()));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "cached");
+    }
+
+    #[test]
+    fn lock_remote_index() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let mirror = temp.path().join("mirror");
+        fs::create_dir_all(mirror.join("lib")).unwrap();
+        fs::write(mirror.join("a.zsh"), "echo a\n").unwrap();
+        fs::write(mirror.join("lib/b.zsh"), "echo b\n").unwrap();
+        let b_url = Url::from_file_path(mirror.join("lib/b.zsh")).unwrap();
+        fs::write(
+            mirror.join("index.txt"),
+            format!("# scripts\na.zsh\n\n{b_url}\n"),
+        )
+        .unwrap();
+        let url = Url::from_file_path(mirror.join("index.txt")).unwrap();
+        let dir = temp.path().join("downloads/index.txt.d");
+        let file = temp.path().join("downloads/index.txt");
+        let mut ctx = Context::testing(temp.path());
+
+        let locked = lock_index(&ctx, dir.clone(), file.clone(), &url).unwrap();
+
+        assert_eq!(locked.dir, dir);
+        assert_eq!(locked.file, None);
+        assert_eq!(fs::read_to_string(dir.join("a.zsh")).unwrap(), "echo a\n");
+        assert_eq!(fs::read_to_string(dir.join("b.zsh")).unwrap(), "echo b\n");
+
+        // A missing file fails the whole download and keeps the previous one.
+        fs::write(mirror.join("index.txt"), "a.zsh\nmissing.zsh\n").unwrap();
+        ctx.lock_mode = Some(LockMode::Reinstall);
+        let err = lock_index(&ctx, dir.clone(), file.clone(), &url).unwrap_err();
+
+        let missing = url.join("missing.zsh").unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("failed to download `{missing}` listed in index `{url}`")
+        );
+        assert!(dir.join("b.zsh").exists());
+        assert!(!temp.path().join("downloads/~index.txt.d").exists());
+    }
+
+    #[test]
+    fn lock_remote_index_invalid() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let index = temp.path().join("index.txt");
+        let url = Url::from_file_path(&index).unwrap();
+        let dir = temp.path().join("downloads/index.txt.d");
+        let file = temp.path().join("downloads/index.txt");
+        let ctx = Context::testing(temp.path());
+
+        let err = lock_index(&ctx, dir.clone(), file.clone(), &url).unwrap_err();
+        assert_eq!(err.to_string(), format!("failed to download index `{url}`"));
+
+        fs::write(&index, "# nothing\n").unwrap();
+        let err = lock_index(&ctx, dir.clone(), file.clone(), &url).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("index `{url}` does not list any files")
+        );
+
+        fs::write(&index, "a/\n").unwrap();
+        let err = lock_index(&ctx, dir, file, &url).unwrap_err();
+        let a = url.join("a/").unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("`{a}` listed in index `{url}` has no file name")
+        );
+    }
 }
@@ -0,0 +1,82 @@
+//! Timing information collected while locking and rendering plugins.
+
+use std::cmp::Reverse;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The durations recorded for each plugin.
+///
+/// This can be shared between threads so that sources installed in parallel
+/// can each record how long they took.
+#[derive(Debug, Default)]
+pub struct Timings {
+    inner: Mutex<Vec<Timing>>,
+}
+
+/// A single recorded duration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timing {
+    /// The name of the plugin, or plugins if they share a source.
+    pub name: String,
+    /// What was timed, either "install" or "render".
+    pub stage: &'static str,
+    /// How long it took.
+    pub duration: Duration,
+}
+
+impl Timings {
+    /// Record how long the given stage took for the given plugin.
+    pub fn record(&self, name: impl Into<String>, stage: &'static str, duration: Duration) {
+        self.inner.lock().unwrap().push(Timing {
+            name: name.into(),
+            stage,
+            duration,
+        });
+    }
+
+    /// Returns all the recorded durations, slowest first.
+    pub fn sorted(&self) -> Vec<Timing> {
+        let mut timings = self.inner.lock().unwrap().clone();
+        timings.sort_by_key(|t| Reverse(t.duration));
+        timings
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn timings_sorted_slowest_first() {
+        let timings = Timings::default();
+        thread::scope(|s| {
+            for (name, millis) in [("a", 10), ("b", 30), ("c", 20)] {
+                let timings = &timings;
+                s.spawn(move || {
+                    timings.record(name, "install", Duration::from_millis(millis));
+                });
+            }
+        });
+        timings.record("a", "render", Duration::from_millis(5));
+        let names: Vec<_> = timings
+            .sorted()
+            .into_iter()
+            .map(|t| (t.name, t.stage))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("b".into(), "install"),
+                ("c".into(), "install"),
+                ("a".into(), "install"),
+                ("a".into(), "render"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+
+use crate::lock::file::LockedPlugin;
+use crate::lock::LockedConfig;
+
+impl LockedConfig {
+    /// Describe what the plugin with the given name was resolved to.
+    pub fn which(&self, name: &str) -> Result<String> {
+        let plugin = self
+            .plugins
+            .iter()
+            .find(|plugin| match plugin {
+                LockedPlugin::External(plugin) => plugin.name == name,
+                LockedPlugin::Inline(plugin) => plugin.name == name,
+            })
+            .ok_or_else(|| anyhow!("plugin with name `{name}` does not exist in the lock file"))?;
+
+        let plugin = match plugin {
+            LockedPlugin::External(plugin) => plugin,
+            LockedPlugin::Inline(_) => return Ok(format!("{name} is an inline plugin\n")),
+        };
+
+        let mut s = String::new();
+        writeln!(s, "source_dir: {}", plugin.source_dir.display())?;
+        writeln!(s, "dir: {}", plugin.dir().display())?;
+        writeln!(s, "files:")?;
+        for file in &plugin.files {
+            writeln!(s, "  {}", file.display())?;
+        }
+        write!(s, "apply: {}", plugin.apply.join(", "))?;
+        if plugin.apply_rules {
+            write!(s, " (global rules take precedence)")?;
+        }
+        writeln!(s)?;
+        Ok(s)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use crate::config::InlinePlugin;
+    use crate::context::Context;
+    use crate::lock::file::{LockedExternalPlugin, LOCKFILE_VERSION};
+
+    fn locked_config(ctx: &Context) -> LockedConfig {
+        let source_dir = ctx.clone_dir().join("example.com/test");
+        LockedConfig {
+            lockfile_version: LOCKFILE_VERSION,
+            ctx: ctx.clone(),
+            config_hash: None,
+            shell_version: None,
+            plugins: vec![
+                LockedPlugin::External(Box::new(LockedExternalPlugin {
+                    name: "test".into(),
+                    source_dir: source_dir.clone(),
+                    url: None,
+                    host: Some("example.com".into()),
+                    default_branch: None,
+                    reference: None,
+                    mirror: None,
+                    revision: None,
+                    post_clone: Vec::new(),
+                    plugin_dir: Some(source_dir.join("plugins")),
+                    files: vec![
+                        source_dir.join("plugins/a.zsh"),
+                        source_dir.join("plugins/b.zsh"),
+                    ],
+                    apply: vec_into!["PATH", "source"],
+                    apply_rules: false,
+                    file_apply: BTreeMap::new(),
+                    hooks: BTreeMap::new(),
+                    vars: BTreeMap::new(),
+                })),
+                LockedPlugin::Inline(InlinePlugin {
+                    name: "inline".into(),
+                    raw: "echo 'testing...'".into(),
+                    apply: None,
+                    profiles: None,
+                    enabled: None,
+                    hooks: None,
+                }),
+            ],
+            templates: crate::config::Shell::Zsh.default_templates().clone(),
+            rules: Vec::new(),
+            hooks: BTreeMap::new(),
+            errors: Vec::new(),
+            timings: Default::default(),
+        }
+    }
+
+    #[test]
+    fn locked_config_which_external() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        let dir = ctx.clone_dir().join("example.com/test");
+        assert_eq!(
+            locked_config(&ctx).which("test").unwrap(),
+            format!(
+                "source_dir: {0}\ndir: {0}/plugins\nfiles:\n  {0}/plugins/a.zsh\n  \
+                 {0}/plugins/b.zsh\napply: PATH, source\n",
+                dir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn locked_config_which_inline() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        assert_eq!(
+            locked_config(&ctx).which("inline").unwrap(),
+            "inline is an inline plugin\n"
+        );
+    }
+
+    #[test]
+    fn locked_config_which_missing() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let ctx = Context::testing(temp.path());
+        assert_eq!(
+            locked_config(&ctx)
+                .which("missing")
+                .unwrap_err()
+                .to_string(),
+            "plugin with name `missing` does not exist in the lock file"
+        );
+    }
+}
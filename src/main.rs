@@ -8,24 +8,30 @@ mod lock;
 mod util;
 
 use std::fs;
-use std::io;
+use std::io::{self, IsTerminal};
+use std::mem;
 use std::panic;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 
 use anyhow::{bail, Context as ResultExt, Error, Result};
+use url::Url;
 
 use crate::cli::{Command, Opt};
-use crate::config::{EditConfig, EditPlugin, Shell};
+use crate::config::{Config, EditConfig, EditPlugin, Position, Shell};
 use crate::context::Context;
-use crate::lock::LockedConfig;
-use crate::util::underlying_io_error_kind;
+use crate::lock::{Annotate, LockMode, LockedConfig};
+use crate::util::{underlying_io_error_kind, PathExt, TempPath};
 
 fn main() {
     let res = panic::catch_unwind(|| {
         let Opt { ctx, command } = cli::from_args();
         if let Err(err) = run_command(&ctx, command) {
-            ctx.log_error(&err);
+            let errs = match err.downcast::<LockErrors>() {
+                Ok(LockErrors(errs)) => errs,
+                Err(err) => vec![err],
+            };
+            ctx.log_errors(&errs);
             process::exit(2);
         }
     });
@@ -44,19 +50,71 @@ pub fn run_command(ctx: &Context, command: Command) -> Result<()> {
     // the lock and source commands.
     let _guard = match acquire_mutex(ctx, ctx.config_dir()) {
         Ok(g) => Some(g),
-        Err(_) if !matches!(command, Command::Lock | Command::Source) => None,
+        Err(_)
+            if !matches!(
+                command,
+                Command::Lock { .. }
+                    | Command::Source { .. }
+                    | Command::Gc
+                    | Command::Restore
+                    | Command::Prune
+            ) =>
+        {
+            None
+        }
         Err(err) => {
             return Err(err).context("failed to acquire lock on config directory");
         }
     };
     let mut warnings = Vec::new();
     let result = match command {
-        Command::Init { shell } => init(ctx, shell),
-        Command::Add { name, plugin } => add(ctx, name, &plugin),
-        Command::Edit => edit(ctx),
-        Command::Remove { name } => remove(ctx, name),
-        Command::Lock => lock(ctx, &mut warnings),
-        Command::Source => source(ctx, &mut warnings),
+        Command::Init { shell, from } => init(ctx, shell, from.as_deref()),
+        Command::Add {
+            name,
+            plugin,
+            position,
+            force,
+        } => add(ctx, name, &plugin, position.as_ref(), force),
+        Command::Edit { editor } => edit(ctx, editor.as_deref()),
+        Command::Remove { name, dry_run } => remove(ctx, &name, dry_run),
+        Command::Rename { old, new } => rename(ctx, &old, &new),
+        Command::Enable { name } => set_enabled(ctx, &name, true),
+        Command::Disable { name } => set_enabled(ctx, &name, false),
+        Command::ConfigGet { key } => config_get(ctx, &key),
+        Command::ConfigSet { key, values } => config_set(ctx, &key, &values),
+        Command::MigrateConfig => migrate_config(ctx),
+        Command::Lock {
+            no_clean,
+            diff,
+            yes,
+            keep_going,
+        } => lock(ctx, no_clean, diff, yes, keep_going, &mut warnings),
+        Command::Source {
+            annotate,
+            no_lock_file,
+            plugins_dir,
+            output,
+            check,
+            plugins_filter,
+            no_clean,
+            keep_going,
+        } => source(
+            ctx,
+            annotate,
+            no_lock_file,
+            plugins_dir,
+            output,
+            check,
+            plugins_filter.as_deref(),
+            no_clean,
+            keep_going,
+            &mut warnings,
+        ),
+        Command::Which { name } => which(ctx, &name),
+        Command::Templates => templates(ctx, &mut warnings),
+        Command::Gc => gc(ctx),
+        Command::Restore => restore(ctx),
+        Command::Prune => prune(ctx, &mut warnings),
     };
     for err in &warnings {
         ctx.log_error_as_warning(err);
@@ -84,7 +142,7 @@ fn acquire_mutex(ctx: &Context, path: &Path) -> Result<fmutex::Guard> {
 /// Executes the `init` subcommand.
 ///
 /// Initialize a new config file.
-fn init(ctx: &Context, shell: Option<Shell>) -> Result<()> {
+fn init(ctx: &Context, shell: Option<Shell>, from: Option<&str>) -> Result<()> {
     let path = ctx.config_file();
     match path
         .metadata()
@@ -94,7 +152,7 @@ fn init(ctx: &Context, shell: Option<Shell>) -> Result<()> {
             ctx.log_header("Unchanged", path);
         }
         Err(err) => {
-            init_config(ctx, shell, path, err)?.to_path(path)?;
+            init_config(ctx, shell, from, path, err)?.to_path(path)?;
             ctx.log_header("Initialized", path);
         }
     }
@@ -104,17 +162,31 @@ fn init(ctx: &Context, shell: Option<Shell>) -> Result<()> {
 /// Executes the `add` subcommand.
 ///
 /// Add a new plugin to the config file.
-fn add(ctx: &Context, name: String, plugin: &EditPlugin) -> Result<()> {
+fn add(
+    ctx: &Context,
+    name: String,
+    plugin: &EditPlugin,
+    position: Option<&Position>,
+    force: bool,
+) -> Result<()> {
     let path = ctx.config_file();
     let mut config = match EditConfig::from_path(path) {
         Ok(config) => {
             ctx.log_header("Loaded", path);
             config
         }
-        Err(err) => init_config(ctx, None, path, err)?,
+        Err(err) => init_config(ctx, None, None, path, err)?,
     };
-    config.add(&name, plugin)?;
-    ctx.log_status("Added", &name);
+    if force {
+        if config.add_or_replace(&name, plugin, position)? {
+            ctx.log_status("Replaced", &name);
+        } else {
+            ctx.log_status("Added", &name);
+        }
+    } else {
+        config.add(&name, plugin, position)?;
+        ctx.log_status("Added", &name);
+    }
     config.to_path(ctx.config_file())?;
     ctx.log_header("Updated", path);
     Ok(())
@@ -123,7 +195,7 @@ fn add(ctx: &Context, name: String, plugin: &EditPlugin) -> Result<()> {
 /// Executes the `edit` subcommand.
 ///
 /// Open up the config file in the default editor.
-fn edit(ctx: &Context) -> Result<()> {
+fn edit(ctx: &Context, editor: Option<&str>) -> Result<()> {
     let path = ctx.config_file();
     let original_contents = match fs::read_to_string(path)
         .with_context(|| format!("failed to read from `{}`", path.display()))
@@ -134,13 +206,17 @@ fn edit(ctx: &Context) -> Result<()> {
             contents
         }
         Err(err) => {
-            let config = init_config(ctx, None, path, err)?;
+            let config = init_config(ctx, None, None, path, err)?;
             config.to_path(path)?;
             ctx.log_header("Initialized", path);
             config.to_string()
         }
     };
-    let handle = editor::Editor::default()?.edit(ctx, path, &original_contents)?;
+    let editor = match editor {
+        Some(cmd) => editor::Editor::from_command(cmd)?,
+        None => editor::Editor::default()?,
+    };
+    let handle = editor.edit(ctx, path, &original_contents)?;
     ctx.log_status("Opened", &"config in temporary file for editing");
     let config = handle.wait_and_update(&original_contents)?;
     config.to_path(path)?;
@@ -150,20 +226,112 @@ fn edit(ctx: &Context) -> Result<()> {
 
 /// Executes the `remove` subcommand.
 ///
-/// Remove a plugin from the config file.
-fn remove(ctx: &Context, name: String) -> Result<()> {
+/// Remove the plugins matching a name or glob pattern from the config file.
+fn remove(ctx: &Context, pattern: &str, dry_run: bool) -> Result<()> {
     let path = ctx.config_file();
     let mut config = EditConfig::from_path(path)?;
     ctx.log_header("Loaded", path);
-    config.remove(&name);
-    ctx.log_status("Removed", &name);
+    let names = config.matching(pattern)?;
+    if dry_run {
+        for name in &names {
+            ctx.log_status("Matched", name);
+        }
+        return Ok(());
+    }
+    for name in &names {
+        config.remove(name);
+        ctx.log_status("Removed", name);
+    }
     config.to_path(ctx.config_file())?;
     ctx.log_header("Updated", path);
     Ok(())
 }
 
+/// Executes the `rename` subcommand.
+///
+/// Rename a plugin in the config file.
+fn rename(ctx: &Context, old: &str, new: &str) -> Result<()> {
+    let path = ctx.config_file();
+    let mut config = EditConfig::from_path(path)?;
+    ctx.log_header("Loaded", path);
+    config.rename(old, new)?;
+    ctx.log_status("Renamed", &format!("{old} -> {new}"));
+    config.to_path(ctx.config_file())?;
+    ctx.log_header("Updated", path);
+    Ok(())
+}
+
+/// Executes the `config get` subcommand.
+///
+/// Print the value of a top-level setting in the config file.
+fn config_get(ctx: &Context, key: &str) -> Result<()> {
+    let path = ctx.config_file();
+    let config = EditConfig::from_path(path)?;
+    ctx.log_header("Loaded", path);
+    if let Some(value) = config.get(key)? {
+        println!("{value}");
+    }
+    Ok(())
+}
+
+/// Executes the `config set` subcommand.
+///
+/// Set the value of a top-level setting in the config file.
+fn config_set(ctx: &Context, key: &str, values: &[String]) -> Result<()> {
+    let path = ctx.config_file();
+    let mut config = EditConfig::from_path(path)?;
+    ctx.log_header("Loaded", path);
+    config.set(key, values)?;
+    config.to_path(path)?;
+    ctx.log_header("Updated", path);
+    Ok(())
+}
+
+/// Executes the `enable` and `disable` subcommands.
+///
+/// Toggle whether a plugin in the config file is used.
+fn set_enabled(ctx: &Context, name: &str, enabled: bool) -> Result<()> {
+    let path = ctx.config_file();
+    let mut config = EditConfig::from_path(path)?;
+    ctx.log_header("Loaded", path);
+    config.set_enabled(name, enabled)?;
+    ctx.log_status(if enabled { "Enabled" } else { "Disabled" }, &name);
+    config.to_path(path)?;
+    ctx.log_header("Updated", path);
+    Ok(())
+}
+
+/// Executes the `migrate-config` subcommand.
+///
+/// Rewrite deprecated keys and template syntax in the config file.
+fn migrate_config(ctx: &Context) -> Result<()> {
+    let path = ctx.config_file();
+    let mut config = EditConfig::from_path(path)?;
+    ctx.log_header("Loaded", path);
+    let rewrites = config.migrate();
+    if rewrites.is_empty() {
+        ctx.log_header("Unchanged", path);
+        return Ok(());
+    }
+    for rewrite in &rewrites {
+        ctx.log_status("Migrated", rewrite);
+    }
+    config.to_path(path)?;
+    ctx.log_header("Updated", path);
+    Ok(())
+}
+
 /// Generic function to initialize the config file.
-fn init_config(ctx: &Context, shell: Option<Shell>, path: &Path, err: Error) -> Result<EditConfig> {
+///
+/// The config is the default for the shell, or the config file at the given
+/// URL or path.
+fn init_config(
+    ctx: &Context,
+    shell: Option<Shell>,
+    from: Option<&str>,
+    path: &Path,
+    err: Error,
+) -> Result<EditConfig> {
     if underlying_io_error_kind(&err) == Some(io::ErrorKind::NotFound) {
         if ctx.interactive
             && !casual::confirm(format!(
@@ -181,91 +349,325 @@ fn init_config(ctx: &Context, shell: Option<Shell>, path: &Path, err: Error) ->
                 )
             })?;
         }
-        Ok(EditConfig::default(shell))
+        match from {
+            Some(from) => fetch_config(ctx, from, path),
+            None => Ok(EditConfig::default(shell)),
+        }
     } else {
         Err(err)
     }
 }
 
+/// Download or read a config file to initialize the config file at the path
+/// with.
+fn fetch_config(ctx: &Context, from: &str, path: &Path) -> Result<EditConfig> {
+    let contents = match Url::parse(from) {
+        Ok(url) => {
+            let temp = TempPath::new_force(path).context("failed to prepare temporary file")?;
+            let file = fs::File::create(temp.path())
+                .with_context(|| format!("failed to create `{}`", temp.path().display()))?;
+            util::download(url.as_str(), file, ctx.max_download_size)
+                .with_context(|| format!("failed to download `{url}`"))?;
+            ctx.log_status("Downloaded", &url);
+            fs::read_to_string(temp.path())
+                .with_context(|| format!("failed to read from `{}`", temp.path().display()))?
+        }
+        Err(_) => {
+            let from = ctx.expand_tilde(PathBuf::from(from));
+            let contents = fs::read_to_string(&from)
+                .with_context(|| format!("failed to read from `{}`", from.display()))?;
+            ctx.log_status("Copied", from.as_path());
+            contents
+        }
+    };
+    EditConfig::from_str(contents).with_context(|| format!("`{from}` is not a valid config file"))
+}
+
+/// All the errors that occurred while locking, these are reported together.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to lock config")]
+struct LockErrors(Vec<Error>);
+
 /// Execute the `lock` subcommand.
 ///
 /// Install the plugins sources and generate the lock file.
-fn lock(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
-    let mut locked = locked(ctx, warnings)?;
+///
+/// If any plugin fails the lock file is not written, unless `keep_going` is
+/// set in which case it is written with the plugins that succeeded. Either
+/// way all the errors are returned together.
+fn lock(
+    ctx: &Context,
+    no_clean: bool,
+    diff: bool,
+    yes: bool,
+    keep_going: bool,
+    warnings: &mut Vec<Error>,
+) -> Result<()> {
+    if ctx.lock_mode() == LockMode::Reinstall
+        && !yes
+        && ctx.interactive
+        && io::stdout().is_terminal()
+        && !casual::confirm("Reinstall all plugin sources?")
+    {
+        bail!("aborted reinstall!");
+    }
+    let path = ctx.lock_file();
+    let previous = if diff {
+        lock::from_path(path).ok()
+    } else {
+        None
+    };
+    let mut locked = locked(ctx, None, no_clean, warnings)?;
+    log_timings(ctx, &locked);
 
-    if let Some(last) = locked.errors.pop() {
-        for err in locked.errors {
-            ctx.log_error(&err);
+    let errors = mem::take(&mut locked.errors);
+    if errors.is_empty() || keep_going {
+        if diff {
+            print!("{}", locked.diff(ctx, previous.as_ref())?);
         }
-        Err(last)
-    } else {
-        let path = ctx.lock_file();
         locked.to_path(path).context("failed to write lock file")?;
         ctx.log_header("Locked", path);
+    }
+    if errors.is_empty() {
         Ok(())
+    } else {
+        Err(LockErrors(errors).into())
     }
 }
 
 /// Execute the `source` subcommand.
 ///
 /// Generate and print out the shell script.
-fn source(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
-    let config_path = ctx.config_file();
+///
+/// If `check` is set the script is only generated, not printed, and any
+/// plugin that failed is returned as an error.
+#[allow(clippy::too_many_arguments)]
+fn source(
+    ctx: &Context,
+    annotate: Option<Annotate>,
+    no_lock_file: bool,
+    plugins_dir: Option<PathBuf>,
+    output: Option<PathBuf>,
+    check: bool,
+    plugins_filter: Option<&str>,
+    no_clean: bool,
+    keep_going: bool,
+    warnings: &mut Vec<Error>,
+) -> Result<()> {
+    let plugins_dir = plugins_dir.as_deref();
     let lock_path = ctx.lock_file();
 
-    let mut to_path = true;
+    let mut to_path = !no_lock_file;
 
-    let locked_config = if ctx.lock_mode.is_some() || newer_than(config_path, lock_path) {
-        locked(ctx, warnings)?
+    let mut locked_config = if no_lock_file || ctx.lock_mode.is_some() {
+        locked(ctx, plugins_dir, no_clean, warnings)?
     } else {
         match lock::from_path(lock_path) {
-            Ok(locked_config) => {
-                if locked_config.verify(ctx) {
-                    to_path = false;
-                    ctx.log_verbose_header("Unlocked", lock_path);
-                    locked_config
-                } else {
-                    locked(ctx, warnings)?
-                }
+            Ok(locked_config)
+                if !config_changed(ctx, &locked_config)
+                    && locked_config.verify(&with_config_shell(ctx)) =>
+            {
+                to_path = false;
+                ctx.log_verbose_header("Unlocked", lock_path);
+                locked_config
             }
-            Err(_) => locked(ctx, warnings)?,
+            _ => locked(ctx, plugins_dir, no_clean, warnings)?,
         }
     };
 
-    let script = locked_config
-        .script(ctx, warnings)
-        .context("failed to render source")?;
+    let script = match (plugins_filter, annotate) {
+        (Some(pattern), _) => locked_config.filtered_script(ctx, pattern, annotate, warnings),
+        (None, Some(annotate)) => locked_config.annotated_script(ctx, annotate, warnings),
+        (None, None) => locked_config.script(ctx, warnings),
+    }
+    .context("failed to render source")?;
+    log_timings(ctx, &locked_config);
 
-    if to_path && locked_config.errors.is_empty() {
-        locked_config
-            .to_path(lock_path)
-            .context("failed to write lock file")?;
-        ctx.log_header("Locked", lock_path);
+    let errors = mem::take(&mut locked_config.errors);
+    if errors.is_empty() || keep_going {
+        if to_path {
+            locked_config
+                .to_path(lock_path)
+                .context("failed to write lock file")?;
+            ctx.log_header("Locked", lock_path);
+        }
+    } else if !check {
+        ctx.log_errors(&errors);
+    }
+
+    match output {
+        _ if check => {}
+        Some(path) => {
+            write_script(&path, &script)?;
+            ctx.log_header("Wrote", path.as_path());
+        }
+        None => print!("{script}"),
+    }
+    if (keep_going || check) && !errors.is_empty() {
+        Err(LockErrors(errors).into())
     } else {
-        for err in &locked_config.errors {
-            ctx.log_error(err);
+        Ok(())
+    }
+}
+
+/// Log how long each plugin took to install and render, slowest first.
+fn log_timings(ctx: &Context, locked: &LockedConfig) {
+    if ctx.timings {
+        for timing in locked.timings.sorted() {
+            ctx.log_status(
+                "Timed",
+                &format!("{} {} ({:.2?})", timing.name, timing.stage, timing.duration),
+            );
         }
     }
+}
+
+/// Atomically write the script to the given path.
+///
+/// The script is first written to a temporary file next to the path which is
+/// then renamed, so an existing file is never partially overwritten.
+fn write_script(path: &Path, script: &str) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory `{}`", parent.display()))?;
+    }
+    let temp = TempPath::new_force(path).context("failed to prepare temporary output file")?;
+    fs::write(temp.path(), script)
+        .with_context(|| format!("failed to write to `{}`", temp.path().display()))?;
+    temp.rename(path)
+        .with_context(|| format!("failed to write script to `{}`", path.display()))
+}
+
+/// Execute the `which` subcommand.
+///
+/// Print the directory and files that a plugin resolved to in the lock file.
+fn which(ctx: &Context, name: &str) -> Result<()> {
+    let path = ctx.lock_file();
+    let locked = lock::from_path(path).context("failed to load lock file")?;
+    ctx.log_header("Unlocked", path);
+    print!("{}", locked.which(name)?);
+    Ok(())
+}
 
-    print!("{script}");
+/// Execute the `templates` subcommand.
+///
+/// Print the built-in and configured templates that can be applied.
+fn templates(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
+    let path = ctx.config_file();
+    let config = config::from_path(path, warnings).context("failed to load config file")?;
+    ctx.log_header("Loaded", path);
+    print!("{}", config.list_templates());
     Ok(())
 }
 
-/// Returns `true` if the left path is newer than the right.
-fn newer_than(left: &Path, right: &Path) -> bool {
-    let modified = |p| fs::metadata(p).and_then(|m| m.modified()).ok();
-    match (modified(left), modified(right)) {
-        (Some(ltime), Some(rtime)) => ltime > rtime,
-        _ => false,
+/// Execute the `gc` subcommand.
+///
+/// Garbage collect the cloned Git sources in the lock file.
+fn gc(ctx: &Context) -> Result<()> {
+    let path = ctx.lock_file();
+    let locked = lock::from_path(path).context("failed to load lock file")?;
+    ctx.log_header("Unlocked", path);
+    let reclaimed = locked.gc(ctx)?;
+    ctx.log_header("Reclaimed", &util::format_bytes(reclaimed));
+    Ok(())
+}
+
+/// Execute the `restore` subcommand.
+///
+/// Reinstall the plugin sources exactly as recorded in the lock file.
+fn restore(ctx: &Context) -> Result<()> {
+    let path = ctx.lock_file();
+    let locked = lock::from_path(path).context("failed to load lock file")?;
+    ctx.log_header("Unlocked", path);
+    let errors = locked.restore(ctx);
+    if errors.is_empty() {
+        ctx.log_header("Restored", path);
+        Ok(())
+    } else {
+        Err(LockErrors(errors).into())
     }
 }
 
-/// Reads the config from the config file path, locks it, and returns the
-/// locked config.
-fn locked(ctx: &Context, warnings: &mut Vec<Error>) -> Result<LockedConfig> {
+/// Execute the `prune` subcommand.
+///
+/// Remove the sources in the lock file that are no longer used by the config
+/// without relocking.
+fn prune(ctx: &Context, warnings: &mut Vec<Error>) -> Result<()> {
     let path = ctx.config_file();
     let config = config::from_path(path, warnings).context("failed to load config file")?;
     ctx.log_header("Loaded", path);
-    config::clean(ctx, warnings, &config)?;
-    lock::config(ctx, config)
+    let path = ctx.lock_file();
+    let locked = lock::from_path(path).context("failed to load lock file")?;
+    ctx.log_header("Unlocked", path);
+    let removed = config::prune(ctx, warnings, &config, locked.installed(ctx))?;
+    let noun = if removed == 1 { "source" } else { "sources" };
+    ctx.log_header("Pruned", &format!("{removed} {noun}"));
+    Ok(())
+}
+
+/// The context with the shell set to the one in the config file, so that a
+/// lock file generated for a different shell is not reused.
+fn with_config_shell(ctx: &Context) -> Context {
+    Context {
+        shell: config::shell_from_path(ctx.config_file()).ok(),
+        ..ctx.clone()
+    }
+}
+
+/// Whether the config file has changed since the lock file was generated.
+///
+/// If the lock file records a hash of the config file then the contents are
+/// compared, otherwise the modification times are.
+fn config_changed(ctx: &Context, locked: &LockedConfig) -> bool {
+    let config_path = ctx.config_file();
+    match &locked.config_hash {
+        Some(hash) => util::hash_file(config_path).ok().as_ref() != Some(hash),
+        None => config_path.newer_than(ctx.lock_file()),
+    }
+}
+
+/// Reads the config from the config file path, or the standard input if the
+/// path is `-`, locks it, and returns the locked config.
+///
+/// If a plugins directory is given then each of its subdirectories is added as
+/// a local plugin, in which case the config file is optional. Unless
+/// `no_clean` is set, unused clones and downloads are removed first.
+fn locked(
+    ctx: &Context,
+    plugins_dir: Option<&Path>,
+    no_clean: bool,
+    warnings: &mut Vec<Error>,
+) -> Result<LockedConfig> {
+    let path = ctx.config_file();
+    let stdin = ctx.config_from_stdin();
+    let result = if stdin {
+        config::from_stdin(warnings)
+    } else {
+        config::from_path(path, warnings)
+    };
+    let mut config = match result {
+        Ok(config) => {
+            if stdin {
+                ctx.log_header("Loaded", &"stdin");
+            } else {
+                ctx.log_header("Loaded", path);
+            }
+            config
+        }
+        Err(err)
+            if plugins_dir.is_some()
+                && underlying_io_error_kind(&err) == Some(io::ErrorKind::NotFound) =>
+        {
+            Config::default()
+        }
+        Err(err) => return Err(err).context("failed to load config file"),
+    };
+    if let Some(dir) = plugins_dir {
+        config.add_plugins_dir(dir)?;
+        ctx.log_header("Loaded", dir);
+    }
+    if !no_clean {
+        config::clean(ctx, warnings, &config)?;
+    }
+    lock::config(ctx, config, warnings)
 }
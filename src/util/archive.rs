@@ -0,0 +1,105 @@
+//! Extract archive files.
+//!
+//! We shell out to `tar` and `unzip` instead of depending on crates for each
+//! compression format.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context as ResultExt, Result};
+
+/// Tar archive file extensions, `tar` detects the compression itself.
+const TAR_EXTENSIONS: &[&str] = &[
+    ".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tbz2", ".tar.xz", ".txz",
+];
+
+/// Returns whether the given path has the extension of a supported archive.
+pub fn is_archive(path: &Path) -> bool {
+    is_tar(path) || is_zip(path)
+}
+
+fn is_tar(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|p| TAR_EXTENSIONS.iter().any(|ext| p.ends_with(ext)))
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "zip")
+}
+
+/// Extract the archive into the given directory, which must exist.
+pub fn extract(archive: &Path, dir: &Path) -> Result<()> {
+    let mut command = if is_zip(archive) {
+        let mut c = Command::new("unzip");
+        c.arg("-q").arg(archive).arg("-d").arg(dir);
+        c
+    } else {
+        let mut c = Command::new("tar");
+        c.arg("-xf").arg(archive).arg("-C").arg(dir);
+        c
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run `{program}`"))?;
+    if !output.status.success() {
+        bail!(
+            "`{program}` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn is_archive_extensions() {
+        for path in ["a.tar", "a.tar.gz", "a.tgz", "a.tar.xz", "a.tbz2", "a.zip"] {
+            assert!(is_archive(Path::new(path)), "{path}");
+        }
+        for path in ["a", "a.gz", "a.zsh", "tar", "a.tar/b"] {
+            assert!(!is_archive(Path::new(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn extract_tar_gz() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("src/plugin")).unwrap();
+        fs::write(dir.join("src/plugin/test.plugin.zsh"), "echo 'testing...'").unwrap();
+        let status = Command::new("tar")
+            .args(["-czf", "plugin.tar.gz", "-C", "src", "plugin"])
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        fs::create_dir(dir.join("out")).unwrap();
+        extract(&dir.join("plugin.tar.gz"), &dir.join("out")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("out/plugin/test.plugin.zsh")).unwrap(),
+            "echo 'testing...'"
+        );
+    }
+
+    #[test]
+    fn extract_corrupt_archive() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let dir = temp.path();
+        fs::write(dir.join("bad.tar.gz"), "not an archive").unwrap();
+        let err = extract(&dir.join("bad.tar.gz"), dir).unwrap_err();
+        assert!(err.to_string().starts_with("`tar` failed with"));
+    }
+}
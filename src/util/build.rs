@@ -77,3 +77,15 @@ pub const CRATE_VERBOSE_VERSION: &str = concat!(
     "\n  host: ",
     env!("RUSTC_VERSION_HOST"),
 );
+
+/// The version information as a JSON object, for use by other tools.
+pub fn version_json() -> String {
+    serde_json::json!({
+        "name": CRATE_NAME,
+        "version": CRATE_RELEASE,
+        "commit": option_env!("GIT_COMMIT_HASH"),
+        "date": option_env!("GIT_COMMIT_DATE"),
+        "rustc": env!("RUSTC_VERSION_RELEASE"),
+    })
+    .to_string()
+}
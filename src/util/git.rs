@@ -1,34 +1,126 @@
 //! Git helpers.
 
-use std::path::Path;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use git2::{
-    BranchType, Cred, CredentialType, Error, FetchOptions, Oid, RemoteCallbacks, Repository,
-    ResetType,
+    BranchType, Cred, CredentialType, Error, FetchOptions, ObjectType, Oid, RemoteCallbacks,
+    Repository, ResetType,
 };
 use once_cell::sync::Lazy;
 use url::Url;
 
-use anyhow::Context as ResultExt;
+use anyhow::{bail, Context as ResultExt};
+
+/// The environment variables that can contain a GitHub token, in order of
+/// precedence.
+const GITHUB_TOKEN_VARS: &[&str] = &["SHELDON_GITHUB_TOKEN", "GITHUB_TOKEN"];
+
+/// The default SSH key files to try, relative to the home directory.
+const DEFAULT_SSH_KEYS: &[&str] = &[".ssh/id_ed25519", ".ssh/id_ecdsa", ".ssh/id_rsa"];
+
+/// Keeps track of which credentials have already been tried.
+///
+/// libgit2 keeps calling the credentials callback until it returns an error,
+/// so each method must only be tried once.
+#[derive(Debug, Default)]
+struct Attempts {
+    token: bool,
+    helper: bool,
+    agent: bool,
+    ssh_keys: usize,
+    default: bool,
+}
+
+/// Returns the next credentials to try for the given URL.
+///
+/// For HTTPS this tries a GitHub token from the environment and then the Git
+/// credential helper. For SSH this tries the SSH agent and then the default
+/// key files.
+fn credentials(
+    attempts: &mut Attempts,
+    url: &str,
+    username: Option<&str>,
+    allowed: CredentialType,
+) -> Result<Cred, Error> {
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        if !attempts.token {
+            attempts.token = true;
+            if let Some(token) = github_token(url) {
+                return Cred::userpass_plaintext(username.unwrap_or("x-access-token"), &token);
+            }
+        }
+        if !attempts.helper {
+            attempts.helper = true;
+            if let Ok(cred) = git2::Config::open_default()
+                .and_then(|config| Cred::credential_helper(&config, url, username))
+            {
+                return Ok(cred);
+            }
+        }
+    }
+    if allowed.contains(CredentialType::SSH_KEY) {
+        if let Some(username) = username {
+            if !attempts.agent {
+                attempts.agent = true;
+                if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                    return Ok(cred);
+                }
+            }
+            let keys = default_ssh_keys();
+            while let Some(key) = keys.get(attempts.ssh_keys) {
+                attempts.ssh_keys += 1;
+                if let Ok(cred) = Cred::ssh_key(username, None, key, None) {
+                    return Ok(cred);
+                }
+            }
+        }
+    }
+    if allowed.contains(CredentialType::DEFAULT) && !attempts.default {
+        attempts.default = true;
+        return Cred::default();
+    }
+    Err(Error::from_str(
+        "remote authentication failed, for HTTPS set `SHELDON_GITHUB_TOKEN` or configure a Git \
+         credential helper, for SSH add a key to the SSH agent",
+    ))
+}
+
+/// Returns a GitHub token from the environment if the URL is a GitHub URL.
+fn github_token(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if !matches!(url.host_str(), Some("github.com" | "gist.github.com")) {
+        return None;
+    }
+    GITHUB_TOKEN_VARS
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|token| !token.is_empty()))
+}
+
+/// Returns the default SSH key files that exist.
+fn default_ssh_keys() -> Vec<PathBuf> {
+    let home = match home::home_dir() {
+        Some(home) => home,
+        None => return Vec::new(),
+    };
+    DEFAULT_SSH_KEYS
+        .iter()
+        .map(|key| home.join(key))
+        .filter(|key| key.exists())
+        .collect()
+}
 
 /// Call a function with generated fetch options.
 fn with_fetch_options<T, F>(f: F) -> anyhow::Result<T>
 where
     F: FnOnce(FetchOptions<'_>) -> anyhow::Result<T>,
 {
+    let mut attempts = Attempts::default();
     let mut rcb = RemoteCallbacks::new();
-    rcb.credentials(|_, username, allowed| {
-        if allowed.contains(CredentialType::SSH_KEY) {
-            if let Some(username) = username {
-                return Cred::ssh_key_from_agent(username);
-            }
-        }
-        if allowed.contains(CredentialType::DEFAULT) {
-            return Cred::default();
-        }
-        Err(Error::from_str(
-            "remote authentication required but none available",
-        ))
+    rcb.credentials(move |url, username, allowed| {
+        credentials(&mut attempts, url, username, allowed)
     });
 
     // Try to auto-detect the proxy from the git configuration so that
@@ -49,35 +141,122 @@ pub fn open(dir: &Path) -> anyhow::Result<Repository> {
     Ok(repo)
 }
 
-static DEFAULT_REFSPECS: Lazy<Vec<String>> = Lazy::new(|| {
-    vec_into![
-        "+refs/heads/*:refs/remotes/origin/*",
-        "+HEAD:refs/remotes/origin/HEAD"
-    ]
-});
+static DEFAULT_REFSPECS: Lazy<Vec<String>> =
+    Lazy::new(|| vec_into!["+refs/heads/*:refs/remotes/origin/*"]);
+
+static HEAD_REFSPECS: Lazy<Vec<String>> = Lazy::new(|| vec_into!["+HEAD:refs/remotes/origin/HEAD"]);
+
+/// The file in the locks directory used to lock a repository directory.
+///
+/// It is named after a hash of the repository directory.
+pub fn lock_file(locks_dir: &Path, dir: &Path) -> anyhow::Result<PathBuf> {
+    let name = Oid::hash_object(ObjectType::Blob, dir.to_string_lossy().as_bytes())?;
+    Ok(locks_dir.join(format!("{name}.lock")))
+}
+
+/// Lock a repository directory for exclusive use.
+///
+/// Any other thread or process locking the same directory waits until the
+/// returned guard is dropped, so concurrent clones and checkouts can't clobber
+/// each other's working tree.
+pub fn lock_dir(locks_dir: &Path, dir: &Path) -> anyhow::Result<fmutex::Guard> {
+    fs::create_dir_all(locks_dir)
+        .with_context(|| format!("failed to create directory `{}`", locks_dir.display()))?;
+    let path = lock_file(locks_dir, dir)?;
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed to create `{}`", path.display()))?;
+    fmutex::lock(&path).with_context(|| format!("failed to lock `{}`", path.display()))
+}
 
 /// Clone a Git repository.
-pub fn clone(url: &Url, dir: &Path) -> anyhow::Result<Repository> {
-    with_fetch_options(|mut opts| {
-        let repo = Repository::init(dir)?;
-        repo.remote("origin", url.as_str())?
-            .fetch(&DEFAULT_REFSPECS, Some(&mut opts), None)?;
-        Ok(repo)
+///
+/// If cloning from the URL fails then each of the mirrors is tried in turn.
+/// The `origin` remote always points at the URL. Returns the mirror that was
+/// cloned from, if any.
+pub fn clone(url: &Url, mirrors: &[Url], dir: &Path) -> anyhow::Result<(Repository, Option<Url>)> {
+    let repo = Repository::init(dir)
+        .with_context(|| format!("failed to initialize repository at `{}`", dir.display()))?;
+    let mirror = with_fetch_options(|mut opts| {
+        let mut remote = repo.remote("origin", url.as_str())?;
+        fetch_remote(&repo, &mut remote, &mut opts)?;
+        Ok(None)
     })
-    .with_context(|| format!("failed to git clone `{url}`"))
+    .or_else(|err| fetch_mirrors(&repo, mirrors, err))
+    .with_context(|| format!("failed to git clone `{url}`"))?;
+    Ok((repo, mirror))
 }
 
 /// Fetch a Git repository.
-pub fn fetch(repo: &Repository) -> anyhow::Result<()> {
+///
+/// If fetching from the `origin` remote fails then each of the mirrors is
+/// tried in turn. Returns the mirror that was fetched from, if any.
+pub fn fetch(repo: &Repository, mirrors: &[Url]) -> anyhow::Result<Option<Url>> {
     with_fetch_options(|mut opts| {
-        repo.find_remote("origin")
-            .context("failed to find remote `origin`")?
-            .fetch(&DEFAULT_REFSPECS, Some(&mut opts), None)?;
-        Ok(())
+        let mut remote = repo
+            .find_remote("origin")
+            .context("failed to find remote `origin`")?;
+        fetch_remote(repo, &mut remote, &mut opts)?;
+        Ok(None)
     })
+    .or_else(|err| fetch_mirrors(repo, mirrors, err))
     .context("failed to git fetch")
 }
 
+/// Fetch from each mirror in turn until one succeeds.
+///
+/// Returns the mirror that succeeded, or the last error if they all failed.
+fn fetch_mirrors(
+    repo: &Repository,
+    mirrors: &[Url],
+    mut err: anyhow::Error,
+) -> anyhow::Result<Option<Url>> {
+    for mirror in mirrors {
+        let result = with_fetch_options(|mut opts| {
+            let mut remote = repo.remote_anonymous(mirror.as_str())?;
+            fetch_remote(repo, &mut remote, &mut opts)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => return Ok(Some(mirror.clone())),
+            Err(e) => err = e.context(format!("failed to fetch from mirror `{mirror}`")),
+        }
+    }
+    Err(err)
+}
+
+/// Fetch all branches from the remote and point the remote HEAD at the
+/// remote's default branch, like `git clone` does.
+///
+/// If the remote doesn't advertise a default branch then the remote HEAD is
+/// fetched directly instead.
+fn fetch_remote(
+    repo: &Repository,
+    remote: &mut git2::Remote<'_>,
+    opts: &mut FetchOptions<'_>,
+) -> Result<(), Error> {
+    remote.fetch(&DEFAULT_REFSPECS, Some(opts), None)?;
+    let branch = remote
+        .default_branch()
+        .ok()
+        .and_then(|b| b.as_str()?.strip_prefix("refs/heads/").map(String::from));
+    match branch {
+        Some(branch) => {
+            repo.reference_symbolic(
+                "refs/remotes/origin/HEAD",
+                &format!("refs/remotes/origin/{branch}"),
+                true,
+                "set remote HEAD to the default branch",
+            )?;
+        }
+        None => remote.fetch(&HEAD_REFSPECS, Some(opts), None)?,
+    }
+    Ok(())
+}
+
 /// Checkout at repository at a particular revision.
 pub fn checkout(repo: &Repository, oid: Oid) -> anyhow::Result<()> {
     let obj = repo
@@ -89,6 +268,42 @@ pub fn checkout(repo: &Repository, oid: Oid) -> anyhow::Result<()> {
         .with_context(|| format!("failed to checkout `{oid}`"))
 }
 
+/// The summaries of the commits reachable from `to` but not from `from`, newest
+/// first.
+pub fn log(repo: &Repository, from: Oid, to: Oid) -> anyhow::Result<Vec<String>> {
+    fn _log(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<String>, Error> {
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(to)?;
+        revwalk.hide(from)?;
+        revwalk
+            .map(|oid| {
+                let commit = repo.find_commit(oid?)?;
+                Ok(commit.summary().unwrap_or_default().to_string())
+            })
+            .collect()
+    }
+    _log(repo, from, to).with_context(|| format!("failed to walk commits from `{from}` to `{to}`"))
+}
+
+/// Garbage collect a Git repository.
+///
+/// This is not supported by libgit2 so we shell out to `git` instead.
+pub fn gc(dir: &Path) -> anyhow::Result<()> {
+    let output = Command::new("git")
+        .args(["gc", "--quiet"])
+        .current_dir(dir)
+        .output()
+        .context("failed to run `git gc`")?;
+    if !output.status.success() {
+        bail!(
+            "`git gc` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
 /// Recursively update Git submodules.
 pub fn submodule_update(repo: &Repository) -> Result<(), Error> {
     fn _submodule_update(repo: &Repository, todo: &mut Vec<Repository>) -> Result<(), Error> {
@@ -113,9 +328,20 @@ fn resolve_refname(repo: &Repository, refname: &str) -> Result<Oid, Error> {
     Ok(obj.id())
 }
 
-/// Get the *remote* HEAD as an object identifier.
-pub fn resolve_head(repo: &Repository) -> anyhow::Result<Oid> {
-    resolve_refname(repo, "refs/remotes/origin/HEAD").context("failed to find remote HEAD")
+/// Get the *remote* HEAD as an object identifier, along with the name of the
+/// default branch if it is known.
+pub fn resolve_head(repo: &Repository) -> anyhow::Result<(Oid, Option<String>)> {
+    let oid =
+        resolve_refname(repo, "refs/remotes/origin/HEAD").context("failed to find remote HEAD")?;
+    let branch = repo
+        .find_reference("refs/remotes/origin/HEAD")
+        .ok()
+        .and_then(|r| {
+            r.symbolic_target()?
+                .strip_prefix("refs/remotes/origin/")
+                .map(String::from)
+        });
+    Ok((oid, branch))
 }
 
 /// Resolve a branch to a object identifier.
@@ -128,14 +354,37 @@ pub fn resolve_branch(repo: &Repository, branch: &str) -> anyhow::Result<Oid> {
 }
 
 /// Resolve a revision to a object identifier.
+///
+/// The revision can be any revision expression, for example `v0.1.0~1` or
+/// `ad149784a^`. If the expression starts with `HEAD` or a branch name then the
+/// *remote* HEAD or branch is used, so that the result doesn't depend on what
+/// is currently checked out.
 pub fn resolve_rev(repo: &Repository, rev: &str) -> anyhow::Result<Oid> {
-    let obj = repo
-        .revparse_single(rev)
-        .with_context(|| format!("failed to find revision `{rev}`"))?;
-    Ok(match obj.as_tag() {
-        Some(tag) => tag.target_id(),
-        None => obj.id(),
-    })
+    fn _resolve_rev(repo: &Repository, rev: &str) -> Result<Oid, Error> {
+        let spec = remote_revspec(repo, rev);
+        let obj = repo.revparse_single(&spec)?;
+        let obj = obj.peel(git2::ObjectType::Commit)?;
+        Ok(obj.id())
+    }
+    _resolve_rev(repo, rev).with_context(|| format!("failed to find revision `{rev}`"))
+}
+
+/// Rewrite the start of a revision expression to refer to the remote HEAD or
+/// branch, tags and object identifiers are left as is.
+fn remote_revspec(repo: &Repository, rev: &str) -> String {
+    let (base, suffix) = rev.split_at(rev.find(['~', '^', '@', ':']).unwrap_or(rev.len()));
+    if base == "HEAD" {
+        format!("refs/remotes/origin/HEAD{suffix}")
+    } else if base.is_empty() || repo.refname_to_id(&format!("refs/tags/{base}")).is_ok() {
+        rev.to_string()
+    } else if repo
+        .find_branch(&format!("origin/{base}"), BranchType::Remote)
+        .is_ok()
+    {
+        format!("refs/remotes/origin/{base}{suffix}")
+    } else {
+        rev.to_string()
+    }
 }
 
 /// Resolve a tag to a object identifier.
@@ -148,3 +397,88 @@ pub fn resolve_tag(repo: &Repository, tag: &str) -> anyhow::Result<Oid> {
     }
     _resolve_tag(repo, tag).with_context(|| format!("failed to find tag `{tag}`"))
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_token_other_host() {
+        assert_eq!(github_token("https://example.com/owner/repo"), None);
+    }
+
+    #[test]
+    fn credentials_exhausted() {
+        let mut attempts = Attempts::default();
+        let url = "https://example.com/owner/repo";
+        let err = (0..5)
+            .find_map(|_| {
+                credentials(
+                    &mut attempts,
+                    url,
+                    None,
+                    CredentialType::USER_PASS_PLAINTEXT,
+                )
+                .err()
+            })
+            .unwrap();
+        assert!(attempts.token && attempts.helper);
+        assert!(err.message().contains("SHELDON_GITHUB_TOKEN"));
+    }
+
+    #[test]
+    fn log_between_commits() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let repo = Repository::init(temp.path()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let mut oids = Vec::new();
+        for message in ["First", "Second\n\nWith a body", "Third"] {
+            let parents: Vec<_> = oids
+                .last()
+                .map(|&o| repo.find_commit(o).unwrap())
+                .into_iter()
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            oids.push(
+                repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+                    .unwrap(),
+            );
+        }
+
+        assert_eq!(log(&repo, oids[0], oids[2]).unwrap(), ["Third", "Second"]);
+        assert!(log(&repo, oids[2], oids[0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resolve_tag_lightweight_and_annotated() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let repo = Repository::init(temp.path()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+            .unwrap();
+        let obj = repo.find_object(commit, None).unwrap();
+        repo.tag_lightweight("lightweight", &obj, false).unwrap();
+        let tag = repo
+            .tag("annotated", &obj, &sig, "Annotated tag", false)
+            .unwrap();
+        assert_ne!(tag, commit);
+
+        assert_eq!(resolve_tag(&repo, "lightweight").unwrap(), commit);
+        assert_eq!(resolve_tag(&repo, "annotated").unwrap(), commit);
+        assert_eq!(
+            resolve_tag(&repo, "missing").unwrap_err().to_string(),
+            "failed to find tag `missing`"
+        );
+    }
+}
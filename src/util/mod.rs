@@ -1,16 +1,21 @@
+pub mod archive;
 pub mod build;
 pub mod git;
 mod path_ext;
 mod temp;
 
+use std::env;
 use std::fs::File;
 use std::io;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::result;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error, Result};
 use once_cell::sync::Lazy;
+use regex_macro::regex;
 
+pub use crate::util::path_ext::PathExt;
 pub use crate::util::temp::TempPath;
 
 pub static TEMPLATE_ENGINE: Lazy<upon::Engine<'static>> = Lazy::new(upon::Engine::new);
@@ -25,19 +30,169 @@ pub fn underlying_io_error_kind(error: &Error) -> Option<io::ErrorKind> {
     None
 }
 
+/// Expands `$VAR` and `${VAR}` environment variables in the given path.
+///
+/// Paths that are not valid UTF-8 are returned unchanged.
+pub fn expand_env_vars(path: &Path) -> Result<PathBuf> {
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return Ok(path.to_path_buf()),
+    };
+    let re = regex!(r"\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))");
+    let mut expanded = String::with_capacity(s.len());
+    let mut last = 0;
+    for captures in re.captures_iter(s) {
+        let m = captures.get(0).unwrap();
+        let key = captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .unwrap()
+            .as_str();
+        let value =
+            env::var(key).map_err(|_| anyhow!("environment variable `{key}` is not set"))?;
+        expanded.push_str(&s[last..m.start()]);
+        expanded.push_str(&value);
+        last = m.end();
+    }
+    expanded.push_str(&s[last..]);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Returns the total size in bytes of all the files in the given directory.
+pub fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(result::Result::ok)
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Returns a hash of the contents of the given file.
+///
+/// This is the same as the Git object ID of the file so it is stable across
+/// versions.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    let oid = git2::Oid::hash_object(git2::ObjectType::Blob, &bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    Ok(oid.to_string())
+}
+
+/// Formats a number of bytes in a human readable way.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for u in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = u;
+    }
+    format!("{value:.1} {unit}")
+}
+
 /// Download a remote file.
-pub fn download(url: &str, mut file: File) -> result::Result<(), curl::Error> {
+///
+/// If a maximum size is given then the download is aborted once more than that
+/// many bytes have been received.
+pub fn download(url: &str, mut file: File, max_size: Option<u64>) -> Result<()> {
     let mut easy = curl::easy::Easy::new();
     easy.fail_on_error(true)?; // -f
     easy.follow_location(true)?; // -L
     easy.url(url.as_ref())?;
-    let mut transfer = easy.transfer();
-    transfer.write_function(move |data| {
-        match file.write_all(data) {
-            Ok(()) => Ok(data.len()),
-            Err(_) => Ok(0), // signals to cURL that the writing failed
+    let mut written = 0;
+    let mut exceeded = false;
+    let result = {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| {
+            written += data.len() as u64;
+            if max_size.is_some_and(|max| written > max) {
+                exceeded = true;
+                return Ok(0);
+            }
+            match file.write_all(data) {
+                Ok(()) => Ok(data.len()),
+                Err(_) => Ok(0), // signals to cURL that the writing failed
+            }
+        })?;
+        transfer.perform()
+    };
+    match (result, max_size) {
+        (Err(_), Some(max)) if exceeded => Err(anyhow!(
+            "download is larger than the maximum size of {}",
+            format_bytes(max)
+        )),
+        (result, _) => Ok(result?),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Unit tests
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_max_size() {
+        let temp = tempfile::tempdir().expect("create temporary directory");
+        let src = temp.path().join("src.txt");
+        std::fs::write(&src, "x".repeat(2048)).unwrap();
+        let url = url::Url::from_file_path(&src).unwrap();
+        let dst = temp.path().join("dst.txt");
+
+        download(url.as_str(), File::create(&dst).unwrap(), Some(4096)).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap().len(), 2048);
+
+        let err = download(url.as_str(), File::create(&dst).unwrap(), Some(1024)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "download is larger than the maximum size of 1.0 KiB"
+        );
+    }
+
+    #[test]
+    fn expand_env_vars_set() {
+        env::set_var("SHELDON_TEST_EXPAND_ENV_VARS", "/test");
+        for (p, exp) in [
+            ("/fol/der", "/fol/der"),
+            ("$SHELDON_TEST_EXPAND_ENV_VARS", "/test"),
+            ("$SHELDON_TEST_EXPAND_ENV_VARS/fol/der", "/test/fol/der"),
+            ("${SHELDON_TEST_EXPAND_ENV_VARS}/der", "/test/der"),
+            ("~/$/der", "~/$/der"),
+        ] {
+            assert_eq!(expand_env_vars(Path::new(p)).unwrap(), Path::new(exp));
         }
-    })?;
-    transfer.perform()?;
-    Ok(())
+    }
+
+    #[test]
+    fn format_bytes_units() {
+        for (bytes, exp) in [
+            (0, "0 B"),
+            (1023, "1023 B"),
+            (1024, "1.0 KiB"),
+            (1536, "1.5 KiB"),
+            (5 * 1024 * 1024, "5.0 MiB"),
+            (3 * 1024 * 1024 * 1024, "3.0 GiB"),
+        ] {
+            assert_eq!(format_bytes(bytes), exp);
+        }
+    }
+
+    #[test]
+    fn expand_env_vars_not_set() {
+        let err = expand_env_vars(Path::new("$SHELDON_TEST_NOT_SET/zsh")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable `SHELDON_TEST_NOT_SET` is not set"
+        );
+    }
 }
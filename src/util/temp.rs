@@ -54,12 +54,13 @@ impl TempPath {
     }
 
     /// Move the temporary path to a new location.
+    ///
+    /// An existing file at the new location is replaced atomically, an existing
+    /// directory is removed first.
     pub fn rename(mut self, new_path: &Path) -> io::Result<()> {
-        if let Err(err) = nuke_path(new_path) {
-            if err.kind() != io::ErrorKind::NotFound {
-                return Err(err);
-            }
-        };
+        if new_path.is_dir() {
+            fs::remove_dir_all(new_path)?;
+        }
         if let Some(path) = &self.path {
             fs::rename(path, new_path)?;
             // This is so that the Drop impl doesn't try delete a non-existent file.
@@ -1,8 +1,9 @@
 use std::env;
 use std::ffi::OsStr;
 use std::io;
+use std::io::Write;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 use pretty_assertions::assert_eq;
 
@@ -10,6 +11,7 @@ use crate::helpers::TestDirs;
 
 pub struct TestCommand {
     command: Command,
+    stdin: Option<String>,
     expect_exit_code: Option<i32>,
     expect_stdout: Option<String>,
     expect_stderr: Option<String>,
@@ -53,12 +55,14 @@ impl TestCommand {
             .env("HOME", dirs.home.path())
             .env("SHELDON_CONFIG_DIR", &dirs.config)
             .env("SHELDON_DATA_DIR", &dirs.data)
+            .env("SHELDON_JOBS", "1")
             .args(&params)
             .arg("--non-interactive")
             .arg("--verbose");
 
         Self {
             command,
+            stdin: None,
             expect_exit_code: None,
             expect_stdout: None,
             expect_stderr: None,
@@ -80,6 +84,11 @@ impl TestCommand {
         self
     }
 
+    pub fn stdin(mut self, stdin: String) -> Self {
+        self.stdin = Some(stdin);
+        self
+    }
+
     pub fn env<K, V>(mut self, key: K, val: V) -> Self
     where
         K: AsRef<OsStr>,
@@ -118,7 +127,19 @@ impl TestCommand {
     /// Run the command and assert that the output is as expected.
     #[track_caller]
     pub fn run(mut self) -> io::Result<()> {
-        let result = self.command.output()?;
+        let result = match self.stdin {
+            Some(stdin) => {
+                let mut child = self
+                    .command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                child.stdin.take().unwrap().write_all(stdin.as_bytes())?;
+                child.wait_with_output()?
+            }
+            None => self.command.output()?,
+        };
         let result_exit_code = result.status.code().unwrap();
         let result_stdout = String::from_utf8_lossy(&result.stdout);
         let result_stderr = String::from_utf8_lossy(&result.stderr);
@@ -5,7 +5,9 @@ use std::env;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Once;
+use std::time::Duration;
 
 use once_cell::sync::Lazy;
 use pretty_assertions::assert_eq;
@@ -54,27 +56,51 @@ impl TestCase {
             p
         };
 
-        let subs = upon::value! {
+        let render = |raw: &str, subs: &upon::Value| {
+            ENGINE
+                .compile(raw)
+                .unwrap()
+                .render(&ENGINE, subs)
+                .to_string()
+                .unwrap()
+        };
+
+        let mut raws = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+            raws.insert(name, fs::read_to_string(&path)?);
+        }
+
+        let mut subs = upon::value! {
             version: env!("CARGO_PKG_VERSION"),
             home: dirs.home.path(),
             config: &dirs.config,
             data: &dirs.data,
         };
 
-        let mut data = HashMap::new();
-        for entry in fs::read_dir(dir)? {
-            let path = entry?.path();
-            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
-            let raw = fs::read_to_string(&path)?;
-            let value = ENGINE
-                .compile(&raw)
-                .unwrap()
-                .render(&ENGINE, &subs)
-                .to_string()
-                .unwrap();
-            data.insert(name, value);
+        // The lock file records a hash of the rendered config file.
+        let config_hash = ["plugins.toml", "test.toml"]
+            .iter()
+            .find_map(|name| raws.get(*name))
+            .map(|raw| {
+                git2::Oid::hash_object(git2::ObjectType::Blob, render(raw, &subs).as_bytes())
+                    .unwrap()
+                    .to_string()
+            })
+            .unwrap_or_default();
+        if let upon::Value::Map(map) = &mut subs {
+            map.insert("config_hash".into(), config_hash.into());
         }
 
+        let data = raws
+            .into_iter()
+            .map(|(name, raw)| {
+                let value = render(&raw, &subs);
+                (name, value)
+            })
+            .collect();
+
         Ok(Self { dirs, data })
     }
 
@@ -167,6 +193,7 @@ fn lock_and_source_clean() -> io::Result<()> {
     {
         fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(data.join("repos/test.com/test.txt"))?;
     }
@@ -186,6 +213,7 @@ fn lock_and_source_clean_permission_denied() -> io::Result<()> {
     {
         fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(data.join("repos/test.com/test.txt"))?;
     }
@@ -255,7 +283,8 @@ fn lock_and_source_github_branch() -> io::Result<()> {
 #[test]
 fn lock_and_source_github_submodule() -> io::Result<()> {
     let case = TestCase::load("github_submodule")?;
-    case.run()?;
+    case.write_config_file("plugins.toml")?;
+    case.command("lock").run()?;
 
     // Check that sheldon-test@recursive-recursive was in fact cloned.
     let dir = case
@@ -266,6 +295,13 @@ fn lock_and_source_github_submodule() -> io::Result<()> {
     assert!(dir.is_dir());
     assert!(file.is_file());
     let repo = git2::Repository::open(&dir).unwrap();
+    // The locked revision is the tip of the branch
+    let head = repo.revparse_commit("HEAD").unwrap().id().to_string();
+    assert_eq!(
+        fs::read_to_string(case.dirs.data.join("plugins.lock"))?.replace(&head, "HEAD"),
+        case.get("plugins.lock")
+    );
+    case.command("source").run()?;
     // HEAD is 2 commits head of origin/master
     assert_eq!(
         repo.revparse_commit("HEAD~2").unwrap().id(),
@@ -397,6 +433,285 @@ fn lock_and_source_override_data_dir() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn lock_and_source_override_data_dir_profile() -> io::Result<()> {
+    let case = TestCase::load("override_data_dir_profile")?;
+    let data_dir = case.dirs.home.path().join("test");
+    case.write_config_file("plugins.toml")?;
+    case.command("lock")
+        .env("SHELDON_PROFILE", "work")
+        .env("SHELDON_DATA_DIR", case.dirs.home.path().join("other"))
+        .env("SHELDON_WORK_DATA_DIR", &data_dir)
+        .run()?;
+    case.assert_contents_path("plugins.work.lock", &data_dir.join("plugins.work.lock"))?;
+    case.command("source")
+        .env("SHELDON_PROFILE", "work")
+        .env("SHELDON_DATA_DIR", case.dirs.home.path().join("other"))
+        .env("SHELDON_WORK_DATA_DIR", &data_dir)
+        .run()?;
+    Ok(())
+}
+
+#[test]
+fn source_no_lock_file() -> io::Result<()> {
+    let case = TestCase::load("no_lock_file")?;
+    case.write_config_file("plugins.toml")?;
+    case.command("source").arg("--no-lock-file").run()?;
+    assert!(!case.dirs.data.join("plugins.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn source_config_file_stdin() -> io::Result<()> {
+    let case = TestCase::load("config_file_stdin")?;
+    case.command("source")
+        .env("SHELDON_CONFIG_FILE", "-")
+        .stdin(case.get("plugins.toml"))
+        .run()?;
+    assert!(!case.dirs.data.join("plugins.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn lock_quiet_still_warns() -> io::Result<()> {
+    let case = TestCase::load("quiet")?;
+    case.write_config_file("plugins.toml")?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(String::new())
+        .expect_stderr(case.get("lock.stderr"))
+        .arg("--quiet")
+        .arg("lock")
+        .run()?;
+    case.assert_contents("plugins.lock")
+}
+
+#[test]
+fn source_color_always_when_piped() -> io::Result<()> {
+    // Both stdout and stderr are pipes here, but `--color always` should still
+    // color the messages on stderr and leave the script on stdout alone.
+    let case = TestCase::load("color_always")?;
+    case.write_config_file("plugins.toml")?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(case.get("source.stdout"))
+        .expect_stderr(case.get("source.stderr"))
+        .arg("--color")
+        .arg("always")
+        .arg("source")
+        .run()
+}
+
+#[test]
+fn lock_format_json() -> io::Result<()> {
+    let case = TestCase::load("format_json")?;
+    case.write_config_file("plugins.toml")?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(2)
+        .expect_stdout(String::new())
+        .expect_stderr(case.get("lock.stderr"))
+        .arg("--format")
+        .arg("json")
+        .arg("lock")
+        .run()
+}
+
+#[test]
+fn source_up_to_date_is_silent() -> io::Result<()> {
+    let case = TestCase::load("empty")?;
+    case.run()?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(case.get("source.stdout"))
+        .expect_stderr(String::new())
+        .arg("--quiet")
+        .arg("--format")
+        .arg("json")
+        .arg("source")
+        .run()
+}
+
+#[test]
+fn source_config_reverted_is_not_relocked() -> io::Result<()> {
+    let case = TestCase::load("empty")?;
+    case.run()?;
+    let config = case.dirs.config.join("plugins.toml");
+    let lock = case.dirs.data.join("plugins.lock");
+    let modified = fs::metadata(&lock)?.modified()?;
+    fs::write(&config, "[plugins.test]\ninline = \"echo 'test'\"\n")?;
+    case.write_config_file("plugins.toml")?;
+    fs::File::options()
+        .write(true)
+        .open(&config)?
+        .set_modified(modified + Duration::from_secs(60))?;
+    TestCommand::new(&case.dirs)
+        .expect_exit_code(0)
+        .expect_stdout(String::new())
+        .expect_stderr(String::new())
+        .arg("--quiet")
+        .arg("source")
+        .run()?;
+    assert_eq!(fs::metadata(&lock)?.modified()?, modified);
+    Ok(())
+}
+
+#[test]
+fn source_config_newer_than_lock_file() -> io::Result<()> {
+    let case = TestCase::load("empty")?;
+    case.run()?;
+    let config = case.dirs.config.join("plugins.toml");
+    let lock_file = case.dirs.data.join("plugins.lock");
+    let source = |stdout: &str| {
+        TestCommand::new(&case.dirs)
+            .expect_exit_code(0)
+            .expect_stdout(stdout.into())
+            .expect_stderr(String::new())
+            .arg("--quiet")
+            .arg("source")
+    };
+
+    // A newer config file with the same contents doesn't cause a relock.
+    let locked = fs::read_to_string(&lock_file)?;
+    let modified = fs::metadata(&lock_file)?.modified()?;
+    fs::File::options()
+        .write(true)
+        .open(&config)?
+        .set_modified(modified + Duration::from_secs(60))?;
+    source("").run()?;
+    assert_eq!(fs::metadata(&lock_file)?.modified()?, modified);
+    assert_eq!(fs::read_to_string(&lock_file)?, locked);
+
+    // Changing the contents does.
+    fs::write(&config, "[plugins.test]\ninline = \"echo 'test'\"\n")?;
+    source("echo 'test'\n").run()
+}
+
+#[test]
+fn source_output() -> io::Result<()> {
+    let case = TestCase::load("output")?;
+    case.write_config_file("plugins.toml")?;
+    let output = case.dirs.home.path().join("cache/sheldon.zsh");
+    fs::create_dir_all(output.parent().unwrap())?;
+    fs::write(&output, "stale")?;
+    case.command("source").arg("--output").arg(&output).run()?;
+    case.assert_contents_path("sheldon.zsh", &output)?;
+    assert!(!output.with_file_name("~sheldon.zsh").exists());
+    Ok(())
+}
+
+#[test]
+fn source_plugins_dir() -> io::Result<()> {
+    let case = TestCase::load("plugins_dir")?;
+    let plugins_dir = case.dirs.home.path().join("plugins");
+    for name in ["one", "two", "three", ".hidden"] {
+        let dir = plugins_dir.join(name);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{name}.plugin.zsh")), "")?;
+    }
+    case.command("source")
+        .arg("--plugins-dir")
+        .arg(&plugins_dir)
+        .run()?;
+    assert!(!case.dirs.data.join("plugins.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn source_no_clean() -> io::Result<()> {
+    let case = TestCase::load("plugins_dir")?;
+    let plugins_dir = case.dirs.home.path().join("plugins");
+    for name in ["one", "two", "three"] {
+        let dir = plugins_dir.join(name);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{name}.plugin.zsh")), "")?;
+    }
+    let extra = case.dirs.data.join("repos/example.com/extra");
+    fs::create_dir_all(&extra)?;
+    fs::write(extra.join("extra.zsh"), "")?;
+    case.command("source")
+        .arg("--plugins-dir")
+        .arg(&plugins_dir)
+        .arg("--no-clean")
+        .run()?;
+    assert!(extra.join("extra.zsh").exists());
+    Ok(())
+}
+
+#[test]
+fn lock_keep_going() -> io::Result<()> {
+    let case = TestCase::load("keep_going")?;
+    let good = case.dirs.home.path().join("good");
+    fs::create_dir_all(&good)?;
+    fs::write(good.join("good.plugin.zsh"), "")?;
+    case.write_config_file("plugins.toml")?;
+    case.command("lock")
+        .arg("--keep-going")
+        .expect_exit_code(2)
+        .run()?;
+    case.assert_contents("plugins.lock")?;
+    case.command("source").run()?;
+    Ok(())
+}
+
+#[test]
+fn source_check() -> io::Result<()> {
+    let case = TestCase::load("source_check")?;
+    let good = case.dirs.home.path().join("good");
+    fs::create_dir_all(&good)?;
+    fs::write(good.join("good.plugin.zsh"), "")?;
+    case.write_config_file("plugins.toml")?;
+    case.command("source")
+        .arg("--check")
+        .expect_exit_code(2)
+        .run()?;
+    assert!(!case.dirs.data.join("plugins.lock").exists());
+    Ok(())
+}
+
+#[test]
+fn init_from() -> io::Result<()> {
+    let case = TestCase::load("init_from")?;
+    let shared = case.dirs.home.path().join("shared.toml");
+    case.write_file(&shared, "shared.toml")?;
+    case.command("init").arg("--from").arg(&shared).run()?;
+    case.assert_contents_path("shared.toml", &case.dirs.config.join("plugins.toml"))?;
+    Ok(())
+}
+
+#[test]
+fn lock_and_source_local_archive() -> io::Result<()> {
+    let case = TestCase::load("local_archive")?;
+    let home = case.dirs.home.path();
+    fs::create_dir_all(home.join("src/plugin"))?;
+    fs::write(home.join("src/plugin/test.plugin.zsh"), "echo 'testing...'")?;
+    let status = process::Command::new("tar")
+        .args(["-czf", "plugin.tar.gz", "-C", "src", "plugin"])
+        .current_dir(home)
+        .status()?;
+    assert!(status.success());
+    case.run()
+}
+
+#[test]
+fn lock_and_source_shell_nu() -> io::Result<()> {
+    let case = TestCase::load("shell_nu")?;
+    let dir = case.dirs.home.path().join("test");
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("test.nu"), "echo 'testing...'")?;
+    case.run()
+}
+
+#[test]
+fn lock_and_source_sequential() -> io::Result<()> {
+    let case = TestCase::load("sequential")?;
+    for name in ["one", "two", "three"] {
+        let dir = case.dirs.home.path().join(name);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{name}.plugin.zsh")), "")?;
+    }
+    case.run()
+}
+
 #[test]
 fn lock_and_source_profiles() -> io::Result<()> {
     let case = TestCase::load("profiles")?;
@@ -509,3 +824,21 @@ fn version() -> io::Result<()> {
         .run()?;
     Ok(())
 }
+
+#[test]
+fn version_json() -> io::Result<()> {
+    let dirs = TestDirs::default()?;
+    let expected = serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": option_env!("GIT_COMMIT_HASH"),
+        "date": option_env!("GIT_COMMIT_DATE"),
+        "rustc": env!("RUSTC_VERSION_RELEASE"),
+    });
+    TestCommand::new(&dirs)
+        .arg("version")
+        .arg("--json")
+        .expect_exit_code(0)
+        .expect_stdout(format!("{expected}\n"))
+        .run()
+}